@@ -4,6 +4,7 @@ use criterion::{black_box, criterion_group, criterion_main, BatchSize, Benchmark
 use crossbeam_skiplist::SkipMap;
 use rand::{seq::SliceRandom, Rng, SeedableRng}; // 0.6.5
 use rand_chacha::ChaChaRng;
+use rust_kv::key::{InternalKey, MemoryMap, Value};
 use rust_kv::run::Run;
 use rust_kv::Config;
 use std::sync::Arc;
@@ -21,7 +22,7 @@ fn gen_rand_bytes<T: Rng>(mut rng: &mut T) -> Vec<u8> {
 }
 
 // Size in bytes approx
-fn create_skipmap(size: u64) -> Arc<SkipMap<i32, Option<Vec<u8>>>> {
+fn create_skipmap(size: u64) -> Arc<MemoryMap<i32>> {
     let mut curr_size: u64 = 0;
     let map = Arc::new(SkipMap::new());
     let seed = [42; 32];
@@ -31,7 +32,7 @@ fn create_skipmap(size: u64) -> Arc<SkipMap<i32, Option<Vec<u8>>>> {
         let rand_key: i32 = rng.gen();
         let rand_val = gen_rand_bytes(&mut rng);
         curr_size += 4 + rand_val.len() as u64;
-        map.insert(rand_key, Some(rand_val));
+        map.insert(InternalKey::new(rand_key, 0), Value::Put(rand_val));
     }
 
     return map;
@@ -11,17 +11,17 @@ fn printerfunc(c: &mut Criterion) {
     c.bench_function("contains fpr 0.05", |b| b.iter(|| 10 + 2));
 }
 
-fn single_threaded_runner(store: RustStore, workload: WorkloadParameters) {
+fn single_threaded_runner(store: RustStore<i32>, workload: WorkloadParameters) {
     for op in workload.into_iter() {
         match op.request_type {
             RequestType::Get => {
                 store.get(&op.key);
             }
             RequestType::Put => {
-                store.put(op.key, op.value.unwrap());
+                store.put(op.key, op.value.unwrap()).unwrap();
             }
             RequestType::Delete => {
-                store.delete(&op.key);
+                store.delete(&op.key).unwrap();
             }
         }
     }
@@ -39,7 +39,7 @@ fn bench_single_threaded_puts(c: &mut Criterion) {
                     let mut config = Config::default();
                     let dir = tempfile::tempdir().unwrap();
                     config.set_directory(dir.path());
-                    RustStore::new(Some(config))
+                    RustStore::new(Some(config)).unwrap()
                 },
                 |rust_store| {
                     let workload = WorkloadParameters::new(n, 0, 0, 0, 0.0, 0.0, [42; 32]).unwrap();
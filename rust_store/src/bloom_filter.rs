@@ -78,7 +78,7 @@ impl BloomFilter {
         return BloomFilter::new_with_size(bits, optimal_num_hashes(bits, expected_num_items));
     }
 
-    fn index_iterator<'a, T: Hash>(&'a self, item: &'a T) -> Vec<usize> {
+    fn index_iterator<'a, T: Hash + ?Sized>(&'a self, item: &'a T) -> Vec<usize> {
         // collapse this into a vec to avoid references into the bloom filter after
         return (0..self.num_hashes)
             .map(move |i| {
@@ -93,13 +93,13 @@ impl BloomFilter {
             .collect();
     }
 
-    pub fn insert<T: Hash>(&mut self, item: &T) {
+    pub fn insert<T: Hash + ?Sized>(&mut self, item: &T) {
         for index in self.index_iterator(item) {
             self.bits.set(index, true)
         }
     }
 
-    pub fn contains<T: Hash>(&self, item: &T) -> bool {
+    pub fn contains<T: Hash + ?Sized>(&self, item: &T) -> bool {
         self.index_iterator(item)
             .iter()
             .all(|index| self.bits.get(*index).unwrap())
@@ -0,0 +1,439 @@
+use crate::compaction::CompactionReport;
+use crate::key::{expires_at, InternalKey, Key, MemoryMap, RangeTombstone, Value};
+use crate::lsm::{Lsm, LsmError};
+use crate::merge_operator::resolve;
+use crate::run::{Item, Level, Run};
+use crate::rust_store::{Config, RustStoreError};
+use crate::wal::WalRecord;
+use crate::write_stall::WriteStall;
+use crossbeam_skiplist::SkipMap;
+use log::trace;
+use parking_lot::RwLock;
+use std::borrow::Borrow;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Id of the column family `RustStore`'s own methods read and write
+pub const DEFAULT_COLUMN_FAMILY: u32 = 0;
+pub const DEFAULT_COLUMN_FAMILY_NAME: &str = "default";
+
+/// The memory maps and levels of one column family.
+///
+/// Every family has its own pair of memory maps, but which of the pair is active is decided by
+/// the store, so all of them switch maps and flush together.
+pub(crate) struct ColumnFamilyData<K: Key> {
+    pub(crate) id: u32,
+    pub(crate) name: String,
+    pub(crate) primary_memory_map: Arc<MemoryMap<K>>,
+    pub(crate) secondary_memory_map: Arc<MemoryMap<K>>,
+    /// Range deletes written to each memory map, flushed into the same run as the map
+    pub(crate) primary_range_tombstones: RwLock<Vec<RangeTombstone<K>>>,
+    pub(crate) secondary_range_tombstones: RwLock<Vec<RangeTombstone<K>>>,
+    pub(crate) primary_memory_map_memory_use: AtomicU64,
+    pub(crate) secondary_memory_map_memory_use: AtomicU64,
+    /// Tuning for this family, its directory is always the store's
+    pub(crate) config: Config,
+    pub(crate) levels: RwLock<Vec<RwLock<Level<K>>>>,
+}
+
+impl<K: Key> ColumnFamilyData<K> {
+    pub(crate) fn new(
+        id: u32,
+        name: &str,
+        config: Config,
+        levels: Vec<RwLock<Level<K>>>,
+    ) -> ColumnFamilyData<K> {
+        return ColumnFamilyData {
+            id: id,
+            name: String::from(name),
+            primary_memory_map: Arc::new(SkipMap::new()),
+            secondary_memory_map: Arc::new(SkipMap::new()),
+            primary_range_tombstones: RwLock::new(vec![]),
+            secondary_range_tombstones: RwLock::new(vec![]),
+            primary_memory_map_memory_use: AtomicU64::new(0),
+            secondary_memory_map_memory_use: AtomicU64::new(0),
+            config: config,
+            levels: RwLock::new(levels),
+        };
+    }
+
+    /// Sequence number of the newest write in any of the family's runs
+    pub(crate) fn max_run_seq(self: &Self) -> u64 {
+        return self
+            .levels
+            .read()
+            .iter()
+            .flat_map(|level| {
+                level
+                    .read()
+                    .runs
+                    .iter()
+                    .map(|run| run.max_seq)
+                    .collect::<Vec<u64>>()
+            })
+            .max()
+            .unwrap_or(0);
+    }
+
+    /// Get the value of `key` as it was once every write up to `seq` had been applied
+    pub(crate) fn get_at<Q>(self: &Self, key: &Q, seq: u64) -> Option<Vec<u8>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + Hash + Debug + ToOwned<Owned = K> + ?Sized,
+    {
+        // merge operands are folded into the versions below them, down to a put or delete
+        let mut versions = vec![];
+        let mut seq = seq;
+        while let Some(item) = self.get_version_at(key, seq) {
+            let item_seq = item.seq();
+            let value = item.value();
+            let done = !matches!(value, Value::Merge(_));
+            versions.push(value);
+            if done || item_seq == 0 {
+                break;
+            }
+            seq = item_seq - 1;
+        }
+        return resolve(versions.into_iter(), self.config.merge_operator.as_deref());
+    }
+
+    /// Sequence number of the newest write to `key`, deletes included
+    pub(crate) fn latest_seq<Q>(self: &Self, key: &Q) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: Ord + Hash + Debug + ToOwned<Owned = K> + ?Sized,
+    {
+        return self.get_version_at(key, u64::MAX).map(|item| item.seq());
+    }
+
+    /// The newest version of `key` written at or before `seq`, a delete is returned as an item
+    /// without a value.
+    fn get_version_at<Q>(self: &Self, key: &Q, seq: u64) -> Option<Item<K>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + Hash + Debug + ToOwned<Owned = K> + ?Sized,
+    {
+        // TODO verify locking
+        // Every copy of the key is a candidate, the one with the highest sequence number at or
+        // before seq wins. Sequence numbers only grow, so the memory maps are checked first and
+        // runs holding only older writes can be skipped.
+        let lookup = InternalKey::new(key.to_owned(), seq);
+        let mut found: Option<Item<K>> = None;
+        let memory = [
+            (&self.primary_memory_map, &self.primary_range_tombstones),
+            (&self.secondary_memory_map, &self.secondary_range_tombstones),
+        ];
+        for (map, range_tombstones) in memory.iter() {
+            // the first entry at or after (key, seq) is the newest version visible at seq
+            if let Some(entry) = map.range(&lookup..).next() {
+                let entry_key = entry.key();
+                if entry_key.key.borrow() == key
+                    && found.as_ref().map_or(true, |f| entry_key.seq > f.seq())
+                {
+                    found = Some(Item::new(
+                        entry_key.key.clone(),
+                        entry_key.seq,
+                        entry.value().clone(),
+                    ));
+                }
+            }
+            if let Some(deleted_at) =
+                RangeTombstone::newest_covering(&range_tombstones.read(), key, seq)
+            {
+                if found.as_ref().map_or(true, |f| deleted_at > f.seq()) {
+                    found = Some(Item::new(key.to_owned(), deleted_at, Value::Delete));
+                }
+            }
+        }
+
+        trace!("Searching levels for key {:?}", key);
+        let read_levels = self.levels.read();
+        for i in 0..read_levels.len() {
+            let level = read_levels[i].read();
+            found = level.get_from_level(key, seq, found);
+        }
+        if found.is_none() {
+            trace!("No value found for key {:?} ", key);
+        }
+        return found;
+    }
+
+    /// Sorted iterator over the live key value pairs with keys in `range` as they were once
+    /// every write up to `seq` had been applied.
+    /// The memory maps are copied up front while the runs are read lazily, the run files are
+    /// opened under the levels lock so a compaction cannot remove them first.
+    pub(crate) fn range_at<R: RangeBounds<K>>(
+        self: &Self,
+        range: R,
+        seq: u64,
+    ) -> impl Iterator<Item = (K, Vec<u8>)> {
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+        // every version of the keys at the ends of the range is inside or outside it
+        let map_range = (
+            match &start {
+                Bound::Included(k) => Bound::Included(InternalKey::new(k.clone(), u64::MAX)),
+                Bound::Excluded(k) => Bound::Excluded(InternalKey::new(k.clone(), 0)),
+                Bound::Unbounded => Bound::Unbounded,
+            },
+            match &end {
+                Bound::Included(k) => Bound::Included(InternalKey::new(k.clone(), 0)),
+                Bound::Excluded(k) => Bound::Excluded(InternalKey::new(k.clone(), u64::MAX)),
+                Bound::Unbounded => Bound::Unbounded,
+            },
+        );
+
+        // a flush adds its run before clearing the map so reading the maps before the levels
+        // can't miss an item
+        let mut iterators: Vec<Box<dyn Iterator<Item = Item<K>>>> = vec![];
+        let mut range_tombstones = vec![];
+        let memory = [
+            (&self.primary_memory_map, &self.primary_range_tombstones),
+            (&self.secondary_memory_map, &self.secondary_range_tombstones),
+        ];
+        for (map, map_range_tombstones) in memory.iter() {
+            let items: Vec<Item<K>> = map
+                .range(map_range.clone())
+                .map(|e| Item::new(e.key().key.clone(), e.key().seq, e.value().clone()))
+                .collect();
+            iterators.push(Box::new(items.into_iter()));
+            range_tombstones.extend(map_range_tombstones.read().iter().cloned());
+        }
+        let read_levels = self.levels.read();
+        for level in read_levels.iter() {
+            let level = level.read();
+            for run in level.runs.iter() {
+                iterators.push(Box::new(run.range(start.clone(), end.clone())));
+                range_tombstones.extend(run.range_tombstones.iter().cloned());
+            }
+        }
+        drop(read_levels);
+
+        return Run::visible_at(
+            Run::merge_iterators(iterators),
+            seq,
+            self.config.merge_operator.clone(),
+            range_tombstones,
+        );
+    }
+
+    pub(crate) fn insert_item(self: &Self, key: K, seq: u64, val: Value, primary: bool) {
+        let size = bincode::serialized_size(&key).unwrap_or(0)
+            + 8
+            + match &val {
+                Value::Put(v) | Value::Merge(v) => v.len() as u64,
+                Value::Expiring { value, .. } => value.len() as u64 + 8,
+                Value::Delete => 0,
+            };
+        let key = InternalKey::new(key, seq);
+        if primary {
+            trace!("Putting key {:?} into primary memmap", &key);
+            self.primary_memory_map.insert(key, val);
+            self.primary_memory_map_memory_use
+                .fetch_add(size, Ordering::SeqCst);
+        } else {
+            trace!("Putting key {:?} into secondary memmap", &key);
+            self.secondary_memory_map.insert(key, val);
+            self.secondary_memory_map_memory_use
+                .fetch_add(size, Ordering::SeqCst);
+        }
+    }
+
+    pub(crate) fn insert_range_tombstone(self: &Self, tombstone: RangeTombstone<K>, primary: bool) {
+        let size = bincode::serialized_size(&tombstone).unwrap_or(0);
+        if primary {
+            trace!(
+                "Putting range tombstone {:?} into primary memmap",
+                &tombstone
+            );
+            self.primary_range_tombstones.write().push(tombstone);
+            self.primary_memory_map_memory_use
+                .fetch_add(size, Ordering::SeqCst);
+        } else {
+            trace!(
+                "Putting range tombstone {:?} into secondary memmap",
+                &tombstone
+            );
+            self.secondary_range_tombstones.write().push(tombstone);
+            self.secondary_memory_map_memory_use
+                .fetch_add(size, Ordering::SeqCst);
+        }
+    }
+
+    /// Whether one of the memory maps has outgrown the family's budget
+    pub(crate) fn time_to_flush(self: &Self, primary: bool) -> bool {
+        let memory_use = if primary {
+            self.primary_memory_map_memory_use.load(Ordering::Relaxed)
+        } else {
+            self.secondary_memory_map_memory_use.load(Ordering::Relaxed)
+        };
+        trace!(
+            "time_to_flush {} primary {}: mem use: {} mem budget {}",
+            &self.name,
+            primary,
+            memory_use,
+            self.config.memory_map_budget
+        );
+        return memory_use > self.config.memory_map_budget;
+    }
+
+    /// Whether the primary or secondary memory map holds writes which are not in a run yet
+    pub(crate) fn holds_writes(self: &Self, primary: bool) -> bool {
+        return if primary {
+            !self.primary_memory_map.is_empty() || !self.primary_range_tombstones.read().is_empty()
+        } else {
+            !self.secondary_memory_map.is_empty()
+                || !self.secondary_range_tombstones.read().is_empty()
+        };
+    }
+
+    /// How far flushes and compactions of the family have fallen behind its writes
+    pub(crate) fn write_stall(self: &Self) -> WriteStall {
+        let memory_use = self.primary_memory_map_memory_use.load(Ordering::Relaxed)
+            + self.secondary_memory_map_memory_use.load(Ordering::Relaxed);
+        let level1_runs = match self.levels.read().first() {
+            Some(level) => level.read().num_sorted_runs(),
+            None => 0,
+        };
+        return WriteStall::from_config(&self.config, memory_use, level1_runs);
+    }
+}
+
+/// A handle to one of the independent keyspaces in a `RustStore`.
+///
+/// Each column family has its own memory maps, levels and tuning, but every family in a store
+/// shares its write ahead log, manifest and background threads. Writes to several families can
+/// be made atomic with `WriteBatch::put_cf` and friends. Column families are declared with
+/// `Config::add_column_family` and looked up with `RustStore::column_family`.
+///
+/// # Examples
+///
+/// ```
+/// use rust_kv::{Config, RustStore, WriteBatch};
+/// let mut config = Config::default();
+/// config.add_column_family("users", Config::default());
+/// let db = RustStore::new(Some(config)).unwrap();
+/// let users = db.column_family("users").unwrap();
+/// let mut batch = WriteBatch::new();
+/// batch.put(1, vec![1u8]);
+/// batch.put_cf(&users, 1, vec![2u8]);
+/// db.write(batch).unwrap();
+/// assert_eq!(db.get(&1), Some(vec![1u8]));
+/// assert_eq!(users.get(&1), Some(vec![2u8]));
+/// ```
+pub struct ColumnFamily<K: Key> {
+    lsm: Arc<Lsm<K>>,
+    data: Arc<ColumnFamilyData<K>>,
+}
+
+impl<K: Key> ColumnFamily<K> {
+    pub(crate) fn new(lsm: Arc<Lsm<K>>, data: Arc<ColumnFamilyData<K>>) -> ColumnFamily<K> {
+        return ColumnFamily {
+            lsm: lsm,
+            data: data,
+        };
+    }
+
+    pub(crate) fn id(self: &Self) -> u32 {
+        return self.data.id;
+    }
+
+    pub fn name(self: &Self) -> &str {
+        return &self.data.name;
+    }
+
+    /// Get a value from the column family, see `RustStore::get`
+    pub fn get<Q>(self: &Self, key: &Q) -> Option<Vec<u8>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + Hash + Debug + ToOwned<Owned = K> + ?Sized,
+    {
+        return self.data.get_at(key, self.lsm.last_seq());
+    }
+
+    /// Iterate over the key value pairs in `range`, see `RustStore::range`
+    pub fn range<R: RangeBounds<K>>(self: &Self, range: R) -> impl Iterator<Item = (K, Vec<u8>)> {
+        return self.data.range_at(range, self.lsm.last_seq());
+    }
+
+    pub fn put(self: &Self, key: K, value: Vec<u8>) -> Result<(), RustStoreError> {
+        return self.write(WalRecord::Put {
+            key: key,
+            value: value,
+        });
+    }
+
+    /// Put a key value pair which expires after `ttl`, see `RustStore::put_with_ttl`
+    pub fn put_with_ttl(
+        self: &Self,
+        key: K,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<(), RustStoreError> {
+        return self.write(WalRecord::ExpiringPut {
+            key: key,
+            value: value,
+            expires_at: expires_at(ttl),
+        });
+    }
+
+    /// Write an operand for the family's merge operator, see `RustStore::merge`
+    pub fn merge(self: &Self, key: K, operand: Vec<u8>) -> Result<(), RustStoreError> {
+        if self.data.config.merge_operator.is_none() {
+            return Err(LsmError::NoMergeOperator.into());
+        }
+        return self.write(WalRecord::Merge {
+            key: key,
+            value: operand,
+        });
+    }
+
+    pub fn delete(self: &Self, key: &K) -> Result<(), RustStoreError> {
+        return self.write(WalRecord::Delete { key: key.clone() });
+    }
+
+    /// Delete every key in `start..end`, see `RustStore::delete_range`
+    pub fn delete_range(self: &Self, start: K, end: K) -> Result<(), RustStoreError> {
+        if start >= end {
+            return Ok(());
+        }
+        return self.write(WalRecord::DeleteRange {
+            start: start,
+            end: end,
+        });
+    }
+
+    /// Merge every run file of the column family holding keys in `range` down into its deepest
+    /// level, see `RustStore::compact_range`
+    pub fn compact_range<R: RangeBounds<K>>(
+        self: &Self,
+        range: R,
+    ) -> Result<CompactionReport, RustStoreError> {
+        return Ok(self.lsm.compact_column_family_range(
+            &self.data,
+            range.start_bound().cloned(),
+            range.end_bound().cloned(),
+        )?);
+    }
+
+    /// Merge every run file of the column family into its deepest level, see
+    /// `RustStore::compact_all`
+    pub fn compact_all(self: &Self) -> Result<CompactionReport, RustStoreError> {
+        return self.compact_range(..);
+    }
+
+    fn write(self: &Self, record: WalRecord<K>) -> Result<(), RustStoreError> {
+        self.lsm.write_to_column_family(self.data.id, record)?;
+        return Ok(());
+    }
+}
+
+impl<K: Key> Clone for ColumnFamily<K> {
+    fn clone(&self) -> ColumnFamily<K> {
+        return ColumnFamily::new(self.lsm.clone(), self.data.clone());
+    }
+}
@@ -0,0 +1,254 @@
+use crate::lsm::LsmError;
+use crate::rust_store::{Config, RustStoreError};
+use std::ops::AddAssign;
+
+/// A compaction picked by a `CompactionPolicy`, levels are 1 indexed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compaction {
+    /// Merge every run in the level into a single run in the same level
+    MergeLevel { level: usize },
+    /// Merge every run in the level into a single run in the next level, together with the runs
+    /// already in the next level if `merge_with_next` is set
+    MoveDown { level: usize, merge_with_next: bool },
+}
+
+/// The number of runs in a level and how many memory map flushes their writes came from
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LevelShape {
+    pub num_runs: usize,
+    pub flushes: u64,
+}
+
+/// What compactions have read, written and dropped.
+///
+/// Versions read but not written were shadowed by newer ones no snapshot reads, expired,
+/// folded together by the merge operator or deleted. Deletes are dropped once they reach the
+/// bottom level, unless a live snapshot is older than them.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CompactionStats {
+    /// Compactions run
+    pub compactions: u64,
+    /// Versions read from the runs merged
+    pub entries_read: u64,
+    /// Versions written to the runs produced
+    pub entries_written: u64,
+    /// Point deletes read but not written
+    pub tombstones_dropped: u64,
+    /// Range deletes dropped at the bottom level
+    pub range_tombstones_dropped: u64,
+}
+
+impl AddAssign for CompactionStats {
+    fn add_assign(&mut self, other: CompactionStats) {
+        self.compactions += other.compactions;
+        self.entries_read += other.entries_read;
+        self.entries_written += other.entries_written;
+        self.tombstones_dropped += other.tombstones_dropped;
+        self.range_tombstones_dropped += other.range_tombstones_dropped;
+    }
+}
+
+/// What a compaction requested through `RustStore::compact_range` or `compact_all` rewrote
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CompactionReport {
+    /// Level the merged files were written to, None if there was nothing to compact
+    pub output_level: Option<usize>,
+    /// Run files merged and removed
+    pub files_read: usize,
+    /// Run files written, none if every version read was dropped
+    pub files_written: usize,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub stats: CompactionStats,
+}
+
+/// Decides when levels are compacted from `t`, `k` and `z`.
+///
+/// Level `i` holds up to `t^i` memory map flushes worth of runs, once it is full its runs move
+/// down to the next level. Every level may hold up to `k` runs except for the largest, which
+/// may hold `z`, a level with more runs is merged into a single run where it is.
+///
+/// `k = z = 1` is leveling, `k = z = t - 1` is tiering and `k = t - 1, z = 1` is lazy leveling,
+/// where every level is tiered but the largest.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompactionPolicy {
+    t: u64,
+    k: u64,
+    z: u64,
+}
+
+impl CompactionPolicy {
+    pub fn new(t: u64, k: u64, z: u64) -> CompactionPolicy {
+        return CompactionPolicy { t: t, k: k, z: z };
+    }
+
+    /// The policy set by `t`, `k` and `z` of `config`, which are public fields and so are
+    /// checked again here
+    pub fn from_config(config: &Config) -> Result<CompactionPolicy, LsmError> {
+        check_policy(config.t, config.k, config.z).map_err(LsmError::InvalidOption)?;
+        return Ok(CompactionPolicy::new(config.t, config.k, config.z));
+    }
+
+    /// One run per level, fails if `t` is below 2
+    pub fn leveling(t: u64) -> Result<CompactionPolicy, RustStoreError> {
+        check_policy(t, 1, 1).map_err(RustStoreError::OptionParsingError)?;
+        return Ok(CompactionPolicy::new(t, 1, 1));
+    }
+
+    /// Runs are only merged once a level is full, fails if `t` is below 2
+    pub fn tiering(t: u64) -> Result<CompactionPolicy, RustStoreError> {
+        // k and z are at least 1 once t is at least 2
+        check_policy(t, 1, 1).map_err(RustStoreError::OptionParsingError)?;
+        return Ok(CompactionPolicy::new(t, t - 1, t - 1));
+    }
+
+    /// Tiering for every level but the largest, which is leveled, fails if `t` is below 2
+    pub fn lazy_leveling(t: u64) -> Result<CompactionPolicy, RustStoreError> {
+        check_policy(t, 1, 1).map_err(RustStoreError::OptionParsingError)?;
+        return Ok(CompactionPolicy::new(t, t - 1, 1));
+    }
+
+    /// Memory map flushes level `level` holds once it is full
+    fn capacity(self: &Self, level: usize) -> u64 {
+        return self.t.saturating_pow(level as u32);
+    }
+
+    fn max_runs(self: &Self, largest: bool) -> usize {
+        return if largest { self.z } else { self.k } as usize;
+    }
+
+    /// The next compaction to run given the shape of each level, None if every level is within
+    /// the policy. Upper levels are compacted first.
+    pub fn pick(self: &Self, levels: &[LevelShape]) -> Option<Compaction> {
+        let largest = levels.iter().rposition(|l| l.num_runs > 0)?;
+        for (i, shape) in levels.iter().enumerate().take(largest + 1) {
+            let level = i + 1;
+            if shape.num_runs == 0 {
+                continue;
+            }
+            if shape.flushes >= self.capacity(level) {
+                // the next level is the largest one, or becomes it
+                let next = levels.get(i + 1).cloned().unwrap_or_default();
+                // merging into a level which is then full anyway would rewrite it twice
+                let merge_with_next = next.num_runs + 1 > self.max_runs(level >= largest)
+                    && next.flushes + shape.flushes < self.capacity(level + 1);
+                return Some(Compaction::MoveDown {
+                    level: level,
+                    merge_with_next: merge_with_next,
+                });
+            }
+            if shape.num_runs > self.max_runs(i == largest) {
+                return Some(Compaction::MergeLevel { level: level });
+            }
+        }
+        return None;
+    }
+}
+
+/// Levels only grow with a size ratio `t` of at least 2, and every level has to be able to hold
+/// a run. Returns what is wrong with `t`, `k` and `z` otherwise.
+pub(crate) fn check_policy(t: u64, k: u64, z: u64) -> Result<(), String> {
+    if t < 2 {
+        return Err("size ratio t must be at least 2".parse().unwrap());
+    }
+    if k == 0 || z == 0 {
+        return Err("every level must be able to hold a run".parse().unwrap());
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod test_compaction {
+    use crate::compaction::{Compaction, CompactionPolicy, LevelShape};
+    use test_case::test_case;
+
+    // Flush `num_flushes` memory maps into level 1, running every compaction the policy picks
+    // after each one, and return the number of runs in each level
+    fn simulate(policy: CompactionPolicy, num_flushes: usize) -> Vec<usize> {
+        // the flushes each run in each level came from
+        let mut levels: Vec<Vec<u64>> = vec![];
+        for _ in 0..num_flushes {
+            if levels.is_empty() {
+                levels.push(vec![]);
+            }
+            levels[0].push(1);
+            loop {
+                let shapes: Vec<LevelShape> = levels
+                    .iter()
+                    .map(|runs| LevelShape {
+                        num_runs: runs.len(),
+                        flushes: runs.iter().sum(),
+                    })
+                    .collect();
+                match policy.pick(&shapes) {
+                    Some(Compaction::MergeLevel { level }) => {
+                        let merged = levels[level - 1].drain(..).sum();
+                        levels[level - 1].push(merged);
+                    }
+                    Some(Compaction::MoveDown {
+                        level,
+                        merge_with_next,
+                    }) => {
+                        let mut merged: u64 = levels[level - 1].drain(..).sum();
+                        if levels.len() == level {
+                            levels.push(vec![]);
+                        }
+                        if merge_with_next {
+                            merged += levels[level].drain(..).sum::<u64>();
+                        }
+                        levels[level].push(merged);
+                    }
+                    None => break,
+                }
+            }
+        }
+        return levels.iter().map(|runs| runs.len()).collect();
+    }
+
+    #[test_case(CompactionPolicy::leveling(3).unwrap(), 2 => vec![1]; "leveling merges in place")]
+    #[test_case(CompactionPolicy::leveling(3).unwrap(), 3 => vec![0, 1]; "leveling moves full level")]
+    #[test_case(CompactionPolicy::leveling(3).unwrap(), 8 => vec![1, 1]; "leveling two levels")]
+    #[test_case(CompactionPolicy::leveling(3).unwrap(), 9 => vec![0, 0, 1]; "leveling three levels")]
+    #[test_case(CompactionPolicy::tiering(3).unwrap(), 2 => vec![2]; "tiering keeps runs")]
+    #[test_case(CompactionPolicy::tiering(3).unwrap(), 8 => vec![2, 2]; "tiering two levels")]
+    #[test_case(CompactionPolicy::tiering(3).unwrap(), 9 => vec![0, 0, 1]; "tiering three levels")]
+    #[test_case(CompactionPolicy::lazy_leveling(3).unwrap(), 2 => vec![1]; "lazy leveling largest level")]
+    #[test_case(CompactionPolicy::lazy_leveling(3).unwrap(), 8 => vec![2, 1]; "lazy leveling two levels")]
+    #[test_case(CompactionPolicy::lazy_leveling(3).unwrap(), 9 => vec![0, 0, 1]; "lazy leveling three levels")]
+    #[test_case(CompactionPolicy::lazy_leveling(3).unwrap(), 26 => vec![2, 2, 1]; "lazy leveling full")]
+    fn runs_per_level(policy: CompactionPolicy, num_flushes: usize) -> Vec<usize> {
+        return simulate(policy, num_flushes);
+    }
+
+    #[test_case(0 ; "zero")]
+    #[test_case(1 ; "one")]
+    fn policies_need_size_ratio_of_two(t: u64) {
+        assert!(CompactionPolicy::leveling(t).is_err());
+        assert!(CompactionPolicy::tiering(t).is_err());
+        assert!(CompactionPolicy::lazy_leveling(t).is_err());
+    }
+
+    #[test]
+    fn policies_bound_runs_per_level() {
+        for t in 2..6 {
+            let policies = [
+                CompactionPolicy::leveling(t).unwrap(),
+                CompactionPolicy::tiering(t).unwrap(),
+                CompactionPolicy::lazy_leveling(t).unwrap(),
+                CompactionPolicy::new(t, 2, 1),
+            ];
+            for policy in policies.iter() {
+                for num_flushes in 1..100 {
+                    let runs = simulate(*policy, num_flushes);
+                    let largest = runs.iter().rposition(|&r| r > 0).unwrap();
+                    for (i, num_runs) in runs.iter().enumerate() {
+                        let max_runs = if i == largest { policy.z } else { policy.k };
+                        assert!(*num_runs as u64 <= max_runs, "{:?} {:?}", policy, runs);
+                    }
+                    // the number of levels grows with the log of the data
+                    assert!(runs.len() <= 1 + (num_flushes as f64).log(t as f64).ceil() as usize);
+                }
+            }
+        }
+    }
+}
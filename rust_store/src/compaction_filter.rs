@@ -0,0 +1,44 @@
+use std::any::Any;
+
+/// What a `CompactionFilter` does with a value
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterDecision {
+    /// Leave the value as it is
+    Keep,
+    /// Delete the key, as if `RustStore::delete` had been called when the value was written
+    Remove,
+    /// Replace the value, a time to live it was put with is kept
+    ChangeValue(Vec<u8>),
+}
+
+/// Decides what happens to values as compactions rewrite them, e.g. to migrate them to a new
+/// schema or purge them without scanning and deleting through the store.
+///
+/// The filter sees every put and merge operand in the runs a compaction merges, older versions
+/// included, so versions live snapshots read are filtered as well. Removing a put deletes the
+/// key as of that version and removing an operand drops it. Flushes don't call the filter, so a
+/// value can be read unfiltered until it is compacted. Deletes have no value and are passed
+/// through as they are.
+///
+/// # Examples
+///
+/// ```
+/// use rust_kv::{CompactionFilter, FilterDecision};
+/// use std::any::Any;
+///
+/// /// Removes every key below 100
+/// struct Purge;
+///
+/// impl CompactionFilter for Purge {
+///     fn filter(&self, key: &dyn Any, _value: &[u8]) -> FilterDecision {
+///         return match key.downcast_ref::<i32>() {
+///             Some(key) if *key < 100 => FilterDecision::Remove,
+///             _ => FilterDecision::Keep,
+///         };
+///     }
+/// }
+/// ```
+pub trait CompactionFilter: Send + Sync {
+    /// Decide what to do with `value`, `key` is the store's key type and can be downcast to it.
+    fn filter(&self, key: &dyn Any, value: &[u8]) -> FilterDecision;
+}
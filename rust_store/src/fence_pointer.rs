@@ -1,7 +1,9 @@
 pub mod fence_pointer {
     use serde::{Deserialize, Serialize};
+    use std::borrow::Borrow;
     use std::cmp::Ord;
     use std::mem::size_of;
+    use std::ops::Bound;
 
     // T is the Key type
     #[derive(Serialize, Deserialize, Debug)]
@@ -11,8 +13,36 @@ pub mod fence_pointer {
     }
 
     impl<T: Ord> FencePointer<T> {
-        pub fn in_range(&self, x: &T) -> bool {
-            return *x >= self.low && *x <= self.high;
+        pub fn in_range<Q>(&self, x: &Q) -> bool
+        where
+            T: Borrow<Q>,
+            Q: Ord + ?Sized,
+        {
+            return x >= self.low.borrow() && x <= self.high.borrow();
+        }
+        /// True if every key in the block sorts before a range starting at `start`
+        pub fn is_before(&self, start: Bound<&T>) -> bool {
+            return match start {
+                Bound::Included(s) => self.high < *s,
+                Bound::Excluded(s) => self.high <= *s,
+                Bound::Unbounded => false,
+            };
+        }
+        /// True if every key in the block sorts after a range ending at `end`
+        pub fn is_after(&self, end: Bound<&T>) -> bool {
+            return match end {
+                Bound::Included(e) => self.low > *e,
+                Bound::Excluded(e) => self.low >= *e,
+                Bound::Unbounded => false,
+            };
+        }
+        /// The smallest key in the block
+        pub fn low(&self) -> &T {
+            return &self.low;
+        }
+        /// The largest key in the block
+        pub fn high(&self) -> &T {
+            return &self.high;
         }
         pub fn new(l: T, h: T) -> FencePointer<T> {
             return FencePointer { low: l, high: h };
@@ -26,6 +56,7 @@ pub mod fence_pointer {
 #[cfg(test)]
 mod test_fp {
     use crate::fence_pointer::fence_pointer::FencePointer;
+    use std::ops::Bound;
     #[test]
     fn fp_int() {
         let fp = FencePointer::new(1, 5);
@@ -63,4 +94,17 @@ mod test_fp {
         assert!(serde_fp.in_range(&5));
         assert!(!serde_fp.in_range(&7));
     }
+
+    #[test]
+    fn fp_range_bounds() {
+        let fp = FencePointer::new(10, 20);
+        assert!(fp.is_before(Bound::Included(&21)));
+        assert!(fp.is_before(Bound::Excluded(&20)));
+        assert!(!fp.is_before(Bound::Included(&20)));
+        assert!(!fp.is_before(Bound::Unbounded));
+        assert!(fp.is_after(Bound::Included(&9)));
+        assert!(fp.is_after(Bound::Excluded(&10)));
+        assert!(!fp.is_after(Bound::Included(&10)));
+        assert!(!fp.is_after(Bound::Unbounded));
+    }
 }
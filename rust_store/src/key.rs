@@ -0,0 +1,324 @@
+use crossbeam_skiplist::SkipMap;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A type that can be used as a key in a RustStore.
+///
+/// Keys are ordered with `Ord`, hashed into the bloom filters and serialized with bincode into
+/// the write ahead log and the run files. It is implemented for integers, strings, vectors
+/// (so `Vec<u8>` byte strings) and tuples of keys. Other types, e.g. a struct for composite
+/// keys, only need `impl Key for MyKey {}` once they derive the supertraits.
+pub trait Key:
+    Ord + Clone + Debug + Hash + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+    /// Name of the ordering of the keys. It is written into the manifest and every run, and a
+    /// store can't be reopened with keys that order differently.
+    fn comparator_name() -> &'static str {
+        return "rust_kv.Ord";
+    }
+}
+
+macro_rules! impl_key {
+    ($($t:ty),*) => {
+        $(impl Key for $t {})*
+    };
+}
+
+impl_key!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize, bool, char, String);
+
+impl<T: Key> Key for Vec<T> {}
+impl<A: Key, B: Key> Key for (A, B) {}
+impl<A: Key, B: Key, C: Key> Key for (A, B, C) {}
+impl<A: Key, B: Key, C: Key, D: Key> Key for (A, B, C, D) {}
+
+/// A key together with the sequence number of the write that produced it.
+///
+/// Ordered by key and then newest (highest sequence number) first, so the first entry at or
+/// after `InternalKey::new(key, seq)` is the version of `key` a read at `seq` should see.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InternalKey<K: Key> {
+    pub key: K,
+    pub seq: u64,
+}
+
+impl<K: Key> InternalKey<K> {
+    pub fn new(key: K, seq: u64) -> InternalKey<K> {
+        return InternalKey { key: key, seq: seq };
+    }
+}
+
+impl<K: Key> PartialOrd for InternalKey<K> {
+    fn partial_cmp(&self, other: &InternalKey<K>) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl<K: Key> Ord for InternalKey<K> {
+    fn cmp(&self, other: &InternalKey<K>) -> Ordering {
+        return self
+            .key
+            .cmp(&other.key)
+            .then_with(|| other.seq.cmp(&self.seq));
+    }
+}
+
+/// What a write did to a key
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Value {
+    Put(Vec<u8>),
+    Delete,
+    /// An operand for the merge operator, folded into the older versions of the key when read
+    Merge(Vec<u8>),
+    /// A put which reads as a delete from `expires_at`, in milliseconds since the unix epoch
+    Expiring {
+        value: Vec<u8>,
+        expires_at: u64,
+    },
+}
+
+impl Value {
+    /// Whether this is a put whose time to live has passed by `now`, see `now_millis`
+    pub fn is_expired(self: &Self, now: u64) -> bool {
+        return match self {
+            Value::Expiring { expires_at, .. } => *expires_at <= now,
+            _ => false,
+        };
+    }
+}
+
+/// Milliseconds since the unix epoch, the clock expiry times are measured with
+pub fn now_millis() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64);
+}
+
+/// When a put with a time to live of `ttl` written now expires, in milliseconds since the unix
+/// epoch. Times past the end of the clock never expire.
+pub fn expires_at(ttl: Duration) -> u64 {
+    let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+    return now_millis().saturating_add(ttl);
+}
+
+/// Every version of every key written since the last flush
+pub type MemoryMap<K> = SkipMap<InternalKey<K>, Value>;
+
+/// Deletes every key in `start..end` written before it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct RangeTombstone<K: Key> {
+    pub start: K,
+    pub end: K,
+    pub seq: u64,
+}
+
+impl<K: Key> RangeTombstone<K> {
+    pub fn new(start: K, end: K, seq: u64) -> RangeTombstone<K> {
+        return RangeTombstone {
+            start: start,
+            end: end,
+            seq: seq,
+        };
+    }
+
+    pub fn covers<Q>(self: &Self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        return self.start.borrow() <= key && key < self.end.borrow();
+    }
+
+    /// Sequence number of the newest of `tombstones` written at or before `seq` which
+    /// covers `key`
+    pub fn newest_covering<Q>(tombstones: &[RangeTombstone<K>], key: &Q, seq: u64) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        return tombstones
+            .iter()
+            .filter(|t| t.seq <= seq && t.covers(key))
+            .map(|t| t.seq)
+            .max();
+    }
+}
+
+/// A user supplied ordering of byte string keys, used through `Bytes<C>`.
+pub trait Comparator: Send + Sync + 'static {
+    /// Stored with every run, it must change whenever the ordering does.
+    const NAME: &'static str;
+
+    /// Must only return `Ordering::Equal` for identical byte strings, keys are hashed as bytes.
+    fn compare(a: &[u8], b: &[u8]) -> Ordering;
+}
+
+/// Orders byte strings the same way as `Vec<u8>` and `[u8]`
+pub struct Lexicographic;
+
+impl Comparator for Lexicographic {
+    const NAME: &'static str = "rust_kv.Lexicographic";
+
+    fn compare(a: &[u8], b: &[u8]) -> Ordering {
+        return a.cmp(b);
+    }
+}
+
+/// A variable length byte string key ordered by the comparator `C`.
+///
+/// ```
+/// use rust_kv::{Bytes, Config, RustStore};
+/// let db: RustStore<Bytes> = RustStore::new(Some(Config::default())).unwrap();
+/// db.put(Bytes::from(&b"key"[..]), vec![1u8]).unwrap();
+/// assert_eq!(db.get(&Bytes::from(&b"key"[..])), Some(vec![1u8]));
+/// ```
+pub struct Bytes<C: Comparator = Lexicographic> {
+    bytes: Vec<u8>,
+    comparator: PhantomData<C>,
+}
+
+impl<C: Comparator> Bytes<C> {
+    pub fn new(bytes: Vec<u8>) -> Bytes<C> {
+        return Bytes {
+            bytes: bytes,
+            comparator: PhantomData,
+        };
+    }
+
+    pub fn as_bytes(self: &Self) -> &[u8] {
+        return &self.bytes;
+    }
+
+    pub fn into_vec(self: Self) -> Vec<u8> {
+        return self.bytes;
+    }
+}
+
+impl<C: Comparator> Key for Bytes<C> {
+    fn comparator_name() -> &'static str {
+        return C::NAME;
+    }
+}
+
+impl<C: Comparator> From<Vec<u8>> for Bytes<C> {
+    fn from(bytes: Vec<u8>) -> Bytes<C> {
+        return Bytes::new(bytes);
+    }
+}
+
+impl<C: Comparator> From<&[u8]> for Bytes<C> {
+    fn from(bytes: &[u8]) -> Bytes<C> {
+        return Bytes::new(bytes.to_vec());
+    }
+}
+
+// The traits are implemented by hand as deriving them would require them of C as well
+
+impl<C: Comparator> Clone for Bytes<C> {
+    fn clone(&self) -> Bytes<C> {
+        return Bytes::new(self.bytes.clone());
+    }
+}
+
+impl<C: Comparator> Debug for Bytes<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "Bytes({:?})", self.bytes);
+    }
+}
+
+impl<C: Comparator> PartialEq for Bytes<C> {
+    fn eq(&self, other: &Bytes<C>) -> bool {
+        return C::compare(&self.bytes, &other.bytes) == Ordering::Equal;
+    }
+}
+
+impl<C: Comparator> Eq for Bytes<C> {}
+
+impl<C: Comparator> PartialOrd for Bytes<C> {
+    fn partial_cmp(&self, other: &Bytes<C>) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl<C: Comparator> Ord for Bytes<C> {
+    fn cmp(&self, other: &Bytes<C>) -> Ordering {
+        return C::compare(&self.bytes, &other.bytes);
+    }
+}
+
+impl<C: Comparator> Hash for Bytes<C> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.bytes.hash(state);
+    }
+}
+
+impl<C: Comparator> Serialize for Bytes<C> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        return self.bytes.serialize(serializer);
+    }
+}
+
+impl<'de, C: Comparator> Deserialize<'de> for Bytes<C> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Bytes<C>, D::Error> {
+        return Ok(Bytes::new(Vec::deserialize(deserializer)?));
+    }
+}
+
+#[cfg(test)]
+mod test_key {
+    use crate::key::{Bytes, Comparator, InternalKey, Key, Lexicographic};
+    use std::cmp::Ordering;
+
+    struct Reverse;
+
+    impl Comparator for Reverse {
+        const NAME: &'static str = "test.Reverse";
+
+        fn compare(a: &[u8], b: &[u8]) -> Ordering {
+            return b.cmp(a);
+        }
+    }
+
+    #[test]
+    fn bytes_use_comparator() {
+        let a: Bytes<Lexicographic> = Bytes::from(&b"a"[..]);
+        let ab: Bytes<Lexicographic> = Bytes::from(&b"ab"[..]);
+        assert!(a < ab);
+
+        let a: Bytes<Reverse> = Bytes::from(&b"a"[..]);
+        let ab: Bytes<Reverse> = Bytes::from(&b"ab"[..]);
+        assert!(a > ab);
+        assert_eq!(a.clone(), a);
+    }
+
+    #[test]
+    fn bytes_serialize_as_bytes() {
+        let key: Bytes<Reverse> = Bytes::from(vec![1u8, 2u8, 3u8]);
+        let ser = bincode::serialize(&key).unwrap();
+        assert_eq!(ser, bincode::serialize(&vec![1u8, 2u8, 3u8]).unwrap());
+        let de: Bytes<Reverse> = bincode::deserialize(&ser).unwrap();
+        assert_eq!(de.as_bytes(), &[1u8, 2u8, 3u8]);
+    }
+
+    #[test]
+    fn internal_keys_order_newest_first() {
+        assert!(InternalKey::new(1, 5) < InternalKey::new(1, 4));
+        assert!(InternalKey::new(1, 0) < InternalKey::new(2, 9));
+        assert_eq!(InternalKey::new(1, 5), InternalKey::new(1, 5));
+    }
+
+    #[test]
+    fn comparator_names() {
+        assert_eq!(<Vec<u8>>::comparator_name(), "rust_kv.Ord");
+        assert_eq!(<Bytes>::comparator_name(), "rust_kv.Lexicographic");
+        assert_eq!(<Bytes<Reverse>>::comparator_name(), "test.Reverse");
+    }
+}
@@ -1,8 +1,31 @@
 pub mod bloom_filter;
+pub mod column_family;
+pub mod compaction;
+pub mod compaction_filter;
 pub mod fence_pointer;
+pub mod key;
 pub mod lsm;
+pub mod manifest;
+pub mod merge_operator;
+pub mod rate_limiter;
 pub mod run;
 pub mod rust_store;
+pub mod snapshot;
+pub mod transaction;
+pub mod wal;
 pub mod workload_generator;
+pub mod write_batch;
+pub mod write_stall;
 
+pub use column_family::ColumnFamily;
+pub use compaction::{CompactionReport, CompactionStats};
+pub use compaction_filter::{CompactionFilter, FilterDecision};
+pub use key::{Bytes, Comparator, Key, Lexicographic};
+pub use merge_operator::MergeOperator;
+pub use rate_limiter::{IoPriority, RateLimiter};
 pub use rust_store::{Config, RustStore, RustStoreError};
+pub use snapshot::Snapshot;
+pub use transaction::{Transaction, TransactionError, TransactionMode};
+pub use wal::SyncPolicy;
+pub use write_batch::WriteBatch;
+pub use write_stall::WriteStallStats;
@@ -1,306 +1,1280 @@
+use crate::column_family::{ColumnFamilyData, DEFAULT_COLUMN_FAMILY, DEFAULT_COLUMN_FAMILY_NAME};
+use crate::compaction::{
+    Compaction, CompactionPolicy, CompactionReport, CompactionStats, LevelShape,
+};
+use crate::key::{expires_at, Key, RangeTombstone, Value};
+use crate::manifest::{Manifest, ManifestError, VersionEdit};
 use crate::run::{Level, Run, RunError};
 
 // use crate::run_manager::run_manager;
 use crate::rust_store;
 use crate::rust_store::Config;
-use crossbeam_skiplist::SkipMap;
-use log::{debug, error, info, trace, warn};
-use parking_lot::RwLock;
-use serde::{Deserialize, Serialize};
-use std::borrow::BorrowMut;
+use crate::wal::{Wal, WalError, WalRecord};
+use crate::write_batch::WriteBatch;
+use crate::write_stall::{WriteStall, WriteStallStats};
+use log::{error, info, warn};
+use parking_lot::{Condvar, Mutex, MutexGuard, RwLock};
+use std::borrow::Borrow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
+use std::fs::{metadata, remove_file};
+use std::hash::{Hash, Hasher};
+use std::ops::{Bound, RangeBounds};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
 use std::thread;
-use std::time::Duration;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum LsmError {
     #[error("Error Deserializing")]
     RunError(#[from] RunError),
+    #[error("Error in the write ahead log")]
+    WalError(#[from] WalError),
+    #[error("Error in the manifest")]
+    ManifestError(#[from] ManifestError),
+    #[error("No merge operator is set")]
+    NoMergeOperator,
+    #[error("The store has been closed")]
+    Closed,
+    #[error("Invalid option: '{0}'")]
+    InvalidOption(String),
     #[error("Not yet implemented")]
     NotImplemented,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct Catalogue {
-    // each outer vector is a level ordered 1 -> n
-    // each inner vector is a run, earlier runs are older
-    levels: Vec<Vec<PathBuf>>,
+// Manifests are rolled over once they reach this size
+const MAX_MANIFEST_SIZE: u64 = 4 * 1024 * 1024;
+
+// Writers blocked by a write stall check again this often, in case the flush or compaction
+// they wait on failed and has to be signalled again
+const STALL_RECHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// Number of locks keys are hashed to by `KeyLocks`
+const KEY_LOCK_STRIPES: usize = 64;
+
+/// Runs removed from and added to the levels of one column family
+struct VersionChange<'a, K: Key> {
+    family: &'a ColumnFamilyData<K>,
+    removed: Vec<(usize, Arc<Run<K>>)>,
+    added: Vec<(usize, Run<K>)>,
+}
+
+/// Wakes a background thread when there is work for it.
+///
+/// A notification sent while the thread is busy is kept until it next waits, so work signalled
+/// during a flush or compaction is picked up as soon as that is done.
+struct Signal {
+    state: Mutex<SignalState>,
+    wakeup: Condvar,
+}
+
+struct SignalState {
+    pending: bool,
+    closed: bool,
 }
 
-impl Catalogue {
-    pub fn get_levels(self: Self) -> Vec<RwLock<Level>> {
-        // for each path
-        unimplemented!();
+impl Signal {
+    fn new() -> Signal {
+        return Signal {
+            state: Mutex::new(SignalState {
+                pending: false,
+                closed: false,
+            }),
+            wakeup: Condvar::new(),
+        };
+    }
+
+    fn notify(self: &Self) {
+        self.state.lock().pending = true;
+        self.wakeup.notify_one();
+    }
+
+    /// Wake the thread up for good, `wait` returns false from now on
+    fn close(self: &Self) {
+        self.state.lock().closed = true;
+        self.wakeup.notify_all();
+    }
+
+    /// Block until notified, returns false once the signal is closed
+    fn wait(self: &Self) -> bool {
+        let mut state = self.state.lock();
+        while !state.pending && !state.closed {
+            self.wakeup.wait(&mut state);
+        }
+        state.pending = false;
+        return !state.closed;
+    }
+}
+
+/// Locks over keys, hashed into a fixed number of stripes.
+///
+/// Every write holds the stripes of the keys it writes until it is in a memory map, and
+/// conditional writes also hold them while checking, so a check reading runs from disk only
+/// holds up writes to keys sharing its stripes.
+struct KeyLocks {
+    stripes: Vec<Mutex<()>>,
+}
+
+impl KeyLocks {
+    fn new() -> KeyLocks {
+        return KeyLocks {
+            stripes: (0..KEY_LOCK_STRIPES).map(|_| Mutex::new(())).collect(),
+        };
+    }
+
+    fn stripe<K: Key>(self: &Self, key: &K) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        return (hasher.finish() % self.stripes.len() as u64) as usize;
+    }
+
+    // Add the stripes of every key `record` writes, a range delete takes all of them
+    fn add_record_stripes<K: Key>(
+        self: &Self,
+        record: &WalRecord<K>,
+        stripes: &mut BTreeSet<usize>,
+    ) {
+        match record {
+            WalRecord::Put { key, .. }
+            | WalRecord::Delete { key }
+            | WalRecord::Merge { key, .. }
+            | WalRecord::ExpiringPut { key, .. } => {
+                stripes.insert(self.stripe(key));
+            }
+            WalRecord::Batch { records } => {
+                for record in records {
+                    self.add_record_stripes(record, stripes);
+                }
+            }
+            WalRecord::DeleteRange { .. } => stripes.extend(0..self.stripes.len()),
+            WalRecord::ColumnFamily { record, .. } => self.add_record_stripes(record, stripes),
+        }
+    }
+
+    /// Lock the stripes of `keys` and of every key `record` writes. Stripes are always taken
+    /// in order, so writers can't deadlock.
+    fn lock<K: Key>(self: &Self, record: &WalRecord<K>, keys: &[K]) -> Vec<MutexGuard<'_, ()>> {
+        let mut stripes: BTreeSet<usize> = keys.iter().map(|key| self.stripe(key)).collect();
+        self.add_record_stripes(record, &mut stripes);
+        return stripes.iter().map(|i| self.stripes[*i].lock()).collect();
     }
 }
 
-pub struct Lsm {
-    primary_memory_map: Arc<SkipMap<i32, Option<Vec<u8>>>>,
-    secondary_memory_map: Arc<SkipMap<i32, Option<Vec<u8>>>>,
+pub struct Lsm<K: Key> {
+    /// Every column family by id, the default family included
+    column_families: RwLock<BTreeMap<u32, Arc<ColumnFamilyData<K>>>>,
+    /// Which memory map of every column family takes new writes
     use_primary_map: AtomicBool,
-    primary_memory_map_memory_use: AtomicU64,
-    secondary_memory_map_memory_use: AtomicU64,
     config: rust_store::Config,
-    levels: RwLock<Vec<RwLock<Level>>>,
     /// Signal to threads that we are shutting down
     time_to_shutdown: AtomicBool,
+    /// None if there is no directory to keep the log in
+    wal: Option<Wal>,
+    /// Records which runs make up each level and holds the directory's lock, None if there is
+    /// no directory or once the store is closed
+    manifest: Mutex<Option<Manifest>>,
+    /// Held while choosing a memory map and logging/inserting a write, and while switching
+    /// memory maps, so every write ends up in the map that owns its log segment.
+    write_lock: Mutex<()>,
+    /// Held by writes from before their check until they are in a memory map, see `KeyLocks`
+    key_locks: KeyLocks,
+    /// Sequence number of the newest write visible to readers
+    last_seq: AtomicU64,
+    /// Sequence numbers of live snapshots, with the number of snapshots at each
+    snapshots: Mutex<BTreeMap<u64, usize>>,
+    /// The first log segment of the active map, older segments belong to the inactive map
+    inactive_map_wal_segment: Mutex<Option<u64>>,
+    /// Held while flushing so the manager and `compact_range` don't flush the same maps at once
+    flush_lock: Mutex<()>,
+    /// Held while compacting so only one compaction picks from the levels at a time
+    compaction_lock: Mutex<()>,
+    /// Totals over every compaction since the store was opened
+    compaction_stats: Mutex<CompactionStats>,
+    /// Raised by writers once a memory map is over budget, the flush thread waits on it
+    flush_signal: Arc<Signal>,
+    /// Raised after every flush, the compaction thread waits on it
+    compaction_signal: Arc<Signal>,
+    /// The flush and compaction threads, joined by close. They only hold a `Weak` to the
+    /// store while waiting, so dropping the last handle without closing stops them too.
+    workers: Mutex<Vec<JoinHandle<()>>>,
+    /// Writers blocked by a write stall wait on this, flushes and compactions wake them up
+    stall_lock: Mutex<()>,
+    stall_wakeup: Condvar,
+    /// Totals over every write held up since the store was opened
+    write_stall_stats: Mutex<WriteStallStats>,
+    closed: AtomicBool,
 }
 
-impl Lsm {
-    pub fn new(config: Option<Config>) -> Arc<Lsm> {
+impl<K: Key> Lsm<K> {
+    pub fn new(config: Option<Config>) -> Result<Arc<Lsm<K>>, LsmError> {
         info!("Creating new LSM");
-        let config_to_use = match config {
+        let mut config_to_use = match config {
             Some(config) => config,
             None => Config::default(),
         };
 
-        if config_to_use.directory.is_some() {
-            //TODO check and load from disk
-            // check for .catalogue file
-            // unimplemented!();
+        // a policy whose levels don't grow would have compactions add levels forever
+        CompactionPolicy::from_config(&config_to_use)?;
+        for family_config in config_to_use.column_families.values() {
+            CompactionPolicy::from_config(family_config)?;
         }
 
+        // the manifest locks the directory, so it is opened before anything is written there
+        let mut manifest = match &config_to_use.directory {
+            Some(dir) => Some(Manifest::open(
+                dir,
+                MAX_MANIFEST_SIZE,
+                K::comparator_name(),
+            )?),
+            None => None,
+        };
+
+        let wal = match &config_to_use.directory {
+            Some(dir) => Some(Wal::open(dir, config_to_use.wal_sync_policy)?),
+            None => None,
+        };
+
+        let mut declared = std::mem::take(&mut config_to_use.column_families);
+        if declared.remove(DEFAULT_COLUMN_FAMILY_NAME).is_some() {
+            warn!("The default column family always uses the store's config");
+        }
+        let mut column_families = BTreeMap::new();
+        let default_levels = match (&config_to_use.directory, &manifest) {
+            (Some(dir), Some(manifest)) => {
+                info!("Loading existing levels from {:?}", dir);
+                manifest.catalogue().get_levels(dir)?
+            }
+            _ => vec![],
+        };
+        column_families.insert(
+            DEFAULT_COLUMN_FAMILY,
+            Arc::new(ColumnFamilyData::new(
+                DEFAULT_COLUMN_FAMILY,
+                DEFAULT_COLUMN_FAMILY_NAME,
+                config_to_use.clone(),
+                default_levels,
+            )),
+        );
+        if let (Some(dir), Some(manifest)) = (&config_to_use.directory, &manifest) {
+            for (id, name) in manifest.catalogue().column_families() {
+                info!("Loading existing levels of column family {}", &name);
+                let levels = manifest.catalogue().column_family_levels(id, dir)?;
+                let family_config = config_to_use.column_family_config(declared.remove(&name));
+                column_families.insert(
+                    id,
+                    Arc::new(ColumnFamilyData::new(id, &name, family_config, levels)),
+                );
+            }
+        }
+        for (name, family_config) in declared {
+            let id = column_families
+                .keys()
+                .max()
+                .unwrap_or(&DEFAULT_COLUMN_FAMILY)
+                + 1;
+            info!("Creating column family {} with id {}", &name, id);
+            if let Some(manifest) = &mut manifest {
+                manifest.log(vec![VersionEdit::CreateColumnFamily {
+                    id: id,
+                    name: name.clone(),
+                }])?;
+            }
+            let family_config = config_to_use.column_family_config(Some(family_config));
+            column_families.insert(
+                id,
+                Arc::new(ColumnFamilyData::new(id, &name, family_config, vec![])),
+            );
+        }
+
+        // writes replayed from the log are newer than everything in a run
+        let last_seq = column_families
+            .values()
+            .map(|family| family.max_run_seq())
+            .max()
+            .unwrap_or(0);
+
         let lsm = Arc::new(Lsm {
-            primary_memory_map: Arc::new(SkipMap::new()),
-            secondary_memory_map: Arc::new(SkipMap::new()),
+            column_families: RwLock::new(column_families),
             use_primary_map: AtomicBool::new(true),
-            primary_memory_map_memory_use: AtomicU64::new(0),
-            secondary_memory_map_memory_use: AtomicU64::new(0),
             config: config_to_use,
-            levels: RwLock::new(vec![]),
             time_to_shutdown: AtomicBool::new(false),
+            wal: wal,
+            manifest: Mutex::new(manifest),
+            write_lock: Mutex::new(()),
+            key_locks: KeyLocks::new(),
+            last_seq: AtomicU64::new(last_seq),
+            snapshots: Mutex::new(BTreeMap::new()),
+            inactive_map_wal_segment: Mutex::new(None),
+            flush_lock: Mutex::new(()),
+            compaction_lock: Mutex::new(()),
+            compaction_stats: Mutex::new(CompactionStats::default()),
+            flush_signal: Arc::new(Signal::new()),
+            compaction_signal: Arc::new(Signal::new()),
+            workers: Mutex::new(vec![]),
+            stall_lock: Mutex::new(()),
+            stall_wakeup: Condvar::new(),
+            write_stall_stats: Mutex::new(WriteStallStats::default()),
+            closed: AtomicBool::new(false),
         });
 
-        let manager_lsm = lsm.clone();
-        info!("Spawning manager thread");
-        thread::spawn(move || manager_lsm.run_manager());
+        if let Some(wal) = &lsm.wal {
+            // Anything in the log was never written to a run, so it all goes back into memory
+            for record in wal.replay()? {
+                lsm.insert_into_memory_map(record);
+            }
+        }
+
+        info!("Spawning flush and compaction threads");
+        let flush_lsm = Arc::downgrade(&lsm);
+        let flush_signal = lsm.flush_signal.clone();
+        let compaction_lsm = Arc::downgrade(&lsm);
+        let compaction_signal = lsm.compaction_signal.clone();
+        *lsm.workers.lock() = vec![
+            thread::spawn(move || run_worker("flush", flush_lsm, flush_signal, Lsm::run_flushes)),
+            thread::spawn(move || {
+                run_worker(
+                    "compaction",
+                    compaction_lsm,
+                    compaction_signal,
+                    Lsm::run_compactions,
+                )
+            }),
+        ];
+        // the log may have filled the maps again and the levels may be due a compaction
+        lsm.flush_signal.notify();
+        lsm.compaction_signal.notify();
+
+        return Ok(lsm);
+    }
+
+    pub(crate) fn default_column_family(self: &Self) -> Arc<ColumnFamilyData<K>> {
+        return self.column_families.read()[&DEFAULT_COLUMN_FAMILY].clone();
+    }
+
+    pub(crate) fn column_family(self: &Self, name: &str) -> Option<Arc<ColumnFamilyData<K>>> {
+        return self
+            .column_families
+            .read()
+            .values()
+            .find(|family| family.name == name)
+            .cloned();
+    }
 
-        return lsm;
+    /// Sequence number of the newest write visible to readers
+    pub fn last_seq(self: &Self) -> u64 {
+        return self.last_seq.load(Ordering::SeqCst);
     }
 
-    pub fn get(self: &Self, key: &i32) -> Option<Vec<u8>> {
-        // TODO verify locking
-        // If use_primary_map is true that means newer data is in the primary map, so we should
-        // check it first.
-        // If the key is in neither of the memory maps then we need to check each level
-        let use_primary_map = self.use_primary_map.load(Ordering::SeqCst);
-        if use_primary_map {
-            trace!("Getting value for key {} trying primary map first", key);
-            // let primary_map = self.primary_memory_map.read();
-            let maybe_res = self.primary_memory_map.get(key);
-            if maybe_res.is_some() {
-                return maybe_res.unwrap().value().clone();
+    pub fn get<Q>(self: &Self, key: &Q) -> Option<Vec<u8>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + Hash + Debug + ToOwned<Owned = K> + ?Sized,
+    {
+        return self.get_at(key, self.last_seq());
+    }
+
+    /// Get the value of `key` as it was once every write up to `seq` had been applied
+    pub fn get_at<Q>(self: &Self, key: &Q, seq: u64) -> Option<Vec<u8>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + Hash + Debug + ToOwned<Owned = K> + ?Sized,
+    {
+        return self.default_column_family().get_at(key, seq);
+    }
+
+    /// Sequence number of the newest write to `key`, deletes included
+    pub fn latest_seq<Q>(self: &Self, key: &Q) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: Ord + Hash + Debug + ToOwned<Owned = K> + ?Sized,
+    {
+        return self.default_column_family().latest_seq(key);
+    }
+
+    pub fn range<R: RangeBounds<K>>(self: &Self, range: R) -> impl Iterator<Item = (K, Vec<u8>)> {
+        return self.range_at(range, self.last_seq());
+    }
+
+    /// Sorted iterator over the live key value pairs with keys in `range` as they were once
+    /// every write up to `seq` had been applied.
+    pub fn range_at<R: RangeBounds<K>>(
+        self: &Self,
+        range: R,
+        seq: u64,
+    ) -> impl Iterator<Item = (K, Vec<u8>)> {
+        return self.default_column_family().range_at(range, seq);
+    }
+
+    /// Write an operand for the merge operator to fold into the value of `key`
+    pub fn merge(self: &Self, key: K, operand: Vec<u8>) -> Result<(), LsmError> {
+        if self.default_column_family().config.merge_operator.is_none() {
+            return Err(LsmError::NoMergeOperator);
+        }
+        return self.write(WalRecord::Merge {
+            key: key,
+            value: operand,
+        });
+    }
+
+    /// Put a value which reads as deleted once `ttl` has passed
+    pub fn put_with_ttl(self: &Self, key: K, val: Vec<u8>, ttl: Duration) -> Result<(), LsmError> {
+        return self.write(WalRecord::ExpiringPut {
+            key: key,
+            value: val,
+            expires_at: expires_at(ttl),
+        });
+    }
+
+    /// Delete every key in `start..end` with a single range tombstone
+    pub fn delete_range(self: &Self, start: K, end: K) -> Result<(), LsmError> {
+        return self.write(WalRecord::DeleteRange {
+            start: start,
+            end: end,
+        });
+    }
+
+    pub fn delete(self: &Self, key: &K) -> Result<(), LsmError> {
+        return self.write(WalRecord::Delete { key: key.clone() });
+    }
+
+    /// Apply every write in the batch at once, an empty batch is a no-op
+    pub fn write_batch(self: &Self, batch: WriteBatch<K>) -> Result<(), LsmError> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        return self.write(batch.into_record());
+    }
+
+    pub fn put(self: &Self, key: K, val: Vec<u8>) -> Result<(), LsmError> {
+        return self.write(WalRecord::Put {
+            key: key,
+            value: val,
+        });
+    }
+
+    /// Apply the batch unless one of `keys` has been written after `seq`, returns false if it
+    /// was not applied. The check holds the locks of `keys` and of the keys in the batch so no
+    /// other write to them can land between it and the batch.
+    pub fn write_batch_if_unchanged(
+        self: &Self,
+        batch: WriteBatch<K>,
+        keys: &Vec<K>,
+        seq: u64,
+    ) -> Result<bool, LsmError> {
+        return self.write_if(batch.into_record(), keys, || {
+            keys.iter()
+                .all(|key| self.latest_seq(key).map_or(true, |latest| latest <= seq))
+        });
+    }
+
+    /// Write `new` to `key`, or delete it if `new` is None, if its current value is `expected`.
+    /// Returns false without writing anything if the value differs.
+    pub fn compare_and_swap(
+        self: &Self,
+        key: K,
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool, LsmError> {
+        let current_key = key.clone();
+        let record = match new {
+            Some(value) => WalRecord::Put {
+                key: key,
+                value: value,
+            },
+            None => WalRecord::Delete { key: key },
+        };
+        // nothing else can write the key while the check holds its lock
+        return self.write_if(record, &[], || {
+            self.get(&current_key).as_deref() == expected
+        });
+    }
+
+    fn write(self: &Self, record: WalRecord<K>) -> Result<(), LsmError> {
+        self.write_if(record, &[], || true)?;
+        return Ok(());
+    }
+
+    // Log the write and then apply it to the active memory map, if `check` passes. The check
+    // runs holding the locks of `check_keys` and of the keys `record` writes, but not the write
+    // lock, so it can read from disk without holding up writes to other keys.
+    fn write_if<F>(
+        self: &Self,
+        record: WalRecord<K>,
+        check_keys: &[K],
+        check: F,
+    ) -> Result<bool, LsmError>
+    where
+        F: FnOnce() -> bool,
+    {
+        self.stall_write();
+        let (position, over_budget) = {
+            let _key_guards = self.key_locks.lock(&record, check_keys);
+            if !check() {
+                return Ok(false);
             }
+            let _guard = self.write_lock.lock();
+            // checked under the write lock, so close's final flush gets every accepted write
+            if self.closed.load(Ordering::SeqCst) {
+                return Err(LsmError::Closed);
+            }
+            let position = match &self.wal {
+                Some(wal) => Some(wal.append(&record)?),
+                None => None,
+            };
+            (position, self.insert_into_memory_map(record))
+        };
+        if over_budget {
+            self.flush_signal.notify();
+        }
+        // syncing happens outside the write lock so writers can share a group commit
+        if let (Some(wal), Some(position)) = (&self.wal, position) {
+            wal.sync_to(position)?;
+        }
+        return Ok(true);
+    }
+
+    /// The worst write stall called for by any column family. The families share the log and
+    /// flush together, so one falling behind holds up writes to all of them.
+    fn write_stall(self: &Self) -> WriteStall {
+        return self
+            .column_families
+            .read()
+            .values()
+            .map(|family| family.write_stall())
+            .max()
+            .unwrap_or(WriteStall::None);
+    }
 
-            // let secondary_map = self.secondary_memory_map.read();
-            let maybe_res = self.secondary_memory_map.get(key);
-            if maybe_res.is_some() {
-                return maybe_res.unwrap().value().clone();
+    /// Delay or block a write while flushes or compactions are falling behind, see
+    /// `Config::set_memory_map_stall` and `Config::set_level1_stall`.
+    ///
+    /// A blocked writer waits until a flush or compaction brings every family back under its
+    /// stop thresholds, and is then let through without a further delay. Nothing is held up
+    /// once the store is shutting down.
+    fn stall_write(self: &Self) {
+        let mut stall = self.write_stall();
+        if stall == WriteStall::None {
+            return;
+        }
+        let start = Instant::now();
+        let stopped = stall == WriteStall::Stop;
+        while stall == WriteStall::Stop && !self.time_to_shutdown.load(Ordering::SeqCst) {
+            self.flush_signal.notify();
+            self.compaction_signal.notify();
+            let mut guard = self.stall_lock.lock();
+            // checked again under the lock so the wake up can't be missed
+            if self.write_stall() == WriteStall::Stop {
+                self.stall_wakeup
+                    .wait_for(&mut guard, STALL_RECHECK_INTERVAL);
             }
+            drop(guard);
+            stall = self.write_stall();
+        }
+        if !stopped {
+            thread::sleep(self.config.write_slowdown_delay);
+        }
+
+        let mut stats = self.write_stall_stats.lock();
+        if stopped {
+            stats.stopped_writes += 1;
         } else {
-            trace!("Getting value for key {} trying secondary map first", key);
+            stats.slowed_writes += 1;
+        }
+        stats.stall_time += start.elapsed();
+    }
+
+    /// Wake up every writer blocked by a write stall to check whether it can go ahead
+    fn wake_stalled_writers(self: &Self) {
+        let _guard = self.stall_lock.lock();
+        self.stall_wakeup.notify_all();
+    }
 
-            let maybe_res = self.secondary_memory_map.get(key);
-            if maybe_res.is_some() {
-                return maybe_res.unwrap().value().clone();
+    /// How many writes have been delayed or blocked since the store was opened, and for how long
+    pub fn write_stall_stats(self: &Self) -> WriteStallStats {
+        return *self.write_stall_stats.lock();
+    }
+
+    /// Log a write to the column family `family` and apply it
+    pub(crate) fn write_to_column_family(
+        self: &Self,
+        family: u32,
+        record: WalRecord<K>,
+    ) -> Result<(), LsmError> {
+        if family == DEFAULT_COLUMN_FAMILY {
+            return self.write(record);
+        }
+        return self.write(WalRecord::ColumnFamily {
+            family: family,
+            record: Box::new(record),
+        });
+    }
+
+    /// Insert a record under the next sequence number and then make it visible to readers.
+    /// The writes in a batch take consecutive sequence numbers and become visible together.
+    /// Callers serialize on the write lock. Returns whether a memory map is over budget now.
+    fn insert_into_memory_map(self: &Self, record: WalRecord<K>) -> bool {
+        let mut seq = self.last_seq.load(Ordering::SeqCst);
+        let primary = self.use_primary_map.load(Ordering::SeqCst);
+        let column_families = self.column_families.read();
+        let records = match record {
+            WalRecord::Batch { records } => records,
+            record => vec![record],
+        };
+        for record in records {
+            seq += 1;
+            let (family, record) = match record {
+                WalRecord::ColumnFamily { family, record } => (family, *record),
+                record => (DEFAULT_COLUMN_FAMILY, record),
+            };
+            let family = match column_families.get(&family) {
+                Some(family) => family,
+                None => {
+                    error!("Write to unknown column family {}, skipping", family);
+                    continue;
+                }
+            };
+            match record {
+                WalRecord::Put { key, value } => {
+                    family.insert_item(key, seq, Value::Put(value), primary)
+                }
+                WalRecord::Delete { key } => family.insert_item(key, seq, Value::Delete, primary),
+                WalRecord::Merge { key, value } => {
+                    family.insert_item(key, seq, Value::Merge(value), primary)
+                }
+                WalRecord::ExpiringPut {
+                    key,
+                    value,
+                    expires_at,
+                } => family.insert_item(
+                    key,
+                    seq,
+                    Value::Expiring {
+                        value: value,
+                        expires_at: expires_at,
+                    },
+                    primary,
+                ),
+                WalRecord::DeleteRange { start, end } => {
+                    family.insert_range_tombstone(RangeTombstone::new(start, end, seq), primary)
+                }
+                WalRecord::Batch { .. } | WalRecord::ColumnFamily { .. } => {
+                    error!("Nested write batches are not supported, skipping")
+                }
             }
+        }
+        self.last_seq.store(seq, Ordering::SeqCst);
+        return column_families
+            .values()
+            .any(|family| family.time_to_flush(primary));
+    }
 
-            let maybe_res = self.primary_memory_map.get(key);
-            if maybe_res.is_some() {
-                return maybe_res.unwrap().value().clone();
+    /// Register a snapshot of everything written so far, returns its sequence number.
+    /// Compactions keep the versions it reads until it is released.
+    pub fn acquire_snapshot(self: &Self) -> u64 {
+        let mut snapshots = self.snapshots.lock();
+        let seq = self.last_seq.load(Ordering::SeqCst);
+        *snapshots.entry(seq).or_insert(0) += 1;
+        return seq;
+    }
+
+    pub fn release_snapshot(self: &Self, seq: u64) {
+        let mut snapshots = self.snapshots.lock();
+        if let Some(count) = snapshots.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                snapshots.remove(&seq);
             }
         }
+    }
 
-        trace!(
-            "Value for key {} not found in memory, searching levels",
-            key
-        );
-        let read_levels = self.levels.read();
-        for i in 0..read_levels.len() {
-            let level = read_levels[i].read();
-            let maybe_res = level.get_from_level(key);
-            if maybe_res.is_some() {
-                return maybe_res;
+    /// Sequence numbers of the live snapshots in ascending order
+    fn live_snapshots(self: &Self) -> Vec<u64> {
+        return self.snapshots.lock().keys().cloned().collect();
+    }
+
+    /// Direct new writes to the other memory map. Returns the first log segment of the newly
+    /// active map, every older segment belongs to the map being switched away from.
+    fn switch_memory_map(self: &Self, use_primary_map: bool) -> Result<Option<u64>, LsmError> {
+        let _guard = self.write_lock.lock();
+        self.use_primary_map
+            .store(use_primary_map, Ordering::SeqCst);
+        return match &self.wal {
+            Some(wal) => Ok(Some(wal.roll()?)),
+            None => Ok(None),
+        };
+    }
+
+    // Once a memory map is in a run its log segments are no longer needed
+    fn remove_wal_segments_before(self: &Self, segment: Option<u64>) {
+        if let (Some(wal), Some(segment)) = (&self.wal, segment) {
+            if let Err(e) = wal.remove_segments_before(segment) {
+                error!("Error removing old WAL segments: {:?}", e);
             }
         }
+    }
 
-        trace!("No value found for key {} ", key);
+    fn column_families(self: &Self) -> Vec<Arc<ColumnFamilyData<K>>> {
+        return self.column_families.read().values().cloned().collect();
+    }
 
-        return None;
+    // levels on disk are 1 indexed, level 0 is the in memory map
+    pub fn add_run_to_level(self: &Self, run: Run<K>, level: usize) -> Result<(), LsmError> {
+        let family = self.default_column_family();
+        return self.install_version(vec![VersionChange {
+            family: &family,
+            removed: vec![],
+            added: vec![(level, run)],
+        }]);
     }
 
-    // Internally Values are stored as Option<Vec<u8>> and None is reserved to mean key deleted
-    pub fn delete(self: &Self, key: &i32) -> () {
-        if self.use_primary_map.load(Ordering::SeqCst) {
-            self.primary_memory_map.insert(key.clone(), None);
-        } else {
-            self.secondary_memory_map.insert(key.clone(), None);
+    /// Atomically remove and add runs to levels, in one or more column families.
+    ///
+    /// The change is synced to the manifest before it is made visible, so after a crash the
+    /// store comes back with either all of the change or none of it. The caller is responsible for
+    /// deleting the files of removed runs once this returns.
+    fn install_version(self: &Self, changes: Vec<VersionChange<K>>) -> Result<(), LsmError> {
+        let mut edits = vec![];
+        for change in &changes {
+            let family_edit = |edit| {
+                if change.family.id == DEFAULT_COLUMN_FAMILY {
+                    return edit;
+                }
+                return VersionEdit::ColumnFamily {
+                    id: change.family.id,
+                    edit: Box::new(edit),
+                };
+            };
+            for (level, run) in &change.removed {
+                edits.push(family_edit(VersionEdit::RemoveRun {
+                    level: *level,
+                    file: run_file_name(run),
+                }));
+            }
+            for (level, run) in &change.added {
+                edits.push(family_edit(VersionEdit::AddRun {
+                    level: *level,
+                    file: run_file_name(run),
+                }));
+            }
         }
+        match self.manifest.lock().as_mut() {
+            Some(manifest) => manifest.log(edits)?,
+            None if self.config.directory.is_some() => return Err(LsmError::Closed),
+            None => {}
+        }
+
+        for change in changes {
+            let mut levels = change.family.levels.write();
+            for (level, run) in change.removed {
+                let mut level = levels[level - 1].write();
+                level.runs.retain(|r| !Arc::ptr_eq(r, &run));
+                level.num_runs = level.runs.len();
+            }
+            for (level, run) in change.added {
+                while levels.len() < level {
+                    info!("creating a new level {}", levels.len() + 1);
+                    levels.push(RwLock::new(Level {
+                        num_runs: 0,
+                        runs: vec![],
+                    }));
+                }
+                info!("inserting into level {}", &level);
+                let mut level = levels[level - 1].write();
+                level.runs.push(Arc::new(run));
+                level.num_runs += 1;
+            }
+        }
+        return Ok(());
     }
 
-    pub fn put(self: &Self, key: i32, val: Vec<u8>) -> () {
-        if self.use_primary_map.load(Ordering::SeqCst) {
-            trace!("Putting key {} into primary memmap", &key);
-            let memuse =
-                self.primary_memory_map_memory_use.load(Ordering::SeqCst) + 4 + val.len() as u64;
-            self.primary_memory_map.insert(key, Some(val));
-            self.primary_memory_map_memory_use
-                .store(memuse, Ordering::SeqCst);
-        } else {
-            trace!("Putting key {} into secondary memmap", &key);
-            let memuse =
-                self.secondary_memory_map_memory_use.load(Ordering::SeqCst) + 4 + val.len() as u64;
-            self.secondary_memory_map.insert(key, Some(val));
-            self.secondary_memory_map_memory_use
-                .store(memuse, Ordering::SeqCst);
+    /// Run the compactions the column family's policy picks until its levels are within it
+    fn compact_column_family(self: &Self, family: &ColumnFamilyData<K>) -> Result<(), LsmError> {
+        let _guard = self.compaction_lock.lock();
+        let policy = CompactionPolicy::from_config(&family.config)?;
+        loop {
+            let shapes: Vec<LevelShape> = family
+                .levels
+                .read()
+                .iter()
+                .map(|level| {
+                    let level = level.read();
+                    LevelShape {
+                        num_runs: level.num_sorted_runs(),
+                        flushes: level.runs.iter().map(|run| run.flushes).sum(),
+                    }
+                })
+                .collect();
+            match policy.pick(&shapes) {
+                Some(compaction) => {
+                    self.compact(family, compaction)?;
+                    self.wake_stalled_writers();
+                }
+                None => return Ok(()),
+            }
         }
     }
 
-    fn time_to_merge_primary_memmap(self: &Self) -> bool {
-        let primary_mem_use = self.primary_memory_map_memory_use.load(Ordering::Relaxed);
-        debug!(
-            "time_to_merge primary: primary mem use: {} primary mem budget {}",
-            primary_mem_use, self.config.memory_map_budget
+    /// Merge the runs `compaction` covers into a sorted run.
+    ///
+    /// A level moving down into a next level which holds a single sorted run moves a few files
+    /// at a time, see `overlapping_files`, and the output joins that sorted run. The policy
+    /// picks the level again until it is no longer full, so the next level is rewritten
+    /// piece by piece rather than all at once. Every other compaction merges whole levels.
+    fn compact(
+        self: &Self,
+        family: &ColumnFamilyData<K>,
+        compaction: Compaction,
+    ) -> Result<(), LsmError> {
+        let levels = family.levels.read();
+        let whole_levels = |merged: &[usize]| {
+            return merged
+                .iter()
+                .flat_map(|level| {
+                    let runs = levels[level - 1].read().runs.clone();
+                    runs.into_iter().map(move |run| (*level, run))
+                })
+                .collect::<Vec<(usize, Arc<Run<K>>)>>();
+        };
+        let (inputs, output_level, sorted_run) = match compaction {
+            Compaction::MergeLevel { level } => (whole_levels(&[level]), level, None),
+            Compaction::MoveDown {
+                level,
+                merge_with_next: true,
+            } if levels[level].read().num_sorted_runs() == 1 => {
+                let upper = levels[level - 1].read();
+                let lower = levels[level].read();
+                (
+                    Lsm::overlapping_files(&upper, &lower, level),
+                    level + 1,
+                    Some(lower.runs[0].sorted_run.clone()),
+                )
+            }
+            Compaction::MoveDown {
+                level,
+                merge_with_next: true,
+            } => (whole_levels(&[level + 1, level]), level + 1, None),
+            Compaction::MoveDown {
+                level,
+                merge_with_next: false,
+            } => (whole_levels(&[level]), level + 1, None),
+        };
+        drop(levels);
+        info!(
+            "Compacting {} runs of {} into level {}: {:?}",
+            inputs.len(),
+            &family.name,
+            output_level,
+            compaction
         );
-        if primary_mem_use > self.config.memory_map_budget {
-            return true;
+        self.merge_into_level(family, inputs, output_level, sorted_run)?;
+        return Ok(());
+    }
+
+    /// Merge `inputs` into new files in `output_level`, which join `sorted_run` if given, and
+    /// swap them for the inputs in the levels of `family`.
+    fn merge_into_level(
+        self: &Self,
+        family: &ColumnFamilyData<K>,
+        inputs: Vec<(usize, Arc<Run<K>>)>,
+        output_level: usize,
+        sorted_run: Option<String>,
+    ) -> Result<CompactionReport, LsmError> {
+        let is_input = |run: &Arc<Run<K>>| inputs.iter().any(|(_, input)| Arc::ptr_eq(run, input));
+        // runs move down the levels, so anything in deeper levels is older than these runs
+        let bottom = family.levels.read().iter().skip(output_level - 1).all(|l| {
+            l.read()
+                .runs
+                .iter()
+                .all(|r| is_input(r) || !inputs.iter().any(|(_, input)| input.overlaps(r)))
+        });
+
+        let runs: Vec<Arc<Run<K>>> = inputs.iter().map(|(_, run)| run.clone()).collect();
+        let mut stats = CompactionStats::default();
+        let added = Run::new_from_merge(
+            &runs,
+            &family.config,
+            output_level,
+            self.live_snapshots(),
+            bottom,
+            sorted_run,
+            &mut stats,
+        )?;
+        info!("Compaction into level {}: {:?}", output_level, stats);
+        *self.compaction_stats.lock() += stats;
+        if added.is_empty() {
+            info!(
+                "Nothing is left after compacting into level {}",
+                output_level
+            );
+        }
+        let report = CompactionReport {
+            output_level: Some(output_level),
+            files_read: runs.len(),
+            files_written: added.len(),
+            bytes_read: runs.iter().map(|run| file_size(&run.file_name)).sum(),
+            bytes_written: added.iter().map(|run| file_size(&run.file_name)).sum(),
+            stats: stats,
+        };
+        self.install_version(vec![VersionChange {
+            family: family,
+            removed: inputs,
+            added: added.into_iter().map(|run| (output_level, run)).collect(),
+        }])?;
+        for run in runs {
+            info!("Cleaning up runs after merging");
+            if let Err(e) = remove_file(&run.file_name) {
+                error!("Error removing merged run {:?}: {:?}", &run.file_name, e);
+            }
         }
-        return false;
+        return Ok(report);
     }
 
-    fn time_to_merge_secondary_memmap(self: &Self) -> bool {
-        let secondary_mem_use = self.secondary_memory_map_memory_use.load(Ordering::Relaxed);
-        debug!(
-            "time_to_merge secondary: secondary mem use: {} secondary mem budget {}",
-            secondary_mem_use, self.config.memory_map_budget
+    /// Flush the memory maps and merge every file of `family` which may hold keys from `start`
+    /// to `end` into its deepest level.
+    ///
+    /// Files sharing keys with the ones picked are merged as well, see `close_over_overlaps`,
+    /// so more than the range may be rewritten. As the output lands in the bottom level deletes
+    /// and the versions they shadow are dropped, unless a live snapshot still reads them.
+    pub(crate) fn compact_column_family_range(
+        self: &Self,
+        family: &ColumnFamilyData<K>,
+        start: Bound<K>,
+        end: Bound<K>,
+    ) -> Result<CompactionReport, LsmError> {
+        self.flush_all()?;
+        let _guard = self.compaction_lock.lock();
+        let (inputs, output_level, sorted_run) = {
+            let all_levels = family.levels.read();
+            let guards: Vec<_> = all_levels.iter().map(|level| level.read()).collect();
+            let levels: Vec<(usize, &Level<K>)> = guards
+                .iter()
+                .enumerate()
+                .map(|(i, level)| (i + 1, &**level))
+                .collect();
+            let in_range = levels
+                .iter()
+                .flat_map(|(l, level)| {
+                    level
+                        .runs
+                        .iter()
+                        .filter(|run| run.overlaps_range(start.as_ref(), end.as_ref()))
+                        .map(move |run| (*l, run.clone()))
+                })
+                .collect();
+            let inputs = Lsm::close_over_overlaps(&levels, in_range);
+            let deepest = levels
+                .iter()
+                .rev()
+                .find(|(_, level)| !level.runs.is_empty());
+            match deepest {
+                Some((l, level)) if !inputs.is_empty() => {
+                    let sorted_run = match level.num_sorted_runs() {
+                        1 => Some(level.runs[0].sorted_run.clone()),
+                        _ => None,
+                    };
+                    (inputs, *l, sorted_run)
+                }
+                _ => {
+                    info!(
+                        "Nothing to compact in {} for {:?}..{:?}",
+                        &family.name, start, end
+                    );
+                    return Ok(CompactionReport::default());
+                }
+            }
+        };
+        info!(
+            "Compacting {} runs of {} into level {} for {:?}..{:?}",
+            inputs.len(),
+            &family.name,
+            output_level,
+            start,
+            end
         );
-        // if self.use_primary_map.load(Ordering::SeqCst) == false {
-        if secondary_mem_use > self.config.memory_map_budget {
-            return true;
+        return self.merge_into_level(family, inputs, output_level, sorted_run);
+    }
+
+    /// Merge every file of the default column family holding keys in `range` into its deepest
+    /// level, see `compact_column_family_range`
+    pub fn compact_range<R: RangeBounds<K>>(
+        self: &Self,
+        range: R,
+    ) -> Result<CompactionReport, LsmError> {
+        return self.compact_column_family_range(
+            &self.default_column_family(),
+            range.start_bound().cloned(),
+            range.end_bound().cloned(),
+        );
+    }
+
+    /// The file of level `level` holding the oldest writes, together with every file of it and
+    /// of the next level sharing keys with it, directly or through another of these files.
+    fn overlapping_files(
+        upper: &Level<K>,
+        lower: &Level<K>,
+        level: usize,
+    ) -> Vec<(usize, Arc<Run<K>>)> {
+        let oldest = upper.runs.iter().min_by_key(|run| run.max_seq);
+        let picked = oldest.map(|run| (level, run.clone())).into_iter().collect();
+        return Lsm::close_over_overlaps(&[(level, upper), (level + 1, lower)], picked);
+    }
+
+    /// `picked` together with every file of `levels` sharing keys with it, directly or through
+    /// another of these files.
+    ///
+    /// No file left behind in these levels overlaps the ones picked, so merging them keeps the
+    /// files of each sorted run disjoint, and writes in upper levels stay above older writes of
+    /// the same keys.
+    fn close_over_overlaps(
+        levels: &[(usize, &Level<K>)],
+        mut picked: Vec<(usize, Arc<Run<K>>)>,
+    ) -> Vec<(usize, Arc<Run<K>>)> {
+        loop {
+            let mut grew = false;
+            for (l, candidates) in levels.iter() {
+                for run in candidates.runs.iter() {
+                    let is_picked = picked.iter().any(|(_, p)| Arc::ptr_eq(p, run));
+                    if !is_picked && picked.iter().any(|(_, p)| p.overlaps(run)) {
+                        picked.push((*l, run.clone()));
+                        grew = true;
+                    }
+                }
+            }
+            if !grew {
+                return picked;
+            }
         }
-        // }
-        return false;
     }
 
-    // levels on disk are 1 indexed, level 0 is the in memory map
-    pub fn add_run_to_level(self: &Self, run: Run, level: usize) {
-        //insert into existing level
-        let mut levels = self.levels.write();
-        if levels.len() < level {
-            info!("creating a new level {}", &level);
-            debug_assert!(levels.len() == level - 1);
-            levels.push(RwLock::new(Level {
-                num_runs: 1,
-                runs: vec![Arc::new(run)],
-            }))
+    /// What compactions have read, written and dropped since the store was opened
+    pub fn compaction_stats(self: &Self) -> CompactionStats {
+        return *self.compaction_stats.lock();
+    }
+
+    /// Write the primary or secondary memory map of every column family to new level 1 runs.
+    ///
+    /// If the maps are the active ones new writes are directed to the others first. Once the
+    /// runs are in the manifest the maps are cleared and their log segments are removed, if
+    /// anything fails the maps and their log are kept so the flush can be retried. The log is
+    /// shared, so every family flushes together to let its segments go.
+    ///
+    /// The other maps may still hold writes from a failed flush, those are flushed before new
+    /// writes go to them since their log segments are removed along with the ones of these maps.
+    fn flush_memory_maps(self: &Self, primary: bool) -> Result<(), LsmError> {
+        let segment = if self.use_primary_map.load(Ordering::SeqCst) == primary {
+            if self
+                .column_families()
+                .iter()
+                .any(|f| f.holds_writes(!primary))
+            {
+                self.flush_memory_maps(!primary)?;
+            }
+            let segment = self.switch_memory_map(!primary)?;
+            *self.inactive_map_wal_segment.lock() = segment;
+            segment
         } else {
-            info!("inserting into existing level {}", &level);
-            debug_assert!(levels.len() > level - 1);
-            let mut level = &mut *levels[level - 1].write();
-            level.runs.push(Arc::new(run));
-            level.num_runs += 1;
+            // a previous flush of these maps failed, they have been inactive since then
+            *self.inactive_map_wal_segment.lock()
+        };
+
+        let column_families = self.column_families();
+        let mut changes = vec![];
+        for family in column_families.iter() {
+            let (map, range_tombstones) = if primary {
+                (&family.primary_memory_map, &family.primary_range_tombstones)
+            } else {
+                (
+                    &family.secondary_memory_map,
+                    &family.secondary_range_tombstones,
+                )
+            };
+            let tombstones = range_tombstones.read().clone();
+            if !map.is_empty() || !tombstones.is_empty() {
+                let new_run = Run::new_from_skipmap_with_range_tombstones(
+                    map.clone(),
+                    tombstones,
+                    &family.config,
+                )?;
+                changes.push(VersionChange {
+                    family: family,
+                    removed: vec![],
+                    added: vec![(1, new_run)],
+                });
+            }
         }
-        info!("Completed adding run to level {}", &level);
+        self.install_version(changes)?;
+
+        for family in column_families.iter() {
+            if primary {
+                family.primary_memory_map.clear();
+                family.primary_range_tombstones.write().clear();
+                family
+                    .primary_memory_map_memory_use
+                    .store(0, Ordering::SeqCst);
+            } else {
+                family.secondary_memory_map.clear();
+                family.secondary_range_tombstones.write().clear();
+                family
+                    .secondary_memory_map_memory_use
+                    .store(0, Ordering::SeqCst);
+            }
+        }
+        self.remove_wal_segments_before(segment);
+        self.wake_stalled_writers();
+        return Ok(());
+    }
+
+    /// Write both memory maps of every column family to level 1 runs, the older maps first so
+    /// level 1 stays ordered oldest to newest
+    fn flush_all(self: &Self) -> Result<(), LsmError> {
+        let _guard = self.flush_lock.lock();
+        let active_is_primary = self.use_primary_map.load(Ordering::SeqCst);
+        self.flush_memory_maps(!active_is_primary)?;
+        self.flush_memory_maps(active_is_primary)?;
+        return Ok(());
     }
 
-    pub fn run_manager(self: &Self) {
-        info!("Starting up run_manager");
+    /// Stop the flush and compaction threads, waiting for any flush or compaction in progress,
+    /// and then write both memory maps to level 1 runs.
+    ///
+    /// The directory is unlocked once this returns, even while other handles to the store are
+    /// still alive, and writes from then on fail with `LsmError::Closed`. Reads keep working.
+    ///
+    /// Calling this more than once is a no-op. Without a directory nothing is written since the
+    /// store can't be reopened anyway.
+    pub fn close(self: &Self) -> Result<(), LsmError> {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        info!("Closing LSM");
+        self.time_to_shutdown.store(true, Ordering::SeqCst);
+        self.flush_signal.close();
+        self.compaction_signal.close();
+        self.wake_stalled_writers();
+        for handle in self.workers.lock().drain(..) {
+            if handle.join().is_err() {
+                error!("A background thread panicked");
+            }
+        }
+
+        let flushed = match self.config.directory {
+            Some(_) => self.flush_all(),
+            None => Ok(()),
+        };
+        // dropping the manifest unlocks the directory, writes that failed to flush are still
+        // in the log for whoever opens it next
+        self.manifest.lock().take();
+        flushed?;
+        info!("LSM closed");
+        return Ok(());
+    }
+
+    /// Flush the memory maps until none is over budget, run each time a writer signals one is.
+    ///
+    /// A failed flush is retried on the next write, which finds the map still over budget.
+    fn run_flushes(self: &Self) {
         loop {
-            if self.time_to_shutdown.load(Ordering::Relaxed) {
-                return;
-            }
-            std::thread::sleep(Duration::new(1, 0));
-            if self.time_to_shutdown.load(Ordering::Relaxed) {
-                info!("run_manager shutting down");
-                // cleanup and save necessary stuff to disk
-                // probably just putting writing the memory maps to new runs
-            }
-
-            if self.time_to_merge_primary_memmap() {
-                info!("About to write primary mmap to disk");
-                self.use_primary_map.store(false, Ordering::SeqCst);
-                // let primary_map = self.primary_memory_map.write();
-                let new_run = Run::new_from_skipmap(self.primary_memory_map.clone(), &self.config);
-                if new_run.is_err() {
-                    error!("Error creating run from memory map: {:?}", new_run.err());
-                    continue;
-                } else {
-                    self.add_run_to_level(new_run.unwrap(), 1);
-                    self.primary_memory_map_memory_use
-                        .store(0, Ordering::SeqCst);
-                }
-            } else if self.time_to_merge_secondary_memmap() {
-                info!("About to write secondary mmap to disk");
-                self.use_primary_map.store(true, Ordering::SeqCst);
-                // let primary_map = self.primary_memory_map.write();
-                let new_run =
-                    Run::new_from_skipmap(self.secondary_memory_map.clone(), &self.config);
-                if new_run.is_err() {
-                    error!("Error creating run from memory map: {:?}", new_run.err());
-                    continue;
-                } else {
-                    self.add_run_to_level(new_run.unwrap(), 1);
-                    self.secondary_memory_map_memory_use
-                        .store(0, Ordering::SeqCst);
-                }
+            let column_families = self.column_families();
+            // one family outgrowing its budget flushes all of them, they share the log
+            let primary = if column_families.iter().any(|f| f.time_to_flush(true)) {
+                true
+            } else if column_families.iter().any(|f| f.time_to_flush(false)) {
+                false
+            } else {
+                break;
+            };
+            info!(
+                "About to write {} mmaps to disk",
+                if primary { "primary" } else { "secondary" }
+            );
+            let flushed = {
+                let _guard = self.flush_lock.lock();
+                self.flush_memory_maps(primary)
+            };
+            if let Err(e) = flushed {
+                error!("Error writing memory maps to runs: {:?}", e);
+                break;
             }
+            self.compaction_signal.notify();
+        }
+    }
 
-            for i in 0..self.levels.read().len() {
-                if self.levels.read()[i].read().runs.len() >= self.config.t as usize {
-                    info!("Compacting runs at level {}", i);
-                    let new_run = Run::new_from_merge(
-                        &self.levels.read()[i].read().runs,
-                        &self.config,
-                        i + 1,
-                    )
-                    .unwrap();
-                    let old_runs = self.levels.read()[i].read().runs.clone();
-                    {
-                        let levels = self.levels.read();
-                        let mut this_level = levels[i].write();
-                        this_level.num_runs = 0;
-                        this_level.runs.clear();
-                        let mut next_level = levels[i + 1].write();
-                        next_level.runs.push(Arc::new(new_run));
-                        next_level.num_runs += 1;
-                    }
-                    for run in old_runs {
-                        info!("Cleaning up runs after merging");
-                        Arc::try_unwrap(run).ok().unwrap().delete().unwrap();
-                    }
-                }
+    /// Run the compactions each column family's policy picks, run after every flush.
+    fn run_compactions(self: &Self) {
+        for family in self.column_families().iter() {
+            if self.time_to_shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            if let Err(e) = self.compact_column_family(family) {
+                error!("Error compacting {}: {:?}", &family.name, e);
             }
         }
     }
 }
 
-impl Drop for Lsm {
+/// Body of a background thread, calls `work` each time `signal` is raised until the signal is
+/// closed or the store has been dropped.
+fn run_worker<K: Key>(name: &str, lsm: Weak<Lsm<K>>, signal: Arc<Signal>, work: fn(&Lsm<K>)) {
+    info!("Starting up the {} thread", name);
+    while signal.wait() {
+        match lsm.upgrade() {
+            Some(lsm) => work(&lsm),
+            None => break,
+        }
+    }
+    info!("Shutting down the {} thread", name);
+}
+
+fn run_file_name<K: Key>(run: &Run<K>) -> PathBuf {
+    return PathBuf::from(run.file_name.file_name().unwrap());
+}
+
+fn file_size(path: &Path) -> u64 {
+    return metadata(path).map_or(0, |m| m.len());
+}
+
+// Without close this doesn't flush, the memory maps are replayed from the log on the next open
+impl<K: Key> Drop for Lsm<K> {
     fn drop(&mut self) {
-        info!("Shutting down run manager");
+        info!("Shutting down the background threads");
         self.time_to_shutdown.store(true, Ordering::Relaxed);
+        self.flush_signal.close();
+        self.compaction_signal.close();
     }
 }
 
 #[cfg(test)]
 mod test_run {
+    use crate::compaction::CompactionReport;
+    use crate::compaction_filter::{CompactionFilter, FilterDecision};
+    use crate::lsm::{Lsm, LsmError};
+    use crate::manifest::ManifestError;
+    use crate::rate_limiter::{IoPriority, RateLimiter};
     use crate::run::Run;
+    use crate::wal::WalRecord;
+    use crate::write_stall::WriteStallStats;
     use crate::Config;
+    use crate::MergeOperator;
     use crossbeam_skiplist::SkipMap;
     use log::info;
     use rand::Rng;
     use rand_chacha::rand_core::SeedableRng;
     use rand_chacha::ChaChaRng;
+    use std::any::Any;
+    use std::convert::TryInto;
     use std::fs;
-    use std::thread::sleep;
-    use tempfile::tempdir;
-    // use test_case::test_case;
-    use crate::lsm::Lsm;
+    use std::path::PathBuf;
     use std::sync::atomic::Ordering;
     use std::sync::Arc;
-    use std::time::Duration;
+    use std::thread::{sleep, spawn};
+    use std::time::{Duration, Instant};
+    use tempfile::tempdir;
+    use test_case::test_case;
     use test_env_log::test;
 
     #[test]
@@ -311,8 +1285,8 @@ mod test_run {
         let dir = tempdir().unwrap();
         config.set_memory_map_budget(1000).unwrap();
         config.set_directory(dir.path());
-        let lsm = Lsm::new(Some(config));
-        lsm.put(42, vec![042u8]);
+        let lsm = Lsm::new(Some(config)).unwrap();
+        lsm.put(42, vec![042u8]).unwrap();
         insert_vals(lsm.clone(), 1500);
         assert_eq!(lsm.get(&42).unwrap(), vec![042u8]);
 
@@ -323,8 +1297,11 @@ mod test_run {
         info!("DB files created in temp directory");
         let mut num_files = 0;
         for path in db_files {
-            info!("Name: {}", path.unwrap().path().display());
-            num_files = num_files + 1;
+            let path = path.unwrap().path();
+            info!("Name: {}", path.display());
+            if path.extension() == Some("run".as_ref()) {
+                num_files = num_files + 1;
+            }
         }
         assert_eq!(num_files, 1);
     }
@@ -337,15 +1314,15 @@ mod test_run {
         let dir = tempdir().unwrap();
         config.set_memory_map_budget(1000).unwrap();
         config.set_directory(dir.path());
-        let lsm = Lsm::new(Some(config));
-        lsm.put(42, vec![042u8]);
+        let lsm = Lsm::new(Some(config)).unwrap();
+        lsm.put(42, vec![042u8]).unwrap();
         insert_vals(lsm.clone(), 1500);
         assert_eq!(lsm.get(&42).unwrap(), vec![042u8]);
         for i in 0..10 {
             insert_vals(lsm.clone(), 1100);
             sleep(Duration::new(3, 0));
         }
-        lsm.delete(&42);
+        lsm.delete(&42).unwrap();
 
         assert_eq!(lsm.get(&42), None)
     }
@@ -358,13 +1335,13 @@ mod test_run {
         let dir = tempdir().unwrap();
         config.set_memory_map_budget(1000).unwrap();
         config.set_directory(dir.path());
-        let lsm = Lsm::new(Some(config));
-        lsm.put(42, vec![042u8]);
+        let lsm = Lsm::new(Some(config)).unwrap();
+        lsm.put(42, vec![042u8]).unwrap();
         for i in 0..10 {
             insert_vals(lsm.clone(), 1100);
             sleep(Duration::new(3, 0));
         }
-        lsm.put(41, vec![041u8]);
+        lsm.put(41, vec![041u8]).unwrap();
         assert_eq!(lsm.get(&42).unwrap(), vec![042u8]);
         assert_eq!(lsm.get(&41).unwrap(), vec![041u8]);
 
@@ -375,18 +1352,954 @@ mod test_run {
         let val = lsm.get(&41);
         assert_eq!(val, Some(vec![041u8]));
 
+        // how far the background compactions got depends on timing, merge everything first
+        let report = lsm.compact_range(..).unwrap();
+        assert_eq!(lsm.get(&42), Some(vec![042u8]));
+        assert_eq!(lsm.get(&41), Some(vec![041u8]));
+
         let db_files = fs::read_dir(dir.path()).unwrap();
 
         info!("DB files created in temp directory");
         let mut num_files = 0;
         for path in db_files {
-            info!("Name: {}", path.unwrap().path().display());
-            num_files = num_files + 1;
+            let path = path.unwrap().path();
+            info!("Name: {}", path.display());
+            if path.extension() == Some("run".as_ref()) {
+                num_files = num_files + 1;
+            }
+        }
+        assert_eq!(num_files, 1);
+        let family = lsm.default_column_family();
+        let levels = family.levels.read();
+        let runs: Vec<usize> = levels.iter().map(|l| l.read().runs.len()).collect();
+        assert_eq!(runs[report.output_level.unwrap() - 1], 1);
+        assert_eq!(runs.iter().sum::<usize>(), 1);
+    }
+
+    #[test]
+    fn lsm_reopen_with_runs() {
+        let _ = env_logger::try_init();
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_memory_map_budget(1000).unwrap();
+        config.set_directory(dir.path());
+        let lsm = Lsm::new(Some(config)).unwrap();
+        for i in 0..3 {
+            for key in 0..200 {
+                lsm.put(key, vec![i as u8; 8]).unwrap();
+            }
+            sleep(Duration::new(2, 0));
+        }
+        lsm.put(500, vec![5u8]).unwrap();
+        assert!(
+            lsm.default_column_family().levels.read()[0]
+                .read()
+                .runs
+                .len()
+                >= 2
+        );
+        // the directory stays locked until the store is closed
+        lsm.close().unwrap();
+
+        let mut config = Config::default();
+        config.set_directory(dir.path());
+        let reopened: Arc<Lsm<i32>> = Lsm::new(Some(config)).unwrap();
+        assert_eq!(
+            reopened.default_column_family().levels.read()[0]
+                .read()
+                .runs
+                .len(),
+            lsm.default_column_family().levels.read()[0]
+                .read()
+                .runs
+                .len()
+        );
+        for key in 0..200 {
+            assert_eq!(reopened.get(&key), Some(vec![2u8; 8]));
         }
-        assert_eq!(num_files, 10);
+        assert_eq!(reopened.get(&500), Some(vec![5u8]));
+    }
+
+    #[test]
+    fn lsm_compaction_survives_reopen() {
+        let _ = env_logger::try_init();
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        config.t = 3;
+        let lsm = Lsm::new(Some(config)).unwrap();
+        let family = lsm.default_column_family();
+        // every batch of keys is unique so we can check nothing is lost in the merge
+        for i in 0..4 {
+            for key in (i * 200)..((i + 1) * 200) {
+                lsm.put(key, vec![i as u8; 8]).unwrap();
+            }
+            lsm.flush_memory_maps(lsm.use_primary_map.load(Ordering::SeqCst))
+                .unwrap();
+            lsm.compact_column_family(&family).unwrap();
+        }
+        // the third flush filled level 1, which moved down
+        assert_eq!(family.levels.read().len(), 2);
+        assert_eq!(family.levels.read()[0].read().runs.len(), 1);
+        assert_eq!(family.levels.read()[1].read().runs.len(), 1);
+        lsm.close().unwrap();
+
+        let mut config = Config::default();
+        config.set_directory(dir.path());
+        let reopened: Arc<Lsm<i32>> = Lsm::new(Some(config)).unwrap();
+        assert_eq!(
+            reopened.default_column_family().levels.read().len(),
+            lsm.default_column_family().levels.read().len()
+        );
+        for i in 0..4 {
+            for key in (i * 200)..((i + 1) * 200) {
+                assert_eq!(reopened.get(&key), Some(vec![i as u8; 8]));
+            }
+        }
+
+        let num_runs = fs::read_dir(dir.path())
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension() == Some("run".as_ref()))
+            .count();
+        let num_runs_in_levels: usize = reopened
+            .default_column_family()
+            .levels
+            .read()
+            .iter()
+            .map(|level| level.read().runs.len())
+            .sum();
+        assert_eq!(num_runs, num_runs_in_levels);
+    }
+
+    #[test]
+    fn lsm_close_flushes_memory_maps() {
+        let _ = env_logger::try_init();
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        let lsm = Lsm::new(Some(config)).unwrap();
+        for key in 0..100 {
+            lsm.put(key, vec![1u8]).unwrap();
+        }
+        lsm.close().unwrap();
+        // closing twice is fine
+        lsm.close().unwrap();
+        assert!(lsm.default_column_family().primary_memory_map.is_empty());
+        assert!(lsm.default_column_family().secondary_memory_map.is_empty());
+        assert_eq!(
+            lsm.default_column_family().levels.read()[0]
+                .read()
+                .runs
+                .len(),
+            1
+        );
+
+        let mut config = Config::default();
+        config.set_directory(dir.path());
+        let reopened: Arc<Lsm<i32>> = Lsm::new(Some(config)).unwrap();
+        // nothing is left in the log to replay
+        assert!(reopened
+            .default_column_family()
+            .primary_memory_map
+            .is_empty());
+        for key in 0..100 {
+            assert_eq!(reopened.get(&key), Some(vec![1u8]));
+        }
+        reopened.close().unwrap();
+    }
+
+    #[test]
+    fn lsm_close_unlocks_directory_and_refuses_writes() {
+        let _ = env_logger::try_init();
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        let lsm: Arc<Lsm<i32>> = Lsm::new(Some(config.clone())).unwrap();
+        lsm.put(1, vec![1u8]).unwrap();
+        // e.g. a column family or snapshot handle outliving the store
+        let handle = lsm.clone();
+        lsm.close().unwrap();
+
+        assert!(matches!(handle.put(2, vec![2u8]), Err(LsmError::Closed)));
+        assert!(matches!(handle.delete(&1), Err(LsmError::Closed)));
+        assert_eq!(handle.get(&1), Some(vec![1u8]));
+
+        let reopened: Arc<Lsm<i32>> = Lsm::new(Some(config)).unwrap();
+        assert_eq!(reopened.get(&1), Some(vec![1u8]));
+        assert_eq!(reopened.get(&2), None);
+        reopened.close().unwrap();
+    }
+
+    #[test]
+    fn dropping_without_close_stops_the_background_threads() {
+        let _ = env_logger::try_init();
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        let lsm: Arc<Lsm<i32>> = Lsm::new(Some(config.clone())).unwrap();
+        lsm.put(1, vec![1u8]).unwrap();
+        let workers: Vec<_> = lsm.workers.lock().drain(..).collect();
+        drop(lsm);
+        for handle in workers {
+            handle.join().unwrap();
+        }
+
+        // the directory was unlocked and the put is replayed from the log
+        let reopened: Arc<Lsm<i32>> = Lsm::new(Some(config)).unwrap();
+        assert_eq!(reopened.get(&1), Some(vec![1u8]));
+        reopened.close().unwrap();
+    }
+
+    #[test]
+    fn failed_flush_is_redone_before_its_maps_take_writes() {
+        let _ = env_logger::try_init();
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        let lsm: Arc<Lsm<i32>> = Lsm::new(Some(config.clone())).unwrap();
+        for key in 0..10 {
+            lsm.put(key, vec![1u8]).unwrap();
+        }
+        let primary = lsm.use_primary_map.load(Ordering::SeqCst);
+        // without the manifest the new runs can't be installed, so the flush fails after
+        // switching maps
+        let manifest = lsm.manifest.lock().take();
+        assert!(lsm.flush_memory_maps(primary).is_err());
+        *lsm.manifest.lock() = manifest;
+        for key in 10..20 {
+            lsm.put(key, vec![2u8]).unwrap();
+        }
+
+        // switching back to the maps of the failed flush would remove the only log of their
+        // writes, so they are flushed first
+        lsm.flush_memory_maps(!primary).unwrap();
+        let family = lsm.default_column_family();
+        assert!(!family.holds_writes(true));
+        assert!(!family.holds_writes(false));
+        assert_eq!(family.levels.read()[0].read().runs.len(), 2);
+        for key in 0..20 {
+            assert_eq!(lsm.get(&key), Some(vec![if key < 10 { 1u8 } else { 2u8 }]));
+        }
+        lsm.close().unwrap();
+
+        let reopened: Arc<Lsm<i32>> = Lsm::new(Some(config)).unwrap();
+        for key in 0..20 {
+            assert_eq!(
+                reopened.get(&key),
+                Some(vec![if key < 10 { 1u8 } else { 2u8 }])
+            );
+        }
+        reopened.close().unwrap();
+    }
+
+    #[test]
+    fn second_open_leaves_directory_alone() {
+        let _ = env_logger::try_init();
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        let lsm: Arc<Lsm<i32>> = Lsm::new(Some(config.clone())).unwrap();
+        lsm.put(1, vec![1u8]).unwrap();
+        let files = || -> Vec<PathBuf> {
+            let mut files: Vec<PathBuf> = fs::read_dir(dir.path())
+                .unwrap()
+                .map(|e| e.unwrap().path())
+                .collect();
+            files.sort();
+            return files;
+        };
+        let before = files();
+
+        assert!(matches!(
+            Lsm::<i32>::new(Some(config)),
+            Err(LsmError::ManifestError(ManifestError::Locked(_)))
+        ));
+        // no log segment or manifest was started by the refused open
+        assert_eq!(files(), before);
+        lsm.close().unwrap();
+    }
+
+    #[test]
+    fn snapshot_reads_survive_compaction() {
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.t = 2;
+        config.set_directory(dir.path());
+        let lsm: Arc<Lsm<i32>> = Lsm::new(Some(config)).unwrap();
+        let family = lsm.default_column_family();
+        let flush_and_compact = || {
+            lsm.flush_memory_maps(lsm.use_primary_map.load(Ordering::SeqCst))
+                .unwrap();
+            lsm.compact_column_family(&family).unwrap();
+        };
+        for i in 0..200 {
+            lsm.put(i, vec![0u8]).unwrap();
+        }
+        let seq = lsm.acquire_snapshot();
+        flush_and_compact();
+        for round in 1..6u8 {
+            for i in 0..200 {
+                lsm.put(i, vec![round]).unwrap();
+            }
+            lsm.delete(&7).unwrap();
+            flush_and_compact();
+        }
+        assert!(family.levels.read().len() > 1);
+
+        for i in 0..200 {
+            assert_eq!(lsm.get_at(&i, seq), Some(vec![0u8]));
+        }
+        assert_eq!(lsm.get(&7), None);
+        assert_eq!(lsm.get(&8), Some(vec![5u8]));
+        assert_eq!(lsm.range_at(0..200, seq).count(), 200);
+        lsm.release_snapshot(seq);
+    }
+
+    struct Counter;
+
+    impl MergeOperator for Counter {
+        fn merge(&self, existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Vec<u8> {
+            let mut count = existing.map_or(0, |v| u64::from_le_bytes(v.try_into().unwrap()));
+            for operand in operands {
+                count += u64::from_le_bytes(operand[..].try_into().unwrap());
+            }
+            return count.to_le_bytes().to_vec();
+        }
+    }
+
+    #[test]
+    fn merge_operands_fold_through_flushes_and_compactions() {
+        let dir = tempdir().unwrap();
+        let mut config = Config::default();
+        config.t = 2;
+        config.set_directory(dir.path());
+        config.set_merge_operator(Arc::new(Counter));
+        let lsm: Arc<Lsm<i32>> = Lsm::new(Some(config)).unwrap();
+        let family = lsm.default_column_family();
+        lsm.put(1, 100u64.to_le_bytes().to_vec()).unwrap();
+        for round in 0..5 {
+            for key in 0..100 {
+                lsm.merge(key, 1u64.to_le_bytes().to_vec()).unwrap();
+            }
+            if round == 2 {
+                lsm.delete(&2).unwrap();
+            }
+            lsm.flush_memory_maps(lsm.use_primary_map.load(Ordering::SeqCst))
+                .unwrap();
+            lsm.compact_column_family(&family).unwrap();
+        }
+        assert!(family.levels.read().len() > 1);
+
+        let count = |lsm: &Lsm<i32>, key| {
+            u64::from_le_bytes(lsm.get(&key).unwrap()[..].try_into().unwrap())
+        };
+        assert_eq!(count(&lsm, 0), 5);
+        assert_eq!(count(&lsm, 1), 105);
+        assert_eq!(count(&lsm, 2), 2);
+        let total: u64 = lsm
+            .range(..)
+            .map(|(_, v)| u64::from_le_bytes(v[..].try_into().unwrap()))
+            .sum();
+        assert_eq!(total, 5 * 100 + 100 - 3);
+        lsm.close().unwrap();
+        drop(lsm);
+
+        let mut config = Config::default();
+        config.set_directory(dir.path());
+        config.set_merge_operator(Arc::new(Counter));
+        let lsm: Arc<Lsm<i32>> = Lsm::new(Some(config)).unwrap();
+        assert_eq!(count(&lsm, 99), 5);
+        assert_eq!(count(&lsm, 1), 105);
+    }
+
+    #[test]
+    fn merge_without_operator_fails() {
+        let lsm: Arc<Lsm<i32>> = Lsm::new(None).unwrap();
+        assert!(matches!(
+            lsm.merge(1, vec![1u8]),
+            Err(LsmError::NoMergeOperator)
+        ));
+    }
+
+    #[test]
+    fn range_tombstones_survive_compaction() {
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.t = 2;
+        config.set_directory(dir.path());
+        let lsm: Arc<Lsm<i32>> = Lsm::new(Some(config)).unwrap();
+        let family = lsm.default_column_family();
+        for i in 0..200 {
+            lsm.put(i, vec![0u8]).unwrap();
+        }
+        lsm.delete_range(50, 150).unwrap();
+        for round in 1..6 {
+            for i in 0..100 {
+                lsm.put(1000 * round + i, vec![round as u8]).unwrap();
+            }
+            lsm.flush_memory_maps(lsm.use_primary_map.load(Ordering::SeqCst))
+                .unwrap();
+            lsm.compact_column_family(&family).unwrap();
+        }
+        assert!(family.levels.read().len() > 1);
+
+        assert_eq!(lsm.get(&49), Some(vec![0u8]));
+        assert_eq!(lsm.get(&50), None);
+        assert_eq!(lsm.get(&149), None);
+        assert_eq!(lsm.get(&150), Some(vec![0u8]));
+        assert_eq!(lsm.range(0..1000).count(), 100);
+    }
+
+    #[test]
+    fn column_families_compact_with_their_own_tuning() {
+        let _ = env_logger::try_init();
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        let mut small_config = Config::default();
+        small_config.t = 3;
+        config.add_column_family("small", small_config);
+        let lsm = Lsm::new(Some(config)).unwrap();
+        let default = lsm.default_column_family();
+        let small = lsm.column_family("small").unwrap();
+        for i in 0..4 {
+            for key in (i * 200)..((i + 1) * 200) {
+                lsm.put(key, vec![i as u8; 8]).unwrap();
+                lsm.write_to_column_family(
+                    small.id,
+                    WalRecord::Put {
+                        key: key,
+                        value: vec![i as u8 + 1; 8],
+                    },
+                )
+                .unwrap();
+            }
+            lsm.flush_memory_maps(lsm.use_primary_map.load(Ordering::SeqCst))
+                .unwrap();
+            lsm.compact_column_family(&default).unwrap();
+            lsm.compact_column_family(&small).unwrap();
+        }
+        // the families flush together but only the small one has compacted
+        assert_eq!(default.levels.read().len(), 1);
+        assert_eq!(default.levels.read()[0].read().runs.len(), 4);
+        assert_eq!(small.levels.read().len(), 2);
+        assert_eq!(small.levels.read()[1].read().runs.len(), 1);
+        for key in 0..800 {
+            assert_eq!(lsm.get(&key), Some(vec![(key / 200) as u8; 8]));
+            assert_eq!(
+                small.get_at(&key, lsm.last_seq()),
+                Some(vec![(key / 200) as u8 + 1; 8])
+            );
+        }
+    }
+
+    #[test_case(1, 1, vec![1, 1]; "leveling")]
+    #[test_case(2, 2, vec![2, 2]; "tiering")]
+    #[test_case(2, 1, vec![2, 1]; "lazy leveling")]
+    fn compaction_policy_shapes_levels(k: u64, z: u64, expected: Vec<usize>) {
+        let _ = env_logger::try_init();
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        config.set_compaction_policy(3, k, z).unwrap();
+        let lsm = Lsm::new(Some(config)).unwrap();
+        let family = lsm.default_column_family();
+        for i in 0..8 {
+            for key in (i * 50)..((i + 1) * 50) {
+                lsm.put(key, vec![i as u8; 8]).unwrap();
+            }
+            lsm.put(-1, vec![i as u8]).unwrap();
+            lsm.flush_memory_maps(lsm.use_primary_map.load(Ordering::SeqCst))
+                .unwrap();
+            lsm.compact_column_family(&family).unwrap();
+        }
+        let runs_per_level: Vec<usize> = family
+            .levels
+            .read()
+            .iter()
+            .map(|level| level.read().runs.len())
+            .collect();
+        assert_eq!(runs_per_level, expected);
+        for key in 0..400 {
+            assert_eq!(lsm.get(&key), Some(vec![(key / 50) as u8; 8]));
+        }
+        assert_eq!(lsm.get(&-1), Some(vec![7u8]));
+        assert_eq!(lsm.range(..).count(), 401);
+    }
+
+    #[test]
+    fn compaction_only_rewrites_overlapping_files() {
+        let _ = env_logger::try_init();
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        config.set_compaction_policy(3, 1, 1).unwrap();
+        config.set_max_file_size(2 * config.block_size).unwrap();
+        let lsm = Lsm::new(Some(config)).unwrap();
+        let family = lsm.default_column_family();
+        let mut rng = ChaChaRng::from_seed([7; 32]);
+        // random bytes after the first so blocks don't compress to nothing
+        let mut value = |first: u8| {
+            let mut value = vec![first; 100];
+            rng.fill(&mut value[1..]);
+            return value;
+        };
+        let flush_and_compact = || {
+            lsm.flush_memory_maps(lsm.use_primary_map.load(Ordering::SeqCst))
+                .unwrap();
+            lsm.compact_column_family(&family).unwrap();
+        };
+        let level_files = |level: usize| -> Vec<PathBuf> {
+            let levels = family.levels.read();
+            let runs = &levels[level - 1].read().runs;
+            return runs.iter().map(|run| run.file_name.clone()).collect();
+        };
+
+        // the third flush fills level 1, which moves to level 2 as one sorted run
+        for i in 0..3 {
+            for key in 0..2000 {
+                lsm.put(key, value(i)).unwrap();
+            }
+            flush_and_compact();
+        }
+        let before = level_files(2);
+        assert!(before.len() > 10, "{} files", before.len());
+        assert!(level_files(1).is_empty());
+
+        // moving a few keys down only rewrites the files holding them
+        for i in 3..6 {
+            for key in 500..510 {
+                lsm.put(key, value(i)).unwrap();
+            }
+            flush_and_compact();
+        }
+        assert!(level_files(1).is_empty());
+        let after = level_files(2);
+        let kept = before.iter().filter(|f| after.contains(f)).count();
+        assert!(kept >= before.len() - 2, "{} of {}", kept, before.len());
+        assert!(after.len() < before.len() + 3);
+
+        let levels = family.levels.read();
+        let level = levels[1].read();
+        assert_eq!(level.num_sorted_runs(), 1);
+        assert_eq!(level.runs.iter().map(|run| run.flushes).sum::<u64>(), 6);
+        for (i, run) in level.runs.iter().enumerate() {
+            for other in level.runs.iter().skip(i + 1) {
+                assert!(!run.overlaps(other));
+            }
+        }
+        drop(level);
+        drop(levels);
+        for key in 0..2000 {
+            let expected = if (500..510).contains(&key) { 5 } else { 2 };
+            assert_eq!(lsm.get(&key).unwrap()[0], expected);
+        }
+        assert_eq!(lsm.range(..).count(), 2000);
+    }
+
+    #[test_case(false ; "no snapshot")]
+    #[test_case(true ; "snapshot older than the deletes")]
+    fn bottom_compaction_drops_deletes(snapshot_before_deletes: bool) {
+        let _ = env_logger::try_init();
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        config.set_compaction_policy(2, 1, 1).unwrap();
+        let lsm = Lsm::new(Some(config)).unwrap();
+        let family = lsm.default_column_family();
+        for key in 0..100 {
+            lsm.put(key, vec![1u8; 8]).unwrap();
+        }
+        lsm.flush_memory_maps(lsm.use_primary_map.load(Ordering::SeqCst))
+            .unwrap();
+        let snapshot = if snapshot_before_deletes {
+            Some(lsm.acquire_snapshot())
+        } else {
+            None
+        };
+        for key in 0..100 {
+            lsm.delete(&key).unwrap();
+        }
+        // the second flush fills level 1, which moves down to the empty level 2
+        lsm.flush_memory_maps(lsm.use_primary_map.load(Ordering::SeqCst))
+            .unwrap();
+        lsm.compact_column_family(&family).unwrap();
+
+        let stats = lsm.compaction_stats();
+        assert_eq!(stats.compactions, 1);
+        assert_eq!(stats.entries_read, 200);
+        assert_eq!(lsm.range(..).count(), 0);
+        let num_files: usize = family
+            .levels
+            .read()
+            .iter()
+            .map(|l| l.read().runs.len())
+            .sum();
+        match snapshot {
+            Some(seq) => {
+                assert_eq!(stats.tombstones_dropped, 0);
+                assert_eq!(stats.entries_written, 200);
+                assert_eq!(num_files, 1);
+                // a transaction reading at the snapshot must see the deletes as conflicts
+                assert!(lsm.latest_seq(&5).unwrap() > seq);
+                assert_eq!(lsm.get_at(&5, seq), Some(vec![1u8; 8]));
+                lsm.release_snapshot(seq);
+            }
+            None => {
+                assert_eq!(stats.tombstones_dropped, 100);
+                assert_eq!(stats.entries_written, 0);
+                assert_eq!(num_files, 0);
+                assert_eq!(lsm.latest_seq(&5), None);
+            }
+        }
+    }
+
+    #[test]
+    fn compact_range_drops_bulk_deletes() {
+        let _ = env_logger::try_init();
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        // tiering with a large size ratio, so the background compactions leave level 1 alone
+        config.set_compaction_policy(10, 9, 9).unwrap();
+        let lsm = Lsm::new(Some(config)).unwrap();
+        let family = lsm.default_column_family();
+        for keys in [0..100, 100..200, 200..300].iter() {
+            for key in keys.clone() {
+                lsm.put(key, vec![1u8; 8]).unwrap();
+            }
+            lsm.flush_memory_maps(lsm.use_primary_map.load(Ordering::SeqCst))
+                .unwrap();
+        }
+        // the deletes are still in memory, compact_range flushes them first
+        for key in 0..100 {
+            lsm.delete(&key).unwrap();
+        }
+        let level_1_files = || family.levels.read()[0].read().runs.len();
+        assert_eq!(level_1_files(), 3);
+
+        // only the puts and deletes of 0..100 share keys with the range
+        let report = lsm.compact_range(20..30).unwrap();
+        assert_eq!(report.output_level, Some(1));
+        assert_eq!(report.files_read, 2);
+        assert_eq!(report.files_written, 0);
+        assert!(report.bytes_read > 0);
+        assert_eq!(report.bytes_written, 0);
+        assert_eq!(report.stats.entries_read, 200);
+        assert_eq!(report.stats.tombstones_dropped, 100);
+        assert_eq!(level_1_files(), 2);
+        assert_eq!(lsm.range(..).count(), 200);
+        assert_eq!(lsm.get(&150), Some(vec![1u8; 8]));
+
+        let report = lsm.compact_range(..).unwrap();
+        assert_eq!(report.output_level, Some(1));
+        assert_eq!(report.files_read, 2);
+        assert_eq!(report.files_written, 1);
+        assert!(report.bytes_written > 0);
+        assert_eq!(report.stats.entries_written, 200);
+        assert_eq!(level_1_files(), 1);
+        assert_eq!(lsm.range(..).count(), 200);
+        assert_eq!(lsm.compaction_stats().compactions, 2);
+
+        assert_eq!(
+            lsm.compact_range(1000..2000).unwrap(),
+            CompactionReport::default()
+        );
+    }
+
+    #[test]
+    fn compact_range_merges_into_deepest_level() {
+        let _ = env_logger::try_init();
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        config.set_compaction_policy(2, 1, 1).unwrap();
+        let lsm = Lsm::new(Some(config)).unwrap();
+        let family = lsm.default_column_family();
+        for i in 0..3 {
+            for key in 0..100 {
+                lsm.put(key, vec![i as u8; 8]).unwrap();
+            }
+            lsm.flush_memory_maps(lsm.use_primary_map.load(Ordering::SeqCst))
+                .unwrap();
+            lsm.compact_column_family(&family).unwrap();
+        }
+        lsm.put(1000, vec![3u8; 8]).unwrap();
+        let num_levels = family.levels.read().len();
+        assert!(num_levels >= 2);
+
+        let report = lsm.compact_range(..).unwrap();
+        assert_eq!(report.output_level, Some(num_levels));
+        let files: Vec<usize> = family
+            .levels
+            .read()
+            .iter()
+            .map(|l| l.read().runs.len())
+            .collect();
+        assert_eq!(files.iter().sum::<usize>(), report.files_written);
+        assert_eq!(files[num_levels - 1], report.files_written);
+        assert_eq!(
+            family.levels.read()[num_levels - 1]
+                .read()
+                .num_sorted_runs(),
+            1
+        );
+        assert_eq!(lsm.get(&50), Some(vec![2u8; 8]));
+        assert_eq!(lsm.get(&1000), Some(vec![3u8; 8]));
+        assert_eq!(lsm.range(..).count(), 101);
+    }
+
+    #[test]
+    fn flushes_and_compactions_go_through_the_rate_limiter() {
+        let _ = env_logger::try_init();
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        config.set_compaction_policy(2, 1, 1).unwrap();
+        let limiter = Arc::new(RateLimiter::new(0));
+        config.set_rate_limiter(limiter.clone());
+        let lsm = Lsm::new(Some(config)).unwrap();
+        let family = lsm.default_column_family();
+        for key in 0..100 {
+            lsm.put(key, vec![1u8; 8]).unwrap();
+        }
+        lsm.flush_memory_maps(lsm.use_primary_map.load(Ordering::SeqCst))
+            .unwrap();
+        let flushed = limiter.bytes_through(IoPriority::High);
+        let level_1_file = family.levels.read()[0].read().runs[0].file_name.clone();
+        assert_eq!(flushed, fs::metadata(level_1_file).unwrap().len());
+        assert_eq!(limiter.bytes_through(IoPriority::Low), 0);
+
+        for key in 0..100 {
+            lsm.put(key, vec![2u8; 8]).unwrap();
+        }
+        lsm.flush_memory_maps(lsm.use_primary_map.load(Ordering::SeqCst))
+            .unwrap();
+        lsm.compact_column_family(&family).unwrap();
+        assert_eq!(limiter.bytes_through(IoPriority::High), 2 * flushed);
+        assert!(limiter.bytes_through(IoPriority::Low) > 0);
+        assert_eq!(lsm.get(&5), Some(vec![2u8; 8]));
+    }
+
+    #[test]
+    fn writers_wake_the_flush_and_compaction_threads() {
+        let _ = env_logger::try_init();
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        config.set_memory_map_budget(1000).unwrap();
+        config.set_compaction_policy(2, 1, 1).unwrap();
+        let lsm = Lsm::new(Some(config)).unwrap();
+        let family = lsm.default_column_family();
+        let num_files = || -> Vec<usize> {
+            return family
+                .levels
+                .read()
+                .iter()
+                .map(|l| l.read().runs.len())
+                .collect();
+        };
+        let wait_for = |done: &dyn Fn() -> bool| {
+            // generous for a loaded machine, this only catches threads which are never woken
+            let start = Instant::now();
+            while !done() {
+                assert!(start.elapsed() < Duration::from_secs(30));
+                sleep(Duration::from_millis(5));
+            }
+        };
+
+        for key in 0..100 {
+            lsm.put(key, vec![1u8; 16]).unwrap();
+        }
+        wait_for(&|| num_files().iter().sum::<usize>() > 0);
+        for key in 100..200 {
+            lsm.put(key, vec![1u8; 16]).unwrap();
+        }
+        // the second flush fills level 1 and wakes the compaction thread
+        wait_for(&|| lsm.compaction_stats().compactions > 0);
+
+        // close wakes both threads up and joins them
+        lsm.close().unwrap();
+        assert!(lsm.workers.lock().is_empty());
+        assert_eq!(lsm.range(..).count(), 200);
+    }
+
+    #[test]
+    fn writers_stop_while_flushes_fall_behind() {
+        let _ = env_logger::try_init();
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        config.set_memory_map_budget(1000).unwrap();
+        config.set_memory_map_stall(1.5, 2.0).unwrap();
+        // slow enough that every flush takes a while
+        config.set_rate_limiter(Arc::new(RateLimiter::new(20 * 1024)));
+        let lsm = Lsm::new(Some(config)).unwrap();
+        let family = lsm.default_column_family();
+        for key in 0..300 {
+            lsm.put(key, vec![1u8; 16]).unwrap();
+            let memory_use = family.primary_memory_map_memory_use.load(Ordering::SeqCst)
+                + family
+                    .secondary_memory_map_memory_use
+                    .load(Ordering::SeqCst);
+            // a write only goes ahead while the maps are under the stop threshold
+            assert!(memory_use < 2100, "{}", memory_use);
+        }
+        let stats = lsm.write_stall_stats();
+        assert!(stats.stopped_writes > 0);
+        assert!(stats.slowed_writes > 0);
+        assert!(stats.stall_time > Duration::from_millis(0));
+        for key in 0..300 {
+            assert_eq!(lsm.get(&key), Some(vec![1u8; 16]));
+        }
+    }
+
+    #[test]
+    fn writers_slow_down_once_level_1_fills_up() {
+        let _ = env_logger::try_init();
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        config.set_level1_stall(1, 1).unwrap();
+        config.set_write_slowdown_delay(Duration::from_millis(20));
+        let lsm = Lsm::new(Some(config)).unwrap();
+        lsm.put(1, vec![1u8; 8]).unwrap();
+        assert_eq!(lsm.write_stall_stats(), WriteStallStats::default());
+
+        lsm.flush_memory_maps(lsm.use_primary_map.load(Ordering::SeqCst))
+            .unwrap();
+        // the policy keeps up to 10 runs in level 1, so writes are delayed but never stopped
+        let start = Instant::now();
+        lsm.put(2, vec![2u8; 8]).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        let stats = lsm.write_stall_stats();
+        assert_eq!(stats.slowed_writes, 1);
+        assert_eq!(stats.stopped_writes, 0);
+        assert!(stats.stall_time >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn key_locks_only_hold_up_writes_to_their_stripes() {
+        let lsm: Arc<Lsm<i32>> = Lsm::new(None).unwrap();
+        let other = (2..)
+            .find(|key| lsm.key_locks.stripe(key) != lsm.key_locks.stripe(&1))
+            .unwrap();
+        // as a compare and swap on key 1 does while it reads the current value
+        let guards = lsm.key_locks.lock(&WalRecord::Delete { key: 1 }, &[]);
+        lsm.put(other, vec![1u8]).unwrap();
+        assert_eq!(lsm.get(&other), Some(vec![1u8]));
+
+        let writer = {
+            let lsm = lsm.clone();
+            spawn(move || lsm.put(1, vec![1u8]).unwrap())
+        };
+        sleep(Duration::from_millis(50));
+        assert_eq!(lsm.get(&1), None);
+        drop(guards);
+        writer.join().unwrap();
+        assert_eq!(lsm.get(&1), Some(vec![1u8]));
+    }
+
+    // Removes multiples of 3 and appends a byte to values of keys one above them
+    struct Thirds;
+
+    impl CompactionFilter for Thirds {
+        fn filter(&self, key: &dyn Any, value: &[u8]) -> FilterDecision {
+            let key = key.downcast_ref::<i32>().unwrap();
+            return match key % 3 {
+                0 => FilterDecision::Remove,
+                1 => FilterDecision::ChangeValue([value, &[9u8]].concat()),
+                _ => FilterDecision::Keep,
+            };
+        }
+    }
+
+    #[test_case(false ; "no snapshot")]
+    #[test_case(true ; "snapshot reading older versions")]
+    fn compaction_filter_removes_and_rewrites_values(hold_snapshot: bool) {
+        let _ = env_logger::try_init();
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        config.set_compaction_policy(2, 1, 1).unwrap();
+        config.set_compaction_filter(Arc::new(Thirds));
+        let lsm = Lsm::new(Some(config)).unwrap();
+        let family = lsm.default_column_family();
+        for key in 0..30 {
+            lsm.put(key, vec![1u8]).unwrap();
+        }
+        lsm.flush_memory_maps(lsm.use_primary_map.load(Ordering::SeqCst))
+            .unwrap();
+        lsm.put(31, vec![1u8]).unwrap();
+        let snapshot = if hold_snapshot {
+            Some(lsm.acquire_snapshot())
+        } else {
+            None
+        };
+        lsm.put(31, vec![2u8]).unwrap();
+        lsm.flush_memory_maps(lsm.use_primary_map.load(Ordering::SeqCst))
+            .unwrap();
+        assert_eq!(lsm.get(&3), Some(vec![1u8]));
+        lsm.compact_column_family(&family).unwrap();
+
+        assert_eq!(lsm.get(&31), Some(vec![2u8, 9u8]));
+        for key in 0..30 {
+            let expected = match key % 3 {
+                0 => None,
+                1 => Some(vec![1u8, 9u8]),
+                _ => Some(vec![1u8]),
+            };
+            assert_eq!(lsm.get(&key), expected);
+            // the versions kept for a snapshot are filtered too
+            if let Some(seq) = snapshot {
+                assert_eq!(lsm.get_at(&key, seq), expected);
+            }
+        }
+        if let Some(seq) = snapshot {
+            assert_eq!(lsm.get_at(&31, seq), Some(vec![1u8, 9u8]));
+            lsm.release_snapshot(seq);
+        }
+    }
+
+    // Removes the operands of key 1 and doubles every value of key 2, as counters
+    struct Doubler;
+
+    impl CompactionFilter for Doubler {
+        fn filter(&self, key: &dyn Any, value: &[u8]) -> FilterDecision {
+            let count = u64::from_le_bytes(value.try_into().unwrap());
+            return match key.downcast_ref::<i32>().unwrap() {
+                1 => FilterDecision::Remove,
+                2 => FilterDecision::ChangeValue((2 * count).to_le_bytes().to_vec()),
+                _ => FilterDecision::Keep,
+            };
+        }
+    }
+
+    #[test]
+    fn compaction_filter_sees_merge_operands() {
+        let _ = env_logger::try_init();
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        config.set_merge_operator(Arc::new(Counter));
+        config.set_compaction_filter(Arc::new(Doubler));
+        let lsm: Arc<Lsm<i32>> = Lsm::new(Some(config)).unwrap();
+        lsm.put(2, 10u64.to_le_bytes().to_vec()).unwrap();
+        for _ in 0..3 {
+            lsm.merge(1, 1u64.to_le_bytes().to_vec()).unwrap();
+            lsm.merge(2, 1u64.to_le_bytes().to_vec()).unwrap();
+            lsm.merge(3, 1u64.to_le_bytes().to_vec()).unwrap();
+        }
+        lsm.compact_range(..).unwrap();
+
+        let count = |key| u64::from_le_bytes(lsm.get(&key).unwrap()[..].try_into().unwrap());
+        assert_eq!(lsm.get(&1), None);
+        assert_eq!(count(2), 20 + 3 * 2);
+        assert_eq!(count(3), 3);
+        lsm.close().unwrap();
     }
 
-    fn insert_vals(map: Arc<Lsm>, size: u64) {
+    fn insert_vals(map: Arc<Lsm<i32>>, size: u64) {
         let mut curr_size: u64 = 0;
         let seed = [42; 32];
         let mut rng = ChaChaRng::from_seed(seed);
@@ -396,7 +2309,7 @@ mod test_run {
             let rand_key: i32 = rng.gen_range(-50000..50000);
             let rand_val = gen_rand_bytes(&mut rng);
             curr_size += 4 + rand_val.len() as u64;
-            map.put(rand_key, rand_val);
+            map.put(rand_key, rand_val).unwrap();
             num_gen_items += 1;
         }
         info!("Generated {} key/value pairs", num_gen_items);
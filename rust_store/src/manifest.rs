@@ -0,0 +1,680 @@
+use crate::key::Key;
+use crate::run::{Level, RunError};
+use crate::wal::{read_record, write_record};
+use log::{debug, error, info, warn};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{read_dir, read_to_string, remove_file, rename, File, OpenOptions, TryLockError};
+use std::io;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+const CURRENT_FILE_NAME: &str = "CURRENT";
+const MANIFEST_PREFIX: &str = "MANIFEST-";
+const LOCK_FILE_NAME: &str = "LOCK";
+
+#[derive(Error, Debug)]
+pub enum ManifestError {
+    #[error("IO Error")]
+    IoError(#[from] io::Error),
+    #[error("Error Serializing")]
+    SerializeError(#[from] bincode::Error),
+    #[error("Error in a run")]
+    RunError(#[from] RunError),
+    #[error("Corrupt manifest: '{0}'")]
+    Corrupt(String),
+    #[error("Directory is in use by another store: '{0}'")]
+    Locked(String),
+    #[error("Store was written with comparator '{found}', keys use '{expected}'")]
+    ComparatorMismatch { expected: String, found: String },
+}
+
+/// A single change to which runs make up which levels.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum VersionEdit {
+    /// A run was added to a level, creating the level if needed
+    AddRun { level: usize, file: PathBuf },
+    /// A run was removed from a level
+    RemoveRun { level: usize, file: PathBuf },
+    /// A column family was created
+    CreateColumnFamily { id: u32, name: String },
+    /// An edit to the levels of a column family other than the default one
+    ColumnFamily { id: u32, edit: Box<VersionEdit> },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum ManifestRecord {
+    /// The full state, the first record of every manifest, with the comparator the keys of
+    /// the store are ordered by
+    Snapshot {
+        comparator: String,
+        catalogue: Catalogue,
+    },
+    /// Edits which are applied together or not at all
+    Edits(Vec<VersionEdit>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Catalogue {
+    // each outer vector is a level ordered 1 -> n
+    // each inner vector is a run, earlier runs are older
+    // run paths are relative to the database directory
+    levels: Vec<Vec<PathBuf>>,
+    // column families other than the default one by id, with their names
+    column_families: BTreeMap<u32, (String, Catalogue)>,
+}
+
+impl Catalogue {
+    pub fn apply(self: &mut Self, edit: &VersionEdit) {
+        match edit {
+            VersionEdit::AddRun { level, file } => {
+                while self.levels.len() < *level {
+                    self.levels.push(vec![]);
+                }
+                self.levels[level - 1].push(file.clone());
+            }
+            VersionEdit::RemoveRun { level, file } => {
+                if let Some(runs) = self.levels.get_mut(level - 1) {
+                    runs.retain(|f| f != file);
+                }
+            }
+            VersionEdit::CreateColumnFamily { id, name } => {
+                self.column_families
+                    .entry(*id)
+                    .or_insert_with(|| (name.clone(), Catalogue::default()));
+            }
+            VersionEdit::ColumnFamily { id, edit } => match self.column_families.get_mut(id) {
+                Some((_, catalogue)) => catalogue.apply(edit),
+                None => warn!("Ignoring edit to unknown column family {}", id),
+            },
+        }
+    }
+
+    pub fn contains(self: &Self, file: &Path) -> bool {
+        return self
+            .levels
+            .iter()
+            .any(|runs| runs.iter().any(|f| f == file))
+            || self
+                .column_families
+                .values()
+                .any(|(_, catalogue)| catalogue.contains(file));
+    }
+
+    /// Ids and names of the column families other than the default one
+    pub fn column_families(self: &Self) -> Vec<(u32, String)> {
+        return self
+            .column_families
+            .iter()
+            .map(|(id, (name, _))| (*id, name.clone()))
+            .collect();
+    }
+
+    pub fn column_family_levels<K: Key>(
+        self: &Self,
+        id: u32,
+        directory: &Path,
+    ) -> Result<Vec<RwLock<Level<K>>>, ManifestError> {
+        return match self.column_families.get(&id) {
+            Some((_, catalogue)) => catalogue.get_levels(directory),
+            None => Ok(vec![]),
+        };
+    }
+
+    pub fn get_levels<K: Key>(
+        self: &Self,
+        directory: &Path,
+    ) -> Result<Vec<RwLock<Level<K>>>, ManifestError> {
+        let mut levels = vec![];
+        for run_files in &self.levels {
+            levels.push(RwLock::new(Level::read_from_disk(directory, run_files)?));
+        }
+        return Ok(levels);
+    }
+}
+
+/// A log of version edits describing which runs belong to which level.
+///
+/// `CURRENT` names the active manifest file. Every manifest starts with a snapshot of the
+/// catalogue followed by groups of edits, each group is synced before it is considered applied.
+/// Once a manifest grows past `max_size` a new one is started from a snapshot and the old
+/// one is removed.
+pub struct Manifest {
+    directory: PathBuf,
+    number: u64,
+    comparator: String,
+    writer: BufWriter<File>,
+    size: u64,
+    max_size: u64,
+    catalogue: Catalogue,
+    /// An append failed part way, so the file may end in a torn record which recovery would
+    /// stop at. No more edits are appended to it.
+    torn: bool,
+    /// Holds an exclusive lock on `LOCK` for as long as the manifest is open
+    _lock: File,
+}
+
+impl Manifest {
+    /// Open the manifest in `directory`, recovering the catalogue if there is one.
+    ///
+    /// A torn group of edits at the end of the log is ignored, so the catalogue is the last fully
+    /// written version. Runs and manifests which are not part of that version are removed, so
+    /// the directory is locked first and opening it fails while another store has it open.
+    /// Without a manifest to recover nothing says which runs belong to the store, so opening a
+    /// directory which already holds runs fails instead.
+    ///
+    /// Opening fails if the store was written with keys ordered by a comparator other than
+    /// `comparator`, even if it has no runs yet.
+    pub fn open(
+        directory: &Path,
+        max_size: u64,
+        comparator: &str,
+    ) -> Result<Manifest, ManifestError> {
+        let lock = lock_directory(directory)?;
+        let current_path = directory.join(CURRENT_FILE_NAME);
+        let (number, catalogue) = if current_path.exists() {
+            let name = read_to_string(&current_path)?;
+            let number = parse_manifest_number(name.trim()).ok_or_else(|| {
+                ManifestError::Corrupt(format!("CURRENT names unknown file {}", name.trim()))
+            })?;
+            let (found, catalogue) = read_manifest(&directory.join(name.trim()))?;
+            if found != comparator {
+                return Err(ManifestError::ComparatorMismatch {
+                    expected: comparator.to_string(),
+                    found: found,
+                });
+            }
+            (number, catalogue)
+        } else {
+            if let Some(run) = find_run(directory)? {
+                return Err(ManifestError::Corrupt(format!(
+                    "{:?} holds runs but no manifest, e.g. {:?}",
+                    directory, run
+                )));
+            }
+            (0, Catalogue::default())
+        };
+
+        let (writer, size) = write_manifest(directory, number + 1, comparator, &catalogue)?;
+        let manifest = Manifest {
+            directory: PathBuf::from(directory),
+            number: number + 1,
+            comparator: comparator.to_string(),
+            writer: writer,
+            size: size,
+            max_size: max_size,
+            catalogue: catalogue,
+            torn: false,
+            _lock: lock,
+        };
+        manifest.remove_old_manifests()?;
+        manifest.remove_unreferenced_runs()?;
+        return Ok(manifest);
+    }
+
+    pub fn catalogue(self: &Self) -> &Catalogue {
+        return &self.catalogue;
+    }
+
+    /// Durably record a group of edits, after this returns they will survive a crash.
+    ///
+    /// If the edits can't be written none of them are applied. The manifest they were partly
+    /// written to is then replaced by a new one before anything else is logged, and edits are
+    /// refused until that succeeds.
+    pub fn log(self: &mut Self, edits: Vec<VersionEdit>) -> Result<(), ManifestError> {
+        if self.torn {
+            // recovery would stop at the torn record and miss every edit after it
+            self.roll()?;
+        }
+        if let Err(e) = self.append(&edits) {
+            self.torn = true;
+            return Err(e);
+        }
+        for edit in &edits {
+            self.catalogue.apply(edit);
+        }
+        if self.size > self.max_size {
+            // the edits are durable already, a failed roll is retried by the next log
+            if let Err(e) = self.roll() {
+                error!("Error rolling manifest: {:?}", e);
+            }
+        }
+        return Ok(());
+    }
+
+    fn append(self: &mut Self, edits: &[VersionEdit]) -> Result<(), ManifestError> {
+        self.size += write_record(&mut self.writer, &ManifestRecord::Edits(edits.to_vec()))?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        return Ok(());
+    }
+
+    /// Start a new manifest from a snapshot of the catalogue and remove the old one
+    pub fn roll(self: &mut Self) -> Result<(), ManifestError> {
+        info!("Rolling manifest to {}{}", MANIFEST_PREFIX, self.number + 1);
+        let (writer, size) = write_manifest(
+            &self.directory,
+            self.number + 1,
+            &self.comparator,
+            &self.catalogue,
+        )?;
+        self.number += 1;
+        self.writer = writer;
+        self.size = size;
+        self.torn = false;
+        // the new manifest is in use either way, leftovers are removed by the next roll or open
+        if let Err(e) = self.remove_old_manifests() {
+            warn!("Error removing old manifests: {:?}", e);
+        }
+        return Ok(());
+    }
+
+    fn remove_old_manifests(self: &Self) -> Result<(), ManifestError> {
+        for entry in read_dir(&self.directory)? {
+            let path = entry?.path();
+            let number = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(parse_manifest_number);
+            if number.is_some() && number != Some(self.number) {
+                debug!("Removing old manifest {:?}", &path);
+                remove_file(&path)?;
+            }
+        }
+        return Ok(());
+    }
+
+    // Remove run files which are not part of the current version, e.g. the output of a
+    // compaction which never made it into the manifest. Only safe before any new runs are created.
+    fn remove_unreferenced_runs(self: &Self) -> Result<(), ManifestError> {
+        for entry in read_dir(&self.directory)? {
+            let path = entry?.path();
+            if !is_run(&path) {
+                continue;
+            }
+            if !self.catalogue.contains(Path::new(path.file_name().unwrap())) {
+                warn!("Removing run {:?} which is not in the manifest", &path);
+                remove_file(&path)?;
+            }
+        }
+        return Ok(());
+    }
+}
+
+// Writes a new manifest holding `catalogue` and points CURRENT at it, returns the writer to
+// append edits with and the bytes written so far
+fn write_manifest(
+    directory: &Path,
+    number: u64,
+    comparator: &str,
+    catalogue: &Catalogue,
+) -> Result<(BufWriter<File>, u64), ManifestError> {
+    let name = format!("{}{}", MANIFEST_PREFIX, number);
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(directory.join(&name))?;
+    let mut writer = BufWriter::new(file);
+    let snapshot = ManifestRecord::Snapshot {
+        comparator: comparator.to_string(),
+        catalogue: catalogue.clone(),
+    };
+    let size = write_record(&mut writer, &snapshot)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+
+    // the new manifest only takes effect once CURRENT is swapped to it
+    let tmp_path = directory.join(format!("{}.tmp", CURRENT_FILE_NAME));
+    let mut current = File::create(&tmp_path)?;
+    current.write_all(name.as_bytes())?;
+    current.sync_all()?;
+    rename(&tmp_path, directory.join(CURRENT_FILE_NAME))?;
+    // CURRENT names the new manifest from here on, so it has to be used even if the
+    // directory can't be synced
+    if let Err(e) = File::open(directory).and_then(|dir| dir.sync_all()) {
+        error!(
+            "Error syncing {:?} after swapping CURRENT: {:?}",
+            directory, e
+        );
+    }
+
+    return Ok((writer, size));
+}
+
+fn is_run(path: &Path) -> bool {
+    return path.extension().and_then(|e| e.to_str()) == Some("run");
+}
+
+// Returns a run file in `directory`, if there is any
+fn find_run(directory: &Path) -> Result<Option<PathBuf>, ManifestError> {
+    for entry in read_dir(directory)? {
+        let path = entry?.path();
+        if is_run(&path) {
+            return Ok(Some(path));
+        }
+    }
+    return Ok(None);
+}
+
+// Takes the lock on the directory which is held for as long as the store is open
+fn lock_directory(directory: &Path) -> Result<File, ManifestError> {
+    let lock = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(directory.join(LOCK_FILE_NAME))?;
+    return match lock.try_lock() {
+        Ok(()) => Ok(lock),
+        Err(TryLockError::WouldBlock) => Err(ManifestError::Locked(format!("{:?}", directory))),
+        Err(TryLockError::Error(e)) => Err(ManifestError::IoError(e)),
+    };
+}
+
+fn parse_manifest_number(file_name: &str) -> Option<u64> {
+    if !file_name.starts_with(MANIFEST_PREFIX) {
+        return None;
+    }
+    return file_name[MANIFEST_PREFIX.len()..].parse().ok();
+}
+
+// Returns the comparator the store was written with and its catalogue
+fn read_manifest(path: &Path) -> Result<(String, Catalogue), ManifestError> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let (comparator, mut catalogue) = match read_record(&mut reader)? {
+        Some(ManifestRecord::Snapshot {
+            comparator,
+            catalogue,
+        }) => (comparator, catalogue),
+        _ => {
+            return Err(ManifestError::Corrupt(format!(
+                "{:?} does not start with a snapshot",
+                path
+            )))
+        }
+    };
+    let mut num_edits = 0;
+    loop {
+        match read_record(&mut reader)? {
+            Some(ManifestRecord::Edits(edits)) => {
+                for edit in &edits {
+                    catalogue.apply(edit);
+                }
+                num_edits += 1;
+            }
+            Some(ManifestRecord::Snapshot { .. }) => {
+                warn!("Ignoring unexpected snapshot in {:?}", path);
+            }
+            None => break,
+        }
+    }
+    info!("Recovered catalogue from {:?} with {} edits", path, num_edits);
+    return Ok((comparator, catalogue));
+}
+
+#[cfg(test)]
+mod test_manifest {
+    use crate::manifest::{Catalogue, Manifest, ManifestError, VersionEdit, MANIFEST_PREFIX};
+    use std::fs::{create_dir, read_dir, remove_dir, File, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    const COMPARATOR: &str = "rust_kv.Ord";
+
+    fn add(level: usize, file: &str) -> VersionEdit {
+        return VersionEdit::AddRun {
+            level: level,
+            file: PathBuf::from(file),
+        };
+    }
+
+    fn remove(level: usize, file: &str) -> VersionEdit {
+        return VersionEdit::RemoveRun {
+            level: level,
+            file: PathBuf::from(file),
+        };
+    }
+
+    fn num_manifests(dir: &std::path::Path) -> usize {
+        return read_dir(dir)
+            .unwrap()
+            .filter(|e| {
+                let name = e.as_ref().unwrap().file_name();
+                name.to_str().unwrap().starts_with(MANIFEST_PREFIX)
+            })
+            .count();
+    }
+
+    #[test]
+    fn manifest_recovers_edits() {
+        let _ = env_logger::try_init();
+        let dir = tempdir().unwrap();
+        let mut expected = Catalogue::default();
+        {
+            let mut manifest = Manifest::open(dir.path(), 1024 * 1024, COMPARATOR).unwrap();
+            let edits = vec![
+                vec![add(1, "a.run")],
+                vec![add(1, "b.run")],
+                vec![remove(1, "a.run"), remove(1, "b.run"), add(2, "c.run")],
+            ];
+            for group in edits {
+                for edit in &group {
+                    expected.apply(edit);
+                }
+                manifest.log(group).unwrap();
+            }
+            assert_eq!(manifest.catalogue(), &expected);
+        }
+        let manifest = Manifest::open(dir.path(), 1024 * 1024, COMPARATOR).unwrap();
+        assert_eq!(manifest.catalogue(), &expected);
+        assert!(manifest.catalogue().contains(&PathBuf::from("c.run")));
+        assert!(!manifest.catalogue().contains(&PathBuf::from("a.run")));
+    }
+
+    #[test]
+    fn manifest_ignores_torn_edit() {
+        let _ = env_logger::try_init();
+        let dir = tempdir().unwrap();
+        let name = {
+            let mut manifest = Manifest::open(dir.path(), 1024 * 1024, COMPARATOR).unwrap();
+            manifest.log(vec![add(1, "a.run")]).unwrap();
+            format!("{}{}", MANIFEST_PREFIX, manifest.number)
+        };
+        // simulate crashing part way through logging a compaction
+        let mut f = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(name))
+            .unwrap();
+        f.write_all(&[200u8, 0u8, 0u8, 0u8, 1u8, 2u8, 3u8]).unwrap();
+
+        let manifest = Manifest::open(dir.path(), 1024 * 1024, COMPARATOR).unwrap();
+        let mut expected = Catalogue::default();
+        expected.apply(&add(1, "a.run"));
+        assert_eq!(manifest.catalogue(), &expected);
+    }
+
+    #[test]
+    fn manifest_rolls_after_failed_append() {
+        let _ = env_logger::try_init();
+        let dir = tempdir().unwrap();
+        let mut manifest = Manifest::open(dir.path(), 1024 * 1024, COMPARATOR).unwrap();
+        manifest.log(vec![add(1, "a.run")]).unwrap();
+        let first_number = manifest.number;
+        // simulate an append which failed after writing part of its record
+        manifest
+            .writer
+            .write_all(&[200u8, 0u8, 0u8, 0u8, 1u8, 2u8, 3u8])
+            .unwrap();
+        manifest.writer.flush().unwrap();
+        manifest.torn = true;
+
+        manifest.log(vec![add(1, "b.run")]).unwrap();
+        assert!(manifest.number > first_number);
+        let mut expected = Catalogue::default();
+        expected.apply(&add(1, "a.run"));
+        expected.apply(&add(1, "b.run"));
+        assert_eq!(manifest.catalogue(), &expected);
+        drop(manifest);
+
+        let reopened = Manifest::open(dir.path(), 1024 * 1024, COMPARATOR).unwrap();
+        assert_eq!(reopened.catalogue(), &expected);
+    }
+
+    #[test]
+    fn failed_roll_keeps_manifest() {
+        let _ = env_logger::try_init();
+        let dir = tempdir().unwrap();
+        let mut manifest = Manifest::open(dir.path(), 256, COMPARATOR).unwrap();
+        // the next manifest can't be created while a directory has its name
+        let next = dir
+            .path()
+            .join(format!("{}{}", MANIFEST_PREFIX, manifest.number + 1));
+        create_dir(&next).unwrap();
+        let first_number = manifest.number;
+        for i in 0..50 {
+            manifest.log(vec![add(1, &format!("{}.run", i))]).unwrap();
+        }
+        assert_eq!(manifest.number, first_number);
+        assert!(manifest.catalogue().contains(&PathBuf::from("49.run")));
+
+        remove_dir(&next).unwrap();
+        manifest.log(vec![add(1, "50.run")]).unwrap();
+        assert!(manifest.number > first_number);
+        let catalogue = manifest.catalogue().clone();
+        drop(manifest);
+
+        let reopened = Manifest::open(dir.path(), 256, COMPARATOR).unwrap();
+        assert_eq!(reopened.catalogue(), &catalogue);
+    }
+
+    #[test]
+    fn manifest_rolls_and_removes_old_manifests() {
+        let _ = env_logger::try_init();
+        let dir = tempdir().unwrap();
+        let mut manifest = Manifest::open(dir.path(), 256, COMPARATOR).unwrap();
+        let first_number = manifest.number;
+        for i in 0..50 {
+            manifest.log(vec![add(1, &format!("{}.run", i))]).unwrap();
+        }
+        assert!(manifest.number > first_number);
+        assert_eq!(num_manifests(dir.path()), 1);
+        let catalogue = manifest.catalogue().clone();
+        drop(manifest);
+
+        let reopened = Manifest::open(dir.path(), 256, COMPARATOR).unwrap();
+        assert_eq!(reopened.catalogue(), &catalogue);
+        assert_eq!(num_manifests(dir.path()), 1);
+    }
+
+    #[test]
+    fn column_families_survive_roll() {
+        let _ = env_logger::try_init();
+        let dir = tempdir().unwrap();
+        let mut manifest = Manifest::open(dir.path(), 256, COMPARATOR).unwrap();
+        manifest
+            .log(vec![VersionEdit::CreateColumnFamily {
+                id: 1,
+                name: String::from("users"),
+            }])
+            .unwrap();
+        let first_number = manifest.number;
+        for i in 0..50 {
+            manifest
+                .log(vec![
+                    add(1, &format!("{}.run", i)),
+                    VersionEdit::ColumnFamily {
+                        id: 1,
+                        edit: Box::new(add(1, &format!("users-{}.run", i))),
+                    },
+                ])
+                .unwrap();
+        }
+        assert!(manifest.number > first_number);
+        let catalogue = manifest.catalogue().clone();
+        drop(manifest);
+
+        let reopened = Manifest::open(dir.path(), 256, COMPARATOR).unwrap();
+        assert_eq!(reopened.catalogue(), &catalogue);
+        assert_eq!(
+            reopened.catalogue().column_families(),
+            vec![(1, String::from("users"))]
+        );
+        assert!(reopened
+            .catalogue()
+            .contains(&PathBuf::from("users-49.run")));
+    }
+
+    #[test]
+    fn manifest_locks_directory() {
+        let _ = env_logger::try_init();
+        let dir = tempdir().unwrap();
+        let mut manifest = Manifest::open(dir.path(), 1024 * 1024, COMPARATOR).unwrap();
+        manifest.log(vec![add(1, "a.run")]).unwrap();
+        File::create(dir.path().join("a.run")).unwrap();
+        assert!(matches!(
+            Manifest::open(dir.path(), 1024 * 1024, COMPARATOR),
+            Err(ManifestError::Locked(_))
+        ));
+        // the failed open leaves the store's files alone
+        assert!(dir.path().join("a.run").exists());
+        assert_eq!(num_manifests(dir.path()), 1);
+
+        drop(manifest);
+        let reopened = Manifest::open(dir.path(), 1024 * 1024, COMPARATOR).unwrap();
+        assert!(reopened.catalogue().contains(&PathBuf::from("a.run")));
+    }
+
+    #[test]
+    fn manifest_refuses_other_comparator() {
+        let _ = env_logger::try_init();
+        let dir = tempdir().unwrap();
+        {
+            let mut manifest = Manifest::open(dir.path(), 1024 * 1024, COMPARATOR).unwrap();
+            manifest.log(vec![add(1, "a.run")]).unwrap();
+        }
+        File::create(dir.path().join("a.run")).unwrap();
+        assert!(matches!(
+            Manifest::open(dir.path(), 1024 * 1024, "test.Reverse"),
+            Err(ManifestError::ComparatorMismatch { .. })
+        ));
+        // nothing is removed or rewritten by the failed open
+        assert!(dir.path().join("a.run").exists());
+        assert_eq!(num_manifests(dir.path()), 1);
+
+        let reopened = Manifest::open(dir.path(), 1024 * 1024, COMPARATOR).unwrap();
+        assert!(reopened.catalogue().contains(&PathBuf::from("a.run")));
+    }
+
+    #[test]
+    fn manifest_refuses_runs_without_manifest() {
+        let _ = env_logger::try_init();
+        let dir = tempdir().unwrap();
+        File::create(dir.path().join("a.run")).unwrap();
+        assert!(matches!(
+            Manifest::open(dir.path(), 1024 * 1024, COMPARATOR),
+            Err(ManifestError::Corrupt(_))
+        ));
+        assert!(dir.path().join("a.run").exists());
+        assert_eq!(num_manifests(dir.path()), 0);
+    }
+
+    #[test]
+    fn manifest_removes_unreferenced_runs() {
+        let _ = env_logger::try_init();
+        let dir = tempdir().unwrap();
+        {
+            let mut manifest = Manifest::open(dir.path(), 1024 * 1024, COMPARATOR).unwrap();
+            manifest.log(vec![add(1, "kept.run")]).unwrap();
+        }
+        File::create(dir.path().join("kept.run")).unwrap();
+        // e.g. the output of a compaction which was never logged
+        File::create(dir.path().join("orphan.run")).unwrap();
+
+        let _manifest = Manifest::open(dir.path(), 1024 * 1024, COMPARATOR).unwrap();
+        assert!(dir.path().join("kept.run").exists());
+        assert!(!dir.path().join("orphan.run").exists());
+    }
+}
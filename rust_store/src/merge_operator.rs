@@ -0,0 +1,70 @@
+use crate::key::{now_millis, Value};
+use log::error;
+
+/// Combines the operands written with `RustStore::merge` into a value.
+///
+/// Operands are stored as they are written and folded together lazily, by reads and by
+/// compactions. A compaction may fold some operands into a value and a later read fold the
+/// rest into that, so merging in two steps must give the same value as merging in one.
+///
+/// # Examples
+///
+/// ```
+/// use rust_kv::MergeOperator;
+/// use std::convert::TryInto;
+///
+/// struct Counter;
+///
+/// impl MergeOperator for Counter {
+///     fn merge(&self, existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Vec<u8> {
+///         let mut count = existing.map_or(0, |v| u64::from_le_bytes(v.try_into().unwrap()));
+///         for operand in operands {
+///             count += u64::from_le_bytes(operand[..].try_into().unwrap());
+///         }
+///         return count.to_le_bytes().to_vec();
+///     }
+/// }
+/// ```
+pub trait MergeOperator: Send + Sync {
+    /// Apply `operands`, oldest first, to `existing`, the value before the first of them or
+    /// None if the key had no value.
+    fn merge(&self, existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Vec<u8>;
+}
+
+/// Fold the versions of one key, newest first, into the value a reader sees. Only versions
+/// up to the first put or delete are used, an expired put reads as a delete.
+pub(crate) fn resolve<I>(versions: I, operator: Option<&dyn MergeOperator>) -> Option<Vec<u8>>
+where
+    I: Iterator<Item = Value>,
+{
+    let now = now_millis();
+    let mut operands = vec![];
+    let mut existing = None;
+    for value in versions {
+        match value {
+            Value::Put(value) => {
+                existing = Some(value);
+                break;
+            }
+            Value::Expiring { value, expires_at } => {
+                if expires_at > now {
+                    existing = Some(value);
+                }
+                break;
+            }
+            Value::Delete => break,
+            Value::Merge(operand) => operands.push(operand),
+        }
+    }
+    if operands.is_empty() {
+        return existing;
+    }
+    operands.reverse();
+    return match operator {
+        Some(operator) => Some(operator.merge(existing.as_deref(), &operands)),
+        None => {
+            error!("Found merge operands but no merge operator is set, ignoring them");
+            existing
+        }
+    };
+}
@@ -0,0 +1,271 @@
+use parking_lot::{Condvar, Mutex};
+use std::cmp::{max, min};
+use std::io;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// The bucket holds at most this long's worth of writes, so an idle limiter can't let a burst
+// of more than that through
+const REFILL_PERIOD: Duration = Duration::from_millis(100);
+
+/// Which background writes are let through first once the rate limit is reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+    /// Memory map flushes, writers are waiting on them to free up memory
+    High,
+    /// Compactions
+    Low,
+}
+
+struct Bucket {
+    /// Bytes which may be written right now, negative once a write larger than the bucket
+    /// has gone through
+    available: i64,
+    last_refill: Instant,
+    /// High priority requests waiting for bytes, low priority ones wait until there are none
+    high_waiting: usize,
+}
+
+/// Limits how many bytes per second flushes and compactions write to disk, so they leave
+/// some of it to foreground reads.
+///
+/// A token bucket which fills up at the rate and holds at most 100 milliseconds worth of
+/// bytes. Low priority requests wait while any high priority one is waiting, so flushes go
+/// ahead of compactions. A rate of 0 means no limit. The limiter is shared through `Config`,
+/// the rate can be changed while the store is open.
+///
+/// # Examples
+///
+/// ```
+/// use rust_kv::{Config, RateLimiter};
+/// use std::sync::Arc;
+/// let limiter = Arc::new(RateLimiter::new(16 * 1024 * 1024));
+/// let mut config = Config::default();
+/// config.set_rate_limiter(limiter.clone());
+/// // later, while the store is open
+/// limiter.set_bytes_per_sec(64 * 1024 * 1024);
+/// ```
+pub struct RateLimiter {
+    bytes_per_sec: AtomicU64,
+    bucket: Mutex<Bucket>,
+    refilled: Condvar,
+    high_priority_bytes: AtomicU64,
+    low_priority_bytes: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> RateLimiter {
+        return RateLimiter {
+            bytes_per_sec: AtomicU64::new(bytes_per_sec),
+            bucket: Mutex::new(Bucket {
+                available: RateLimiter::capacity(bytes_per_sec),
+                last_refill: Instant::now(),
+                high_waiting: 0,
+            }),
+            refilled: Condvar::new(),
+            high_priority_bytes: AtomicU64::new(0),
+            low_priority_bytes: AtomicU64::new(0),
+        };
+    }
+
+    pub fn bytes_per_sec(self: &Self) -> u64 {
+        return self.bytes_per_sec.load(Ordering::SeqCst);
+    }
+
+    /// Change the rate, requests already waiting pick it up straight away. 0 removes the limit.
+    pub fn set_bytes_per_sec(self: &Self, bytes_per_sec: u64) {
+        self.bytes_per_sec.store(bytes_per_sec, Ordering::SeqCst);
+        self.refilled.notify_all();
+    }
+
+    /// Bytes let through at `priority` since the limiter was created
+    pub fn bytes_through(self: &Self, priority: IoPriority) -> u64 {
+        return match priority {
+            IoPriority::High => self.high_priority_bytes.load(Ordering::SeqCst),
+            IoPriority::Low => self.low_priority_bytes.load(Ordering::SeqCst),
+        };
+    }
+
+    /// Block until `bytes` may be written at `priority`.
+    ///
+    /// A request larger than the bucket goes through once the bucket is full and leaves it in
+    /// debt, so later requests make up for it.
+    pub fn request(self: &Self, bytes: u64, priority: IoPriority) {
+        match priority {
+            IoPriority::High => self.high_priority_bytes.fetch_add(bytes, Ordering::SeqCst),
+            IoPriority::Low => self.low_priority_bytes.fetch_add(bytes, Ordering::SeqCst),
+        };
+        let mut bucket = self.bucket.lock();
+        if priority == IoPriority::High {
+            bucket.high_waiting += 1;
+        }
+        loop {
+            let rate = self.bytes_per_sec();
+            if rate == 0 {
+                break;
+            }
+            let capacity = RateLimiter::capacity(rate);
+            let now = Instant::now();
+            let refill = rate as u128 * (now - bucket.last_refill).as_micros() / 1_000_000;
+            if refill > 0 {
+                bucket.available = min(capacity, bucket.available + refill as i64);
+                bucket.last_refill = now;
+            }
+
+            let needed = min(bytes, capacity as u64) as i64;
+            let my_turn = priority == IoPriority::High || bucket.high_waiting == 0;
+            if my_turn && bucket.available >= needed {
+                bucket.available -= bytes as i64;
+                break;
+            }
+            let wait = if my_turn {
+                let missing = (needed - bucket.available) as u128;
+                let micros = max(missing * 1_000_000 / rate as u128, 1000) as u64;
+                min(Duration::from_micros(micros), REFILL_PERIOD)
+            } else {
+                REFILL_PERIOD
+            };
+            self.refilled.wait_for(&mut bucket, wait);
+        }
+        if priority == IoPriority::High {
+            bucket.high_waiting -= 1;
+            if bucket.high_waiting == 0 {
+                // low priority requests may have been passed over
+                self.refilled.notify_all();
+            }
+        }
+    }
+
+    fn capacity(bytes_per_sec: u64) -> i64 {
+        let per_period = bytes_per_sec as u128 * REFILL_PERIOD.as_millis() / 1000;
+        return max(per_period as i64, 1);
+    }
+}
+
+/// Asks the rate limiter, if there is one, before every write to the inner writer
+pub(crate) struct RateLimitedWriter<W: Write> {
+    inner: W,
+    limiter: Option<Arc<RateLimiter>>,
+    priority: IoPriority,
+}
+
+impl<W: Write> RateLimitedWriter<W> {
+    pub(crate) fn new(
+        inner: W,
+        limiter: Option<Arc<RateLimiter>>,
+        priority: IoPriority,
+    ) -> RateLimitedWriter<W> {
+        return RateLimitedWriter {
+            inner: inner,
+            limiter: limiter,
+            priority: priority,
+        };
+    }
+
+    pub(crate) fn get_ref(self: &Self) -> &W {
+        return &self.inner;
+    }
+}
+
+impl<W: Write> Write for RateLimitedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(limiter) = &self.limiter {
+            limiter.request(buf.len() as u64, self.priority);
+        }
+        return self.inner.write(buf);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod test_rate_limiter {
+    use crate::rate_limiter::{IoPriority, RateLimitedWriter, RateLimiter};
+    use std::io::Write;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const KB: u64 = 1024;
+
+    #[test]
+    fn unlimited_never_waits() {
+        let limiter = RateLimiter::new(0);
+        let start = Instant::now();
+        for _ in 0..100 {
+            limiter.request(1024 * 1024 * 1024, IoPriority::Low);
+        }
+        // a hundred gigabytes at any real rate would take minutes
+        assert!(start.elapsed() < Duration::from_secs(30));
+        assert_eq!(
+            limiter.bytes_through(IoPriority::Low),
+            100 * 1024 * 1024 * 1024
+        );
+        assert_eq!(limiter.bytes_through(IoPriority::High), 0);
+    }
+
+    #[test]
+    fn requests_are_held_to_the_rate() {
+        let limiter = RateLimiter::new(1000 * KB);
+        let start = Instant::now();
+        // the first 100KB are in the bucket already
+        for _ in 0..10 {
+            limiter.request(100 * KB, IoPriority::Low);
+        }
+        // about 900ms at the rate, a slow runner only takes longer
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(500), "{:?}", elapsed);
+        assert_eq!(limiter.bytes_through(IoPriority::Low), 1000 * KB);
+    }
+
+    #[test]
+    fn writes_larger_than_the_bucket_go_through() {
+        let limiter = Arc::new(RateLimiter::new(10 * KB));
+        let mut writer = RateLimitedWriter::new(vec![], Some(limiter.clone()), IoPriority::High);
+        writer.write_all(&vec![1u8; 100 * KB as usize]).unwrap();
+        assert_eq!(writer.get_ref().len(), 100 * KB as usize);
+        assert_eq!(limiter.bytes_through(IoPriority::High), 100 * KB);
+    }
+
+    #[test]
+    fn rate_changes_reach_waiting_requests() {
+        let limiter = Arc::new(RateLimiter::new(10 * KB));
+        // leaves the bucket almost 1000KB in debt, about a hundred seconds at this rate
+        limiter.request(1000 * KB, IoPriority::Low);
+        let waiting = limiter.clone();
+        let start = Instant::now();
+        let handle = thread::spawn(move || waiting.request(KB, IoPriority::Low));
+        thread::sleep(Duration::from_millis(100));
+        limiter.set_bytes_per_sec(0);
+        handle.join().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(30));
+    }
+
+    #[test]
+    fn high_priority_goes_first() {
+        let limiter = Arc::new(RateLimiter::new(1000 * KB));
+        let low_done = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let limiter = limiter.clone();
+            let low_done = low_done.clone();
+            thread::spawn(move || {
+                for _ in 0..30 {
+                    limiter.request(100 * KB, IoPriority::Low);
+                }
+                low_done.store(true, Ordering::SeqCst);
+            })
+        };
+        thread::sleep(Duration::from_millis(200));
+        limiter.request(100 * KB, IoPriority::High);
+        // the compaction still has seconds of writes left
+        assert!(!low_done.load(Ordering::SeqCst));
+        handle.join().unwrap();
+        assert_eq!(limiter.bytes_through(IoPriority::High), 100 * KB);
+        assert_eq!(limiter.bytes_through(IoPriority::Low), 3000 * KB);
+    }
+}
@@ -1,29 +1,43 @@
 // use crate::bloom_filter::bloom_filter::BloomFilter;
 use crate::fence_pointer::fence_pointer::FencePointer;
 use bincode::{ErrorKind, Serializer};
-use crossbeam_skiplist::SkipMap;
 use flate2::write::DeflateEncoder;
 use flate2::Compression;
 use serde::{Deserialize, Serialize};
 // use anyhow::Result;
 use crate::bloom_filter::BloomFilter;
+use crate::compaction::CompactionStats;
+use crate::compaction_filter::{CompactionFilter, FilterDecision};
+use crate::key::{now_millis, Key, MemoryMap, RangeTombstone, Value};
+use crate::merge_operator::{resolve, MergeOperator};
+use crate::rate_limiter::{IoPriority, RateLimitedWriter};
 use crate::rust_store;
 use bincode::Options;
 use flate2::read::DeflateDecoder;
-use itertools::EitherOrBoth::{Both, Left, Right};
 use itertools::Itertools;
 use log::{debug, error, info, trace, warn};
+use rayon::prelude::*;
+use std::borrow::Borrow;
+use std::cell::Cell;
+use std::cmp::{max, min, Reverse};
 use std::fmt::Debug;
 use std::fs::{remove_file, File};
+use std::hash::Hash;
 use std::io;
 use std::io::prelude::*;
 use std::io::{BufReader, BufWriter, Cursor, SeekFrom};
 use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::iter::Peekable;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 use thiserror::Error;
 
+static RUN_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
 #[derive(Error, Debug)]
 pub enum RunError {
     #[error("IO Error")]
@@ -32,72 +46,150 @@ pub enum RunError {
     DeserializeError(#[from] bincode::Error),
     #[error("Creating run from memory map")]
     RunCreationError,
+    #[error("Run was written with comparator '{found}', keys use '{expected}'")]
+    ComparatorMismatch { expected: String, found: String },
     #[error("Not yet implemented")]
     NotImplemented,
 }
 
-pub struct Level {
+pub struct Level<K: Key> {
     pub num_runs: usize, // doesn't need to be atomic because Levels are wrapped in RwLocks
-    pub runs: Vec<Arc<Run>>,
+    pub runs: Vec<Arc<Run<K>>>,
 }
 
-impl Level {
-    pub fn read_from_disk() -> Level {
-        unimplemented!();
+impl<K: Key> Level<K> {
+    /// Load the runs making up a level, `run_files` are relative to `directory` and
+    /// ordered oldest to newest.
+    pub fn read_from_disk(
+        directory: &Path,
+        run_files: &Vec<PathBuf>,
+    ) -> Result<Level<K>, RunError> {
+        let mut runs = vec![];
+        for file in run_files {
+            runs.push(Arc::new(Run::load(&directory.join(file))?));
+        }
+        return Ok(Level {
+            num_runs: runs.len(),
+            runs: runs,
+        });
     }
 
-    pub fn get_from_level(&self, key: &i32) -> Option<Vec<u8>> {
-        if self.runs.len() == 0 {
-            return None;
-        };
-        for run in &self.runs {
-            let maybe_res = run.get_from_run(key);
-            if maybe_res.is_some() {
-                return maybe_res;
+    /// Number of sorted runs in the level, each may be split over several run files
+    pub fn num_sorted_runs(self: &Self) -> usize {
+        return self.runs.iter().map(|run| &run.sorted_run).unique().count();
+    }
+
+    /// The newest version of `key` in the level written at or before `seq`, or `found` if that
+    /// is newer. A delete is returned as an item without a value.
+    ///
+    /// Versions are compared by sequence number, so the order of `runs` does not matter.
+    pub fn get_from_level<Q>(&self, key: &Q, seq: u64, found: Option<Item<K>>) -> Option<Item<K>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + Hash + Debug + ToOwned<Owned = K> + ?Sized,
+    {
+        let mut found = found;
+        let mut runs: Vec<&Arc<Run<K>>> = self.runs.iter().collect();
+        runs.sort_by_key(|run| Reverse(run.max_seq));
+        for run in runs {
+            // the remaining runs only hold writes older than the version already found
+            if let Some(item) = &found {
+                if item.seq >= run.max_seq {
+                    break;
+                }
+            }
+            if let Some(item) = run.get_version(key, seq) {
+                if found.as_ref().map_or(true, |f| item.seq > f.seq) {
+                    found = Some(item);
+                }
             }
         }
-        return None;
+        return found;
     }
 }
 
 #[derive(Serialize, Deserialize)]
-pub struct Item<K: Ord + Copy> {
+// Key already requires the serde traits
+#[serde(bound = "")]
+pub struct Item<K: Key> {
     key: K,
-    value: Option<Vec<u8>>,
+    seq: u64,
+    value: Value,
 }
 
-impl<K: Ord + Copy> Item<K> {
-    pub fn new(key: K, value: Option<Vec<u8>>) -> Item<K> {
+impl<K: Key> Item<K> {
+    pub fn new(key: K, seq: u64, value: Value) -> Item<K> {
         return Item {
             key: key,
+            seq: seq,
             value: value,
         };
     }
 
+    /// Sequence number of the write that produced this item
+    pub fn seq(self: &Self) -> u64 {
+        return self.seq;
+    }
+
     pub fn key(self) -> K {
         return self.key;
     }
 
-    pub fn value(self) -> Option<Vec<u8>> {
+    pub fn value(self) -> Value {
         return self.value;
     }
+
+    pub fn key_value(self) -> (K, Value) {
+        return (self.key, self.value);
+    }
 }
 
 //this struct lives at the end of the on disk file
 // the last 8 bytes are a u64 describing the length of the serialized representation
 #[derive(Serialize, Deserialize)]
-pub struct Run {
+#[serde(bound = "")]
+pub struct Run<K: Key> {
+    // first so it can be checked before deserializing anything else
+    comparator: String,
+    // highest sequence number of any item, used to restore the sequence number on startup
+    pub max_seq: u64,
+    /// Range deletes written to the memory map the run came from, or carried down by compactions
+    pub range_tombstones: Vec<RangeTombstone<K>>,
     pub num_blocks: usize,
     pub level: usize,
     bloom_filter: BloomFilter,
-    fence_pointers: Vec<FencePointer<i32>>,
+    fence_pointers: Vec<FencePointer<K>>,
     pub file_name: PathBuf,
     block_size: u64,
     size_in_bytes: usize,
     num_elements: usize,
+    /// Number of memory map flushes the run's writes came from, level capacities are measured
+    /// in flushes
+    pub flushes: u64,
+    /// Compactions write a sorted run as several files with disjoint key ranges, the files of
+    /// one sorted run share this id
+    pub sorted_run: String,
+}
+
+// A key range of a merge, merged on its own thread
+struct Subcompaction<K: Key> {
+    start: Bound<K>,
+    end: Bound<K>,
+    // shares of the merge's estimated items and flushes
+    num_elements: usize,
+    flushes: u64,
 }
 
-impl Run {
+// Where the output of a compaction is cut into files
+struct FileSplit {
+    sorted_run: String,
+    // a file is closed at the first key boundary once it holds this many blocks
+    max_blocks: usize,
+    // items expected in a full file, sizes its bloom filter
+    elements_per_file: usize,
+}
+
+impl<K: Key> Run<K> {
     /// Get disk usage in bytes
     pub fn size_in_bytes(self: &Self) -> usize {
         return self.size_in_bytes;
@@ -105,47 +197,148 @@ impl Run {
 
     pub fn get_memory_use_in_bytes(self: &Self) -> usize {
         return self.bloom_filter.size_in_bytes()
-            + self.num_blocks * self.fence_pointers[0].size_in_bytes();
+            + self
+                .fence_pointers
+                .first()
+                .map_or(0, |fp| self.num_blocks * fp.size_in_bytes());
+    }
+
+    /// The smallest key in the run and the bound on its largest, range deletes included. None
+    /// if the run is empty.
+    pub fn key_range(self: &Self) -> Option<(&K, Bound<&K>)> {
+        let low = self
+            .fence_pointers
+            .first()
+            .map(|fp| fp.low())
+            .into_iter()
+            .chain(self.range_tombstones.iter().map(|t| &t.start))
+            .min()?;
+        let last_key = self.fence_pointers.last().map(|fp| fp.high());
+        let tombstone_end = self.range_tombstones.iter().map(|t| &t.end).max();
+        let high = match (last_key, tombstone_end) {
+            (Some(key), Some(end)) if end > key => Bound::Excluded(end),
+            (Some(key), _) => Bound::Included(key),
+            (None, Some(end)) => Bound::Excluded(end),
+            (None, None) => return None,
+        };
+        return Some((low, high));
+    }
+
+    /// True if a key could be in both this run and `other`
+    pub fn overlaps(self: &Self, other: &Run<K>) -> bool {
+        return match (self.key_range(), other.key_range()) {
+            (Some((low, high)), Some((other_low, other_high))) => {
+                (Bound::Included(other_low), other_high).contains(low)
+                    || (Bound::Included(low), high).contains(other_low)
+            }
+            _ => false,
+        };
     }
 
-    // given a (full) SkipMap construct a run from it
-    // on disk each run has metadata, and two files. One of values and one of keys.
-    // this can be refactored to use run_from_iterator once I implement my own Skiplist, or
-    // wrap the cross bream skiplist to return Items
+    /// True if a key in the range from `start` to `end` could be in this run
+    pub fn overlaps_range(self: &Self, start: Bound<&K>, end: Bound<&K>) -> bool {
+        let (low, high) = match self.key_range() {
+            Some(range) => range,
+            None => return false,
+        };
+        let starts_before_end = match end {
+            Bound::Included(end) => low <= end,
+            Bound::Excluded(end) => low < end,
+            Bound::Unbounded => true,
+        };
+        let ends_after_start = match (start, high) {
+            (Bound::Unbounded, _) | (_, Bound::Unbounded) => true,
+            (Bound::Included(start), Bound::Included(high)) => start <= high,
+            (Bound::Included(start), Bound::Excluded(high))
+            | (Bound::Excluded(start), Bound::Included(high))
+            | (Bound::Excluded(start), Bound::Excluded(high)) => start < high,
+        };
+        return starts_before_end && ends_after_start;
+    }
+
+    /// given a (full) SkipMap construct a level 1 run from it
     pub fn new_from_skipmap(
-        memory_map: Arc<SkipMap<i32, Option<Vec<u8>>>>,
+        memory_map: Arc<MemoryMap<K>>,
+        config: &rust_store::Config,
+    ) -> Result<Run<K>, RunError> {
+        return Run::new_from_skipmap_with_range_tombstones(memory_map, vec![], config);
+    }
+
+    /// given a SkipMap and the range deletes written alongside it construct a level 1 run,
+    /// the map may be empty if there are range deletes
+    pub fn new_from_skipmap_with_range_tombstones(
+        memory_map: Arc<MemoryMap<K>>,
+        mut range_tombstones: Vec<RangeTombstone<K>>,
         config: &rust_store::Config,
-    ) -> Result<Run, RunError> {
+    ) -> Result<Run<K>, RunError> {
         let num_elements = memory_map.len();
-        if memory_map.len() == 0 {
+        if num_elements == 0 && range_tombstones.is_empty() {
             return Err(RunError::RunCreationError);
         }
-        let fpr = 0.1; // TODO calculate the intended fpr using level
         info!(
-            "Creating a new run from a memory map with fpr {} with {} elements",
-            fpr,
-            memory_map.len()
+            "Creating a new run from a memory map with {} elements",
+            num_elements
         );
-        let mut filter = BloomFilter::new_with_rate(fpr, memory_map.len());
-        let mut fence_pointers = vec![];
+        let it = memory_map
+            .iter()
+            .map(|x| Item::new(x.key().key.clone(), x.key().seq, x.value().clone()));
+        return Run::run_from_iterator(
+            &mut it.peekable(),
+            &mut range_tombstones,
+            config,
+            1,
+            num_elements,
+            1,
+            None,
+            IoPriority::High,
+        );
+    }
+
+    /// given an iterator that produces Items create a new run at the designated level.
+    ///
+    /// Without `split` every item and range delete goes into the run. With it the run is closed
+    /// at the first key boundary once it holds `split.max_blocks` blocks, the rest of `it` is
+    /// left for the next file of the sorted run. The range deletes are then cut at the first
+    /// key of the next file and the part after it is left in `range_tombstones`. The run gets
+    /// a share of `flushes` by the number of its items out of the `num_elements` expected, or
+    /// all of them if it is the last file.
+    ///
+    /// The file is written through the config's rate limiter at `priority`, if it has one.
+    fn run_from_iterator<I>(
+        it: &mut Peekable<I>,
+        range_tombstones: &mut Vec<RangeTombstone<K>>,
+        config: &rust_store::Config,
+        level: usize,
+        num_elements: usize,
+        flushes: u64,
+        split: Option<&FileSplit>,
+        priority: IoPriority,
+    ) -> Result<Run<K>, RunError>
+    where
+        I: Iterator<Item = Item<K>>,
+    {
+        let fpr = 0.1; // TODO calculate the intended fpr using level
 
-        let mut min_val: i32 = 0;
-        let mut max_val: i32 = 0;
-
-        let now = SystemTime::now();
-        let epoch_time = now
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_millis();
-        let file_name = format!("1_{}.run", epoch_time);
-        let mut path = match &config.directory {
-            None => "".parse().unwrap(),
-            Some(pb) => pb.clone(),
+        let expected_elements = match split {
+            Some(split) => min(num_elements, split.elements_per_file),
+            None => num_elements,
         };
-        path.push(file_name);
+        let mut filter = BloomFilter::new_with_rate(fpr, max(expected_elements, 1));
+        let mut fence_pointers = vec![];
+        let mut written: usize = 0;
+
+        let mut max_seq: u64 = 0;
+        let mut min_val: Option<K> = None;
+        let mut max_val: Option<K> = None;
+
+        let path = new_run_path(config, level);
         let file = File::create(&path)?;
         info!("Run file name {}", &file.metadata().unwrap().len());
-        let writer = BufWriter::new(file);
+        let writer = BufWriter::new(RateLimitedWriter::new(
+            file,
+            config.rate_limiter.clone(),
+            priority,
+        ));
 
         // This is an index into a page
         let mut idx: u64 = 0;
@@ -158,11 +351,12 @@ impl Run {
         let mut encoder = DeflateEncoder::new(writer, Compression::default());
         // we compress each page individually
 
-        for x in memory_map.iter() {
+        while let Some(item) = it.next() {
+            written += 1;
             encoder.flush()?;
             idx = encoder.total_out();
-            let item: Item<i32> = Item::new(x.key().clone(), x.value().clone());
             filter.insert(&item.key);
+            max_seq = max(max_seq, item.seq);
             // we use ser_length because I don't know how to get compressed length
             let ser_length = bincode_opts.serialized_size(&item)?;
             // finish out the page
@@ -195,19 +389,24 @@ impl Run {
                 }
                 // open up a new encoder for the next page
                 writer.flush()?;
-
                 encoder = DeflateEncoder::new(writer, Compression::default());
                 idx = 0;
-                fence_pointers.push(FencePointer::new(min_val, max_val));
+                fence_pointers.push(FencePointer::new(
+                    min_val.take().unwrap(),
+                    max_val.take().unwrap(),
+                ));
 
                 // start the next page.
-                // if there is a bug related to min/max values if there is one value on a page, this is why
                 num_pages += 1;
-                min_val = item.key;
+                min_val = Some(item.key.clone());
+                max_val = Some(item.key.clone());
                 bincode_opts.serialize_into(&mut encoder, &item)?;
             } else {
                 bincode_opts.serialize_into(&mut encoder, &item)?;
-                max_val = item.key;
+                if min_val.is_none() {
+                    min_val = Some(item.key.clone());
+                }
+                max_val = Some(item.key.clone());
             }
             if !(idx < config.block_size as u64) {
                 error!(
@@ -217,8 +416,55 @@ impl Run {
                 );
                 debug_assert!(idx < config.block_size);
             }
+            if let Some(split) = split {
+                // the versions of a key always stay in one file
+                let full = fence_pointers.len() + 1 >= split.max_blocks;
+                if full
+                    && it
+                        .peek()
+                        .map_or(false, |next| Some(&next.key) != max_val.as_ref())
+                {
+                    break;
+                }
+            }
         }
 
+        // the file holds the range deletes up to the first key of the next one
+        let next_key = it.peek().map(|item| item.key.clone());
+        let range_tombstones = match &next_key {
+            None => std::mem::take(range_tombstones),
+            Some(next_key) => {
+                let mut kept = vec![];
+                let mut rest = vec![];
+                for t in range_tombstones.drain(..) {
+                    if t.start < *next_key {
+                        let end = min(t.end.clone(), next_key.clone());
+                        kept.push(RangeTombstone::new(t.start.clone(), end, t.seq));
+                    }
+                    if t.end > *next_key {
+                        rest.push(RangeTombstone::new(
+                            max(t.start, next_key.clone()),
+                            t.end,
+                            t.seq,
+                        ));
+                    }
+                }
+                *range_tombstones = rest;
+                kept
+            }
+        };
+        max_seq = max(
+            max_seq,
+            range_tombstones.iter().map(|t| t.seq).max().unwrap_or(0),
+        );
+        let flushes = match next_key {
+            None => flushes,
+            Some(_) => min(
+                flushes,
+                (flushes as u128 * written as u128 / max(num_elements, 1) as u128) as u64,
+            ),
+        };
+
         //close out the last page
         // finish the page
         let mut writer = encoder.finish()?;
@@ -234,7 +480,21 @@ impl Run {
         );
         idx = idx + padding_byte_len;
         debug_assert!(idx == config.block_size as u64);
-        fence_pointers.push(FencePointer::new(min_val, max_val));
+        match (min_val, max_val) {
+            (Some(min_val), Some(max_val)) => {
+                fence_pointers.push(FencePointer::new(min_val, max_val))
+            }
+            _ if !range_tombstones.is_empty() => {
+                // the run only holds range deletes, the empty page is never read
+                num_pages = 0;
+            }
+            _ => {
+                // nothing was written, don't leave an empty run behind
+                drop(writer);
+                remove_file(&path)?;
+                return Err(RunError::RunCreationError);
+            }
+        }
 
         // The metadata struct is not compressed
         if fence_pointers.len() != num_pages {
@@ -248,283 +508,563 @@ impl Run {
 
         let run_bytes = fence_pointers.len() * config.block_size as usize
             + filter.size_in_bytes()
-            + fence_pointers.len() * fence_pointers[0].size_in_bytes();
+            + fence_pointers
+                .first()
+                .map_or(0, |fp| fence_pointers.len() * fp.size_in_bytes());
 
         info!(
             "Creating run {:?} metadata with {} pages total size in bytes {}",
             path, num_pages, run_bytes
         );
+        let sorted_run = match split {
+            Some(split) => split.sorted_run.clone(),
+            None => unique_suffix(),
+        };
         let run = Run {
+            comparator: K::comparator_name().to_string(),
+            max_seq: max_seq,
+            range_tombstones: range_tombstones,
             num_blocks: fence_pointers.len(),
-            level: 1,
+            level: level,
             bloom_filter: filter,
             fence_pointers: fence_pointers,
             file_name: path.clone(),
             block_size: config.block_size,
             size_in_bytes: run_bytes,
-            num_elements: num_elements,
+            num_elements: written,
+            flushes: flushes,
+            sorted_run: sorted_run,
         };
 
         let ser_meta = bincode::serialize(&run)?;
-        let ser_meta_length = writer.write(&ser_meta)?;
-        let ser_ser_meta_length = bincode::serialize(&ser_meta_length)?;
-        writer.write(&ser_ser_meta_length)?;
+        writer.write_all(&ser_meta)?;
+        let ser_ser_meta_length = bincode::serialize(&(ser_meta.len() as u64))?;
+        writer.write_all(&ser_ser_meta_length)?;
         writer.flush()?;
+        // the run has to be on disk before the catalogue can refer to it
+        writer.get_ref().get_ref().sync_all()?;
         return Ok(run);
     }
 
-    /// given an iterator that produces Items create a new run at the designated level
-    fn run_from_iterator<K>(
-        it: K,
-        config: &rust_store::Config,
-        level: usize,
-        num_elements: usize,
-    ) -> Result<Run, RunError>
+    /// Given two runs, return an iterator over their merged items
+    /// If a key exists in both runs only the newest version is kept.
+    pub fn runs_to_iterator(
+        left: Arc<Run<K>>,
+        right: Arc<Run<K>>,
+    ) -> impl Iterator<Item = Item<K>> {
+        let iterators: Vec<Box<dyn Iterator<Item = Item<K>>>> =
+            vec![Box::new(left.into_iter()), Box::new(right.into_iter())];
+        return Run::retain_versions(Run::merge_iterators(iterators), vec![]);
+    }
+
+    /// Merge iterators of items sorted by key and then newest first into one iterator with the
+    /// same order. Every version is kept.
+    pub fn merge_iterators<'a>(
+        iterators: Vec<Box<dyn Iterator<Item = Item<K>> + 'a>>,
+    ) -> impl Iterator<Item = Item<K>> + 'a {
+        return iterators
+            .into_iter()
+            .kmerge_by(|i, j| (&i.key, j.seq) < (&j.key, i.seq));
+    }
+
+    /// Drop the versions of a key which no reader can see. The newest version of each key is
+    /// kept, and for every snapshot the newest version written at or before it.
+    /// `snapshots` are sequence numbers in ascending order, `it` is sorted as produced by
+    /// `merge_iterators`.
+    pub fn retain_versions<I>(it: I, snapshots: Vec<u64>) -> impl Iterator<Item = Item<K>>
     where
-        K: Iterator<Item = Item<i32>>,
+        I: Iterator<Item = Item<K>>,
     {
-        let fpr = 0.1; // TODO calculate the intended fpr using level
-
-        let mut filter = BloomFilter::new_with_rate(fpr, num_elements);
-        let mut fence_pointers = vec![];
+        return Run::compact_versions(it, snapshots, None, false, vec![]);
+    }
 
-        let mut min_val: i32 = 0;
-        let mut max_val: i32 = 0;
-
-        let now = SystemTime::now();
-        let epoch_time = now
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_millis();
-        let file_name = format!("1_{}.run", epoch_time);
-        let mut path = match &config.directory {
-            None => "".parse().unwrap(),
-            Some(pb) => pb.clone(),
+    /// Like `retain_versions`, but merge operands are also folded with `operator` into the
+    /// version below them, unless a snapshot reads in between, versions deleted by
+    /// `range_tombstones` are dropped and expired puts lose their value. With `bottom` set
+    /// nothing older than `it` exists, so operands without a version below them are folded
+    /// and deletes and expired puts with nothing below them are dropped as well.
+    ///
+    /// Versions newer than the oldest snapshot are never dropped from the bottom, a
+    /// transaction reading at that snapshot finds out they were written through
+    /// `Lsm::latest_seq` when it commits.
+    pub fn compact_versions<I>(
+        it: I,
+        snapshots: Vec<u64>,
+        operator: Option<Arc<dyn MergeOperator>>,
+        bottom: bool,
+        range_tombstones: Vec<RangeTombstone<K>>,
+    ) -> impl Iterator<Item = Item<K>>
+    where
+        I: Iterator<Item = Item<K>>,
+    {
+        let now = now_millis();
+        let oldest_snapshot = snapshots.first().cloned();
+        let droppable = move |item: &Item<K>| {
+            return bottom && oldest_snapshot.map_or(true, |s| item.seq <= s);
         };
-        path.push(file_name);
-        let file = File::create(&path)?;
-        info!("Run file name {}", &file.metadata().unwrap().len());
-        let writer = BufWriter::new(file);
-
-        // This is an index into a page
-        let mut idx: u64 = 0;
-        let mut num_pages: usize = 1;
-
-        // data goes from memory map -> serialization -> compressor -> file
-
-        // We use the default options because we want varint encoding on our lengths
-        let bincode_opts = bincode::DefaultOptions::new();
-        let mut encoder = DeflateEncoder::new(writer, Compression::default());
-        // we compress each page individually
-
-        for item in it {
-            encoder.flush()?;
-            idx = encoder.total_out();
-            filter.insert(&item.key);
-            // we use ser_length because I don't know how to get compressed length
-            let ser_length = bincode_opts.serialized_size(&item)?;
-            // finish out the page
-            if idx + ser_length >= config.block_size - 20 {
-                // finish out the encoder for each page so each page can be independently decoded.
-                let mut writer = encoder.finish()?;
-                // The wirter writes the bytes 0x3 and 0x0 to mark the end of the stream
-                idx += 2;
-                if idx > config.block_size - 1 {
-                    error!("Wrote more than a page of bytes {}", idx);
-                    debug_assert!(idx <= config.block_size);
+        return Run::group_versions(it).flat_map(move |mut versions| {
+            // range deletes act as a delete of each key they cover, they are kept as ranges
+            // so these are dropped again below
+            let covering: Vec<u64> = range_tombstones
+                .iter()
+                .filter(|t| t.covers(&versions[0].key))
+                .map(|t| t.seq)
+                .collect();
+            if !covering.is_empty() {
+                let key = versions[0].key.clone();
+                for seq in &covering {
+                    versions.push(Item::new(key.clone(), *seq, Value::Delete));
                 }
+                versions.sort_by(|i, j| j.seq.cmp(&i.seq));
+            }
+            // an expired put with nothing below it hides nothing
+            while versions
+                .last()
+                .map_or(false, |i| i.value.is_expired(now) && droppable(i))
+            {
+                versions.pop();
+            }
 
-                // pad out the rest of the page
-                let zeros = vec![4u8; (config.block_size - idx) as usize];
-                let padding_byte_len = writer.write(&zeros)? as u64;
-
-                debug!(
-                    "Writing page with {} bytes with {} bytes of padding. with min {:?} max {:?}",
-                    idx,
-                    config.block_size - idx,
-                    &min_val,
-                    &max_val
-                );
-                idx = idx + padding_byte_len;
-
-                if idx != config.block_size {
-                    warn!("idx is not page size {}", idx);
-                    debug_assert!(idx == config.block_size);
+            let mut kept = vec![];
+            // versions between two snapshots, only the newest of them is ever read directly
+            let mut stripe: Vec<Item<K>> = vec![];
+            for item in versions {
+                if let Some(newer) = stripe.last() {
+                    // a snapshot between this version and the newer one reads this version
+                    let idx = snapshots.partition_point(|s| *s < item.seq);
+                    if idx < snapshots.len() && snapshots[idx] < newer.seq {
+                        let older = std::mem::take(&mut stripe);
+                        kept.extend(Run::fold_stripe(older, operator.as_deref(), false, now));
+                    }
                 }
-                // open up a new encoder for the next page
-                writer.flush()?;
-                encoder = DeflateEncoder::new(writer, Compression::default());
-                idx = 0;
-                fence_pointers.push(FencePointer::new(min_val, max_val));
-
-                // start the next page.
-                // if there is a bug related to min/max values if there is one value on a page, this is why
-                num_pages += 1;
-                min_val = item.key;
-                bincode_opts.serialize_into(&mut encoder, &item)?;
-            } else {
-                bincode_opts.serialize_into(&mut encoder, &item)?;
-                max_val = item.key;
+                stripe.push(item);
             }
-            if !(idx < config.block_size as u64) {
-                error!(
-                    "idx too big! idx: {}, encoder total: {} ",
-                    idx,
-                    encoder.total_out()
-                );
-                debug_assert!(idx < config.block_size);
+            kept.extend(Run::fold_stripe(stripe, operator.as_deref(), bottom, now));
+            if !covering.is_empty() {
+                kept.retain(|item| !(item.value == Value::Delete && covering.contains(&item.seq)));
             }
-        }
+            // nor does a delete, the versions it shadowed are already gone. One below merge
+            // operands is kept as the value they were folded onto.
+            loop {
+                let n = kept.len();
+                let below_operand = n > 1 && matches!(kept[n - 2].value, Value::Merge(_));
+                match kept.last() {
+                    Some(i) if i.value == Value::Delete && droppable(i) && !below_operand => {
+                        kept.pop();
+                    }
+                    _ => break,
+                }
+            }
+            return kept;
+        });
+    }
 
-        //close out the last page
-        // finish the page
-        let mut writer = encoder.finish()?;
-        idx += 2;
-        let zeros = vec![4u8; (config.block_size - idx) as usize];
-        let padding_byte_len = writer.write(&zeros)? as u64;
-        debug!(
-            "Writing page with {} bytes with {} bytes of padding. with min {:?} max {:?}",
-            idx,
-            config.block_size - idx,
-            min_val,
-            max_val
-        );
-        idx = idx + padding_byte_len;
-        debug_assert!(idx == config.block_size as u64);
-        fence_pointers.push(FencePointer::new(min_val, max_val));
+    // Apply `filter` to every put and merge operand. A removed put becomes a delete so the
+    // versions below it stay hidden, a removed operand is dropped.
+    fn filter_items<I>(it: I, filter: Arc<dyn CompactionFilter>) -> impl Iterator<Item = Item<K>>
+    where
+        I: Iterator<Item = Item<K>>,
+    {
+        return it.filter_map(move |mut item| {
+            let decision = match &item.value {
+                Value::Put(value) | Value::Expiring { value, .. } | Value::Merge(value) => {
+                    filter.filter(&item.key, value)
+                }
+                _ => return Some(item),
+            };
+            trace!("Compaction filter {:?} for {:?}", decision, &item.key);
+            match (decision, &mut item.value) {
+                (FilterDecision::Keep, _) => {}
+                (FilterDecision::Remove, Value::Merge(_)) => return None,
+                (FilterDecision::Remove, value) => *value = Value::Delete,
+                (FilterDecision::ChangeValue(new), Value::Put(value))
+                | (FilterDecision::ChangeValue(new), Value::Expiring { value, .. })
+                | (FilterDecision::ChangeValue(new), Value::Merge(value)) => *value = new,
+                (FilterDecision::ChangeValue(_), _) => {}
+            }
+            return Some(item);
+        });
+    }
 
-        // The metadata struct is not compressed
-        if fence_pointers.len() != num_pages {
-            error!(
-                "num fence pointers ({}) !- num pages ({})",
-                fence_pointers.len(),
-                num_pages
-            );
-            debug_assert!(fence_pointers.len() == num_pages);
+    // Reduce versions of a key which are read together, newest first, to as few as possible
+    fn fold_stripe(
+        mut stripe: Vec<Item<K>>,
+        operator: Option<&dyn MergeOperator>,
+        nothing_below: bool,
+        now: u64,
+    ) -> Vec<Item<K>> {
+        let base = stripe
+            .iter()
+            .position(|item| !matches!(item.value, Value::Merge(_)));
+        if let Some(idx) = base {
+            // nothing below a put or delete is read
+            stripe.truncate(idx + 1);
         }
-
-        let run_bytes = fence_pointers.len() * config.block_size as usize
-            + filter.size_in_bytes()
-            + fence_pointers.len() * fence_pointers[0].size_in_bytes();
-
-        info!(
-            "Creating run {:?} metadata with {} pages total size in bytes {}",
-            path, num_pages, run_bytes
-        );
-        let run = Run {
-            num_blocks: fence_pointers.len(),
-            level: level,
-            bloom_filter: filter,
-            fence_pointers: fence_pointers,
-            file_name: path.clone(),
-            block_size: config.block_size,
-            size_in_bytes: run_bytes,
-            num_elements: num_elements,
+        for item in stripe.iter_mut() {
+            if item.value.is_expired(now) {
+                item.value = Value::Delete;
+            }
+        }
+        // folding into a put that will expire would make the result outlive it
+        let foldable = match base {
+            Some(idx) => !matches!(stripe[idx].value, Value::Expiring { .. }),
+            None => nothing_below,
         };
+        if stripe.len() <= 1 || operator.is_none() || !foldable {
+            return stripe;
+        }
+        let seq = stripe[0].seq;
+        let key = stripe[0].key.clone();
+        let value = resolve(stripe.into_iter().map(|item| item.value), operator);
+        let value = match value {
+            Some(value) => Value::Put(value),
+            None => Value::Delete,
+        };
+        return vec![Item::new(key, seq, value)];
+    }
 
-        let ser_meta = bincode::serialize(&run)?;
-        let ser_meta_length = writer.write(&ser_meta)?;
-        let ser_ser_meta_length = bincode::serialize(&ser_meta_length)?;
-        writer.write(&ser_ser_meta_length)?;
-        writer.flush()?;
-        return Ok(run);
+    /// The value of each key a read at `seq` sees, from items sorted as produced by
+    /// `merge_iterators`. Merge operands are folded with `operator`, keys deleted or covered by
+    /// one of `range_tombstones` are left out.
+    pub fn visible_at<I>(
+        it: I,
+        seq: u64,
+        operator: Option<Arc<dyn MergeOperator>>,
+        range_tombstones: Vec<RangeTombstone<K>>,
+    ) -> impl Iterator<Item = (K, Vec<u8>)>
+    where
+        I: Iterator<Item = Item<K>>,
+    {
+        return Run::group_versions(it.filter(move |item| item.seq <= seq)).filter_map(
+            move |versions| {
+                let key = versions[0].key.clone();
+                let deleted_at = RangeTombstone::newest_covering(&range_tombstones, &key, seq);
+                let value = resolve(
+                    versions
+                        .into_iter()
+                        .take_while(|item| deleted_at.map_or(true, |d| item.seq > d))
+                        .map(|item| item.value),
+                    operator.as_deref(),
+                );
+                return value.map(|value| (key, value));
+            },
+        );
     }
 
-    /// Given two runs, return an iterator over their merged items
-    /// If keys exist in both left and right, then the key is disgarded from the right iterator
-    /// i.e left MUST be the newer run and right must be the older run.
-    pub fn runs_to_iterator(left: Arc<Run>, right: Arc<Run>) -> impl Iterator<Item = Item<i32>> {
-        return left
-            .into_iter()
-            .merge_join_by(right.into_iter(), |i, j| i.key.cmp(&j.key))
-            .map(|either| match either {
-                Left(x) => x,
-                Right(x) => x,
-                Both(x, _) => x,
-            });
+    // Collect the versions of each key, newest first, from items sorted as produced by
+    // `merge_iterators`
+    fn group_versions<I>(it: I) -> impl Iterator<Item = Vec<Item<K>>>
+    where
+        I: Iterator<Item = Item<K>>,
+    {
+        let mut it = it.peekable();
+        return std::iter::from_fn(move || {
+            let mut versions = vec![it.next()?];
+            while let Some(item) = it.next_if(|item| item.key == versions[0].key) {
+                versions.push(item);
+            }
+            return Some(versions);
+        });
     }
 
     /// Merge a memory map into an existing level 1 run
     /// without consuming the memory map, since we want to keep reading from it while doing this operation
     pub fn merge_memory_map_into_run(
-        map: Arc<SkipMap<i32, Option<Vec<u8>>>>,
-        run: Arc<Run>,
+        map: Arc<MemoryMap<K>>,
+        run: Arc<Run<K>>,
         config: &rust_store::Config,
-    ) -> Result<Run, RunError> {
+    ) -> Result<Run<K>, RunError> {
         debug_assert!(run.level == 1);
-        let it = map
+        let map_items = map
             .iter()
-            .merge_join_by(run.into_iter(), |i, j| {
-                let jkey = j.key;
-                i.key().cmp(&jkey)
-            })
-            .map(|either| match either {
-                Left(x) => Item::new(x.key().clone(), x.value().clone()),
-                Right(x) => x,
-                Both(x, _) => Item::new(x.key().clone(), x.value().clone()),
-            });
+            .map(|x| Item::new(x.key().key.clone(), x.key().seq, x.value().clone()));
+        let iterators: Vec<Box<dyn Iterator<Item = Item<K>> + '_>> =
+            vec![Box::new(map_items), Box::new(run.into_iter())];
+        let it = Run::retain_versions(Run::merge_iterators(iterators), vec![]);
         info!("Constructed iterator");
         // TODO figure out how to get a better estimate of elements
         // Since this implemented as an iterator, we would need to consume it to get the count,
         // which defeats the point of having an iterator.
         let num_elements = map.len() + run.num_elements;
-        return Run::run_from_iterator(it, config, 1, num_elements);
+        return Run::run_from_iterator(
+            &mut it.peekable(),
+            &mut run.range_tombstones.clone(),
+            config,
+            1,
+            num_elements,
+            run.flushes + 1,
+            None,
+            IoPriority::High,
+        );
     }
 
-    pub fn load(path: String) -> Result<Run, RunError> {
-        // // TODO figure out config and root file directory stuff
-        // let mut f = File::open(path).await?;
-        // let _ = f.seek(SeekFrom::End(-8)).await?;
-        // let meta_size = f.read_u64().await?;
-        //
-        // let mut meta_buf = Box::new(vec![0u8; meta_size as usize]);
-        // f.read_exact(&mut **meta_buf);
-        // let mut deserializer = Deserializer::from_slice(&meta_buf);
-        // let run = serde::de::Deserialize::deserialize(&mut deserializer)?;
-        // return Ok(run);
-        unimplemented!();
+    /// Load an existing run from the metadata at the end of its file
+    pub fn load(path: &Path) -> Result<Run<K>, RunError> {
+        debug!("Loading run from {:?}", path);
+        let mut f = File::open(path)?;
+        let _ = f.seek(SeekFrom::End(-8))?;
+        let mut meta_size_buf = [0u8; 8];
+        f.read_exact(&mut meta_size_buf)?;
+        let meta_size: u64 = bincode::deserialize(&meta_size_buf)?;
+
+        let _ = f.seek(SeekFrom::End(-8 - meta_size as i64))?;
+        let mut meta_buf = vec![0u8; meta_size as usize];
+        f.read_exact(&mut meta_buf)?;
+        let comparator: String = bincode::deserialize(&meta_buf)?;
+        if comparator != K::comparator_name() {
+            return Err(RunError::ComparatorMismatch {
+                expected: K::comparator_name().to_string(),
+                found: comparator,
+            });
+        }
+        let mut run: Run<K> = bincode::deserialize(&meta_buf)?;
+        // the directory may have moved since the run was written
+        run.file_name = PathBuf::from(path);
+        return Ok(run);
     }
 
+    /// Merge `runs` into a sorted run at `level`, keeping the versions `snapshots` (ascending
+    /// sequence numbers) read. Set `bottom` if no run older than `runs` holds data for their
+    /// keys, range deletes are then dropped unless a snapshot still reads below them.
+    ///
+    /// The sorted run is written as files of about `config.max_file_size` bytes with disjoint
+    /// key ranges, which join `sorted_run` if it is given. Nothing is written if every version
+    /// was dropped. What was read, written and dropped is added to `stats`.
+    ///
+    /// A merge of more than a file's worth of blocks is split into up to
+    /// `config.max_subcompactions` key ranges at the fence pointers of `runs`, which are merged
+    /// in parallel.
     pub fn new_from_merge(
-        runs: &Vec<Arc<Run>>,
+        runs: &Vec<Arc<Run<K>>>,
         config: &rust_store::Config,
         level: usize,
-    ) -> Result<Run, RunError> {
+        snapshots: Vec<u64>,
+        bottom: bool,
+        sorted_run: Option<String>,
+        stats: &mut CompactionStats,
+    ) -> Result<Vec<Run<K>>, RunError> {
         info!(
             "Merging {} runs into a new level {} run",
             runs.len(),
             &level
         );
-        // right now dealing with returning the run and changing the level is a pain
-        // The caller should check that there are > 1 runs
-        debug_assert!(runs.len() > 1);
+        // a single run is rewritten too, e.g. when leveling moves it down to an empty level
+        debug_assert!(!runs.is_empty());
         // approx and overestimated as we may double count
-        let mut num_elements =
-            runs[runs.len() - 1].num_elements + runs[runs.len() - 2].num_elements;
-
-        let mut iterators: Vec<Box<dyn Iterator<Item = Item<i32>>>> = vec![Box::new(
-            Run::runs_to_iterator(runs[runs.len() - 1].clone(), runs[runs.len() - 2].clone()),
-        )];
-        for run in runs[0..runs.len() - 3].into_iter().rev() {
-            let next_iter = Box::new(
-                iterators
-                    .pop()
-                    .unwrap()
-                    .merge_join_by(run.into_iter(), |i, j| i.key.cmp(&j.key))
-                    .map(|either| match either {
-                        Left(x) => x,
-                        Right(x) => x,
-                        Both(x, _) => x,
-                    }),
-            );
-            iterators.push(next_iter);
-            num_elements += run.num_elements;
+        let num_elements: usize = runs.iter().map(|run| run.num_elements).sum();
+        let flushes: u64 = runs.iter().map(|run| run.flushes).sum();
+        let num_blocks: usize = runs.iter().map(|run| run.num_blocks).sum();
+        let max_blocks = max(config.max_file_size / config.block_size, 1) as usize;
+        let split = FileSplit {
+            sorted_run: sorted_run.unwrap_or_else(unique_suffix),
+            max_blocks: max_blocks,
+            elements_per_file: num_elements * max_blocks / max(num_blocks, 1) + 1,
+        };
+
+        // with nothing below them, range deletes only matter to snapshots older than them
+        let oldest_snapshot = snapshots.first().cloned();
+        stats.range_tombstones_dropped += runs
+            .iter()
+            .flat_map(|run| run.range_tombstones.iter())
+            .filter(|t| bottom && oldest_snapshot.map_or(true, |s| s >= t.seq))
+            .count() as u64;
+
+        // every subcompaction should write at least a file
+        let num_subcompactions = min(config.max_subcompactions, max(num_blocks / max_blocks, 1));
+        let boundaries = Run::split_keys(runs, num_subcompactions);
+        let n = boundaries.len() + 1;
+        let subcompactions: Vec<Subcompaction<K>> = (0..n)
+            .map(|i| Subcompaction {
+                start: match i {
+                    0 => Bound::Unbounded,
+                    _ => Bound::Included(boundaries[i - 1].clone()),
+                },
+                end: match boundaries.get(i) {
+                    Some(key) => Bound::Excluded(key.clone()),
+                    None => Bound::Unbounded,
+                },
+                num_elements: num_elements * (i + 1) / n - num_elements * i / n,
+                flushes: flushes * (i as u64 + 1) / n as u64 - flushes * i as u64 / n as u64,
+            })
+            .collect();
+        if n > 1 {
+            info!("Merging level {} run in {} subcompactions", level, n);
+        }
+
+        let merged: Vec<Result<(Vec<Run<K>>, CompactionStats), RunError>> = subcompactions
+            .into_par_iter()
+            .map(|sub| {
+                Run::merge_subcompaction(runs, config, level, &snapshots, bottom, &split, sub)
+            })
+            .collect();
+        let mut files = vec![];
+        for result in merged {
+            let (sub_files, sub_stats) = result?;
+            files.extend(sub_files);
+            *stats += sub_stats;
         }
-        return Run::run_from_iterator(iterators.pop().unwrap(), config, level, num_elements);
+        if files.len() > 1 {
+            info!("Split level {} run into {} files", level, files.len());
+        }
+        stats.compactions += 1;
+        return Ok(files);
     }
 
-    // Returns value if it exists in the run
-    pub fn get_from_run(self: &Self, key: &i32) -> Option<Vec<u8>> {
+    // Up to `n - 1` keys, in ascending order, splitting the blocks of `runs` into `n` parts of
+    // about the same size
+    fn split_keys(runs: &Vec<Arc<Run<K>>>, n: usize) -> Vec<K> {
+        if n <= 1 {
+            return vec![];
+        }
+        let mut keys: Vec<&K> = runs
+            .iter()
+            .flat_map(|run| run.fence_pointers.iter().map(|fp| fp.low()))
+            .collect();
+        keys.sort();
+        keys.dedup();
+        return (1..n)
+            .map(|i| keys[i * keys.len() / n].clone())
+            .dedup()
+            .filter(|key| Some(key) != keys.first().cloned())
+            .collect();
+    }
+
+    // Merge the part of `runs` within `sub`'s key range into files
+    fn merge_subcompaction(
+        runs: &Vec<Arc<Run<K>>>,
+        config: &rust_store::Config,
+        level: usize,
+        snapshots: &Vec<u64>,
+        bottom: bool,
+        split: &FileSplit,
+        sub: Subcompaction<K>,
+    ) -> Result<(Vec<Run<K>>, CompactionStats), RunError> {
+        let iterators: Vec<Box<dyn Iterator<Item = Item<K>>>> = runs
+            .iter()
+            .map(|run| {
+                let it = run.range(sub.start.clone(), sub.end.clone());
+                Box::new(it) as Box<dyn Iterator<Item = Item<K>>>
+            })
+            .collect();
+        // the parts of the range deletes within the key range
+        let range_tombstones: Vec<RangeTombstone<K>> = runs
+            .iter()
+            .flat_map(|run| run.range_tombstones.iter())
+            .filter_map(|t| {
+                let start = match &sub.start {
+                    Bound::Included(key) => max(&t.start, key),
+                    _ => &t.start,
+                };
+                let end = match &sub.end {
+                    Bound::Excluded(key) => min(&t.end, key),
+                    _ => &t.end,
+                };
+                if start >= end {
+                    return None;
+                }
+                return Some(RangeTombstone::new(start.clone(), end.clone(), t.seq));
+            })
+            .collect();
+        let oldest_snapshot = snapshots.first().cloned();
+        let mut kept_tombstones = range_tombstones
+            .iter()
+            .filter(|t| !bottom || oldest_snapshot.map_or(false, |s| s < t.seq))
+            .cloned()
+            .collect::<Vec<RangeTombstone<K>>>();
+        let read = Cell::new(0u64);
+        let deletes_read = Cell::new(0u64);
+        let deletes_written = Cell::new(0u64);
+        let input = Run::merge_iterators(iterators).inspect(|item| {
+            read.set(read.get() + 1);
+            if item.value == Value::Delete {
+                deletes_read.set(deletes_read.get() + 1);
+            }
+        });
+        let input: Box<dyn Iterator<Item = Item<K>> + '_> = match &config.compaction_filter {
+            Some(filter) => Box::new(Run::filter_items(input, filter.clone())),
+            None => Box::new(input),
+        };
+        let mut it = Run::compact_versions(
+            input,
+            snapshots.clone(),
+            config.merge_operator.clone(),
+            bottom,
+            range_tombstones,
+        )
+        .inspect(|item| {
+            if item.value == Value::Delete {
+                deletes_written.set(deletes_written.get() + 1);
+            }
+        })
+        .peekable();
+
+        let mut files = vec![];
+        let mut remaining_elements = sub.num_elements;
+        let mut remaining_flushes = sub.flushes;
+        while it.peek().is_some() || !kept_tombstones.is_empty() {
+            let run = Run::run_from_iterator(
+                &mut it,
+                &mut kept_tombstones,
+                config,
+                level,
+                remaining_elements,
+                remaining_flushes,
+                Some(split),
+                IoPriority::Low,
+            )?;
+            remaining_elements = remaining_elements.saturating_sub(run.num_elements);
+            remaining_flushes -= run.flushes;
+            files.push(run);
+        }
+        let stats = CompactionStats {
+            compactions: 0,
+            entries_read: read.get(),
+            entries_written: files.iter().map(|f| f.num_elements as u64).sum(),
+            // expired puts and folded operands can become deletes too
+            tombstones_dropped: deletes_read.get().saturating_sub(deletes_written.get()),
+            range_tombstones_dropped: 0,
+        };
+        return Ok((files, stats));
+    }
+
+    // Returns the newest value if it exists in the run and was put rather than merged
+    pub fn get_from_run<Q>(self: &Self, key: &Q) -> Option<Vec<u8>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + Hash + Debug + ToOwned<Owned = K> + ?Sized,
+    {
+        return match self.get_version(key, u64::MAX).map(|item| item.value) {
+            Some(Value::Put(value)) => Some(value),
+            Some(Value::Expiring { value, expires_at }) if expires_at > now_millis() => Some(value),
+            _ => None,
+        };
+    }
+
+    /// Returns the newest version of `key` written at or before `seq` if it exists in the run,
+    /// a delete is returned as an item without a value. A range delete covering the key counts
+    /// as a delete.
+    pub fn get_version<Q>(self: &Self, key: &Q, seq: u64) -> Option<Item<K>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + Hash + Debug + ToOwned<Owned = K> + ?Sized,
+    {
+        let item = self.get_point_version(key, seq);
+        return match RangeTombstone::newest_covering(&self.range_tombstones, key, seq) {
+            Some(deleted_at) if item.as_ref().map_or(true, |i| i.seq < deleted_at) => {
+                Some(Item::new(key.to_owned(), deleted_at, Value::Delete))
+            }
+            _ => item,
+        };
+    }
+
+    fn get_point_version<Q>(self: &Self, key: &Q, seq: u64) -> Option<Item<K>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + Hash + Debug + ?Sized,
+    {
+        if self.num_blocks == 0 {
+            return None;
+        }
         if !self.bloom_filter.contains(key) {
             debug!("key {:?} not in bloom filter", key);
             return None;
@@ -536,23 +1076,39 @@ impl Run {
             return None;
         }
 
-        let maybe_val = self.get_from_block(page_idx.unwrap(), key);
-        return match maybe_val {
-            Ok(None) => None,
-            Ok(Some(val)) => Some(val),
-            Err(RunError::DeserializeError(_)) => {
-                // need to figure out handling of deserializing a page and what happens when we run out of data.
-                warn!("Failed to get val from run Deserialize error");
-                None
-            }
-            Err(e) => {
-                warn!("Failed to get val from run {}", e);
-                None
-            }
-        };
+        // the versions of a key can continue onto the following pages
+        let mut page = page_idx.unwrap();
+        while (page as usize) < self.num_blocks && self.fence_pointers[page as usize].in_range(key)
+        {
+            let maybe_val = self.get_from_block(page, key, seq);
+            match maybe_val {
+                Ok(None) => {}
+                Ok(Some(item)) => return Some(item),
+                Err(RunError::DeserializeError(_)) => {
+                    // need to figure out handling of deserializing a page and what happens when we run out of data.
+                    warn!("Failed to get val from run Deserialize error");
+                    return None;
+                }
+                Err(e) => {
+                    warn!("Failed to get val from run {}", e);
+                    return None;
+                }
+            };
+            page += 1;
+        }
+        return None;
     }
 
-    fn get_from_block(self: &Self, page: u64, key: &i32) -> Result<Option<Vec<u8>>, RunError> {
+    fn get_from_block<Q>(
+        self: &Self,
+        page: u64,
+        key: &Q,
+        seq: u64,
+    ) -> Result<Option<Item<K>>, RunError>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
         debug!("get_from_block: opening file {:?}", &self.file_name);
         let mut f = File::open(&self.file_name)?;
         let _ = f.seek(SeekFrom::Start(page * self.block_size))?;
@@ -576,7 +1132,7 @@ impl Run {
 
         loop {
             // let item_length: u64 = bincode_opts.deserialize(&mut decompress_bytes)?;
-            let item: Item<i32> = match bincode_opts.deserialize_from(&mut decompressed_cursor) {
+            let item: Item<K> = match bincode_opts.deserialize_from(&mut decompressed_cursor) {
                 Ok(x) => x,
                 Err(e) => {
                     return match *e {
@@ -587,14 +1143,18 @@ impl Run {
                     }
                 }
             };
-            if item.key == *key {
-                return Ok(item.value);
+            if item.key.borrow() == key && item.seq <= seq {
+                return Ok(Some(item));
             }
         }
     }
 
     /// Given a key, find which page the value is on
-    fn get_page_index(self: &Self, key: &i32) -> Option<u64> {
+    fn get_page_index<Q>(self: &Self, key: &Q) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
         let mut idx = 0;
         for fp in &self.fence_pointers {
             if fp.in_range(key) {
@@ -605,39 +1165,44 @@ impl Run {
         return None;
     }
 
-    /// consume the run object and delete the file
-    pub fn delete(self: Self) -> Result<(), RunError> {
-        remove_file(self.file_name)?;
-        return Ok(());
-    }
-}
-
-/// We only need a reference because we are not iterating over memory in the Run struct
-/// but over the file associated with the run.
-impl IntoIterator for &Run {
-    type Item = Item<i32>;
-    type IntoIter = RunIterator;
-
-    fn into_iter(self) -> Self::IntoIter {
+    /// Iterate over the items of the run starting at the beginning of `block`
+    fn iter_from_block(self: &Self, block: usize) -> RunIterator<K> {
+        if self.num_blocks == 0 {
+            return RunIterator {
+                error: None,
+                reader: None,
+                block_size: self.block_size,
+                blocks_remaining: 0,
+                decompressed_block_cursor: Cursor::new(vec![]),
+                deserializer: bincode::DefaultOptions::new(),
+                key_type: PhantomData,
+            };
+        }
         let f = File::open(&self.file_name);
-        debug!("Into iter opening {:?}", &self.file_name);
+        debug!("Into iter opening {:?} at block {}", &self.file_name, block);
 
-        if f.is_err() {
-            let err = f.err();
+        if f.is_err() || block >= self.num_blocks {
+            let err = match f {
+                Err(e) => e,
+                Ok(_) => io::Error::new(io::ErrorKind::UnexpectedEof, "block past end of run"),
+            };
             warn!(
                 "IO Error for {:?} during run into_iter {:?}",
                 &self.file_name, err
             );
             return RunIterator {
-                error: Some(RunError::IoError(err.unwrap())),
+                error: Some(RunError::IoError(err)),
                 reader: None,
                 block_size: self.block_size,
+                blocks_remaining: 0,
                 /// only need this cursor since now it owns the block
                 decompressed_block_cursor: Cursor::new(vec![0u8]),
                 deserializer: bincode::DefaultOptions::new(),
+                key_type: PhantomData,
             };
         } else {
             let mut reader = BufReader::new(f.unwrap());
+            let seek_res = reader.seek(SeekFrom::Start(block as u64 * self.block_size));
 
             let mut block_buf = vec![0u8; self.block_size as usize];
             let bincode_opts = bincode::DefaultOptions::new();
@@ -645,7 +1210,7 @@ impl IntoIterator for &Run {
 
             let mut decompressed: Vec<u8> = Vec::new();
 
-            let read_res = reader.read_exact(&mut block_buf);
+            let read_res = seek_res.and_then(|_| reader.read_exact(&mut block_buf));
 
             let mut deflater = DeflateDecoder::new(Cursor::new(block_buf));
             let _ = deflater.read_to_end(&mut decompressed).unwrap();
@@ -656,9 +1221,11 @@ impl IntoIterator for &Run {
                     error: Some(RunError::IoError(read_res.err().unwrap())),
                     reader: Some(reader),
                     block_size: self.block_size,
+                    blocks_remaining: 0,
                     /// only need this cursor since now it owns the block
                     decompressed_block_cursor: decompressed_cursor,
                     deserializer: bincode_opts,
+                    key_type: PhantomData,
                 };
             }
 
@@ -666,28 +1233,92 @@ impl IntoIterator for &Run {
                 error: None,
                 reader: Some(reader),
                 block_size: self.block_size,
+                blocks_remaining: self.num_blocks - block - 1,
                 /// only need this cursor since now it owns the block
                 decompressed_block_cursor: decompressed_cursor,
                 deserializer: bincode_opts,
+                key_type: PhantomData,
             };
         }
     }
+
+    /// Iterate over the items of the run with keys in `[start, end]` (as given by the bounds),
+    /// fence pointers are used to skip the blocks outside of the range.
+    pub fn range(self: &Self, start: Bound<K>, end: Bound<K>) -> impl Iterator<Item = Item<K>> {
+        let first_block = self
+            .fence_pointers
+            .iter()
+            .position(|fp| !fp.is_before(start.as_ref()));
+        let it = match first_block {
+            Some(block) if !self.fence_pointers[block].is_after(end.as_ref()) => {
+                Some(self.iter_from_block(block))
+            }
+            _ => None,
+        };
+        return it
+            .into_iter()
+            .flatten()
+            .skip_while(move |item| !(start.as_ref(), Bound::Unbounded).contains(&item.key))
+            .take_while(move |item| (Bound::Unbounded, end.as_ref()).contains(&item.key));
+    }
+
+    /// consume the run object and delete the file
+    pub fn delete(self: Self) -> Result<(), RunError> {
+        remove_file(self.file_name)?;
+        return Ok(());
+    }
+}
+
+// Run files are named <level>_<creation time in ms>_<counter>.run, the counter keeps
+// runs created in the same millisecond apart.
+fn new_run_path(config: &rust_store::Config, level: usize) -> PathBuf {
+    let file_name = format!("{}_{}.run", level, unique_suffix());
+    let mut path = match &config.directory {
+        None => "".parse().unwrap(),
+        Some(pb) => pb.clone(),
+    };
+    path.push(file_name);
+    return path;
+}
+
+// <creation time in ms>_<counter>, names run files and sorted runs
+fn unique_suffix() -> String {
+    let now = SystemTime::now();
+    let epoch_time = now
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis();
+    let counter = RUN_FILE_COUNTER.fetch_add(1, Ordering::SeqCst);
+    return format!("{}_{}", epoch_time, counter);
+}
+
+/// We only need a reference because we are not iterating over memory in the Run struct
+/// but over the file associated with the run.
+impl<K: Key> IntoIterator for &Run<K> {
+    type Item = Item<K>;
+    type IntoIter = RunIterator<K>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        return self.iter_from_block(0);
+    }
 }
 
 /// As we iterate over the run, we load a block at a time off of disk
-pub struct RunIterator {
+pub struct RunIterator<K: Key> {
     error: Option<RunError>,
     reader: Option<BufReader<File>>,
     decompressed_block_cursor: Cursor<Vec<u8>>,
     block_size: u64,
+    // the metadata trailer follows the last block, so it must not be read as one
+    blocks_remaining: usize,
     deserializer: bincode::DefaultOptions,
+    key_type: PhantomData<K>,
 }
 
-impl Iterator for RunIterator {
-    type Item = Item<i32>;
+impl<K: Key> Iterator for RunIterator<K> {
+    type Item = Item<K>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        debug_assert!(self.reader.is_some());
         if self.error.is_some() {
             warn!(
                 "Tried to get item from RUnIterator in broken state: {}",
@@ -695,8 +1326,12 @@ impl Iterator for RunIterator {
             );
             return None;
         }
+        if self.reader.is_none() {
+            // a run holding only range deletes has no blocks
+            return None;
+        }
 
-        let item_res: Result<Item<i32>, bincode::Error> = self
+        let item_res: Result<Item<K>, bincode::Error> = self
             .deserializer
             .deserialize_from(&mut self.decompressed_block_cursor);
 
@@ -708,6 +1343,10 @@ impl Iterator for RunIterator {
                 Err(e) => {
                     return match *e {
                         ErrorKind::Io(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                            if self.blocks_remaining == 0 {
+                                return None;
+                            }
+                            self.blocks_remaining -= 1;
                             info!("EOF on block in run iterator, opening next block");
                             let mut block_buf = vec![0u8; self.block_size as usize];
                             let reader = self.reader.as_mut();
@@ -766,7 +1405,10 @@ impl Iterator for RunIterator {
 
 #[cfg(test)]
 mod test_run {
-    use crate::run::Run;
+    use crate::compaction::CompactionStats;
+    use crate::key::{InternalKey, MemoryMap, RangeTombstone, Value};
+    use crate::merge_operator::MergeOperator;
+    use crate::run::{Item, Level, Run};
     use crate::Config;
     use crossbeam_skiplist::SkipMap;
     use log::info;
@@ -778,6 +1420,7 @@ mod test_run {
     use test_case::test_case;
     // use test_env_log::test;
     use rand::prelude::SliceRandom;
+    use std::ops::Bound;
     use std::sync::Arc;
     use std::time::Duration;
 
@@ -810,7 +1453,7 @@ mod test_run {
         info!("Running small_run_from_memory_map");
         let map = create_skipmap(1000, &mut rng);
         for i in 0..500 {
-            map.insert(i, Some(vec![0u8, 20u8, 3u8]));
+            map.insert(InternalKey::new(i, 0), Value::Put(vec![0u8, 20u8, 3u8]));
         }
         let run = Run::new_from_skipmap(map, &config).unwrap();
         let page_index = run.get_page_index(&0).unwrap();
@@ -832,7 +1475,7 @@ mod test_run {
 
         let map = create_skipmap(1000, &mut rng);
         for i in 0..250 {
-            map.insert(i, Some(vec![i as u8]));
+            map.insert(InternalKey::new(i, 0), Value::Put(vec![i as u8]));
         }
         let run = Run::new_from_skipmap(map, &config).unwrap();
         for i in 0..250 {
@@ -842,6 +1485,64 @@ mod test_run {
         run.delete().unwrap();
     }
 
+    #[test]
+    fn small_run_load() {
+        let _ = env_logger::try_init();
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        let map = Arc::new(SkipMap::new());
+        for i in 0..2500 {
+            map.insert(InternalKey::new(i, 0), Value::Put(vec![i as u8]));
+        }
+        let run = Run::new_from_skipmap(map, &config).unwrap();
+        let loaded_run: Run<i32> = Run::load(&run.file_name).unwrap();
+        assert_eq!(loaded_run.num_blocks, run.num_blocks);
+        assert_eq!(loaded_run.num_elements, run.num_elements);
+        for i in 0..2500 {
+            assert_eq!(loaded_run.get_from_run(&i), Some(vec![i as u8]));
+        }
+        run.delete().unwrap();
+    }
+
+    #[test]
+    fn small_run_range() {
+        let _ = env_logger::try_init();
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        let map = Arc::new(SkipMap::new());
+        for i in -1000..2500 {
+            map.insert(InternalKey::new(i, 0), Value::Put(vec![i as u8]));
+        }
+        let run = Run::new_from_skipmap(map, &config).unwrap();
+        assert!(run.num_blocks > 2);
+
+        let keys: Vec<i32> = run
+            .range(Bound::Included(-10), Bound::Excluded(10))
+            .map(|item| item.key())
+            .collect();
+        assert_eq!(keys, (-10..10).collect::<Vec<i32>>());
+
+        // starts in the last block and runs off the end of the run
+        let keys: Vec<i32> = run
+            .range(Bound::Excluded(2490), Bound::Unbounded)
+            .map(|item| item.key())
+            .collect();
+        assert_eq!(keys, (2491..2500).collect::<Vec<i32>>());
+
+        assert_eq!(
+            run.range(Bound::Included(3000), Bound::Unbounded).count(),
+            0
+        );
+        assert_eq!(
+            run.range(Bound::Unbounded, Bound::Excluded(-1000)).count(),
+            0
+        );
+        assert_eq!(run.range(Bound::Unbounded, Bound::Unbounded).count(), 3500);
+        run.delete().unwrap();
+    }
+
     #[test]
     fn small_run_get_no_result() {
         let _ = env_logger::try_init();
@@ -851,7 +1552,7 @@ mod test_run {
         config.set_directory(dir.path());
         let map = Arc::new(SkipMap::new());
         for i in 0..250 {
-            map.insert(i, Some(vec![i as u8]));
+            map.insert(InternalKey::new(i, 0), Value::Put(vec![i as u8]));
         }
         let run = Run::new_from_skipmap(map, &config).unwrap();
         let val = run.get_from_run(&42000);
@@ -868,16 +1569,16 @@ mod test_run {
         config.set_directory(dir.path());
         let map = Arc::new(SkipMap::new());
         for i in 0..250 {
-            map.insert(i, Some(vec![i as u8]));
+            map.insert(InternalKey::new(i, 0), Value::Put(vec![i as u8]));
         }
         let run = Run::new_from_skipmap(map, &config).unwrap();
-        let val = run.get_from_block(0, &42000);
-        assert_eq!(val.unwrap(), None);
+        let val = run.get_from_block(0, &42000, u64::MAX);
+        assert!(val.unwrap().is_none());
 
         for i in 0..250 {
             let page_index = run.get_page_index(&i).unwrap();
-            let val = run.get_from_block(page_index, &i);
-            assert_eq!(val.unwrap(), Some(vec![i as u8]));
+            let val = run.get_from_block(page_index, &i, u64::MAX);
+            assert_eq!(val.unwrap().unwrap().value(), Value::Put(vec![i as u8]));
         }
 
         run.delete().unwrap();
@@ -898,7 +1599,7 @@ mod test_run {
 
         let map = create_skipmap(2000, &mut rng);
         for i in 0..run_size {
-            map.insert(i, Some(vec![i as u8]));
+            map.insert(InternalKey::new(i, 0), Value::Put(vec![i as u8]));
         }
         let run = Arc::new(Run::new_from_skipmap(map, &config).unwrap());
 
@@ -917,7 +1618,10 @@ mod test_run {
         new_keys.shuffle(&mut rng);
 
         for i in 0..new_keys.len() {
-            new_map.insert(new_keys[i], new_vals[i].clone());
+            new_map.insert(
+                InternalKey::new(new_keys[i], 1),
+                Value::Put(new_vals[i].clone().unwrap()),
+            );
         }
 
         let new_run =
@@ -968,7 +1672,7 @@ mod test_run {
     }
 
     // Size in bytes approx
-    fn create_skipmap<T: Rng>(size: u64, mut rng: &mut T) -> Arc<SkipMap<i32, Option<Vec<u8>>>> {
+    fn create_skipmap<T: Rng>(size: u64, mut rng: &mut T) -> Arc<MemoryMap<i32>> {
         let mut curr_size: u64 = 0;
         let map = Arc::new(SkipMap::new());
 
@@ -976,11 +1680,424 @@ mod test_run {
             let rand_key: i32 = rng.gen_range(-50000..50000);
             let rand_val = gen_rand_bytes(&mut rng);
             curr_size += 4 + rand_val.len() as u64;
-            map.insert(rand_key, Some(rand_val));
+            map.insert(InternalKey::new(rand_key, 0), Value::Put(rand_val));
         }
         return map;
     }
 
+    #[test]
+    fn level_get_picks_highest_seq_regardless_of_run_order() {
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        let newer_map = Arc::new(SkipMap::new());
+        newer_map.insert(InternalKey::new(42, 5), Value::Put(vec![5u8]));
+        newer_map.insert(InternalKey::new(43, 4), Value::Delete);
+        let older_map = Arc::new(SkipMap::new());
+        older_map.insert(InternalKey::new(42, 2), Value::Put(vec![2u8]));
+        older_map.insert(InternalKey::new(43, 1), Value::Put(vec![1u8]));
+        let newer_run: Arc<Run<i32>> = Arc::new(Run::new_from_skipmap(newer_map, &config).unwrap());
+        let older_run = Arc::new(Run::new_from_skipmap(older_map, &config).unwrap());
+
+        // the newer run comes first, the opposite of the order runs are pushed in
+        let level = Level {
+            num_runs: 2,
+            runs: vec![newer_run, older_run],
+        };
+        let item = level.get_from_level(&42, u64::MAX, None).unwrap();
+        assert_eq!((item.seq(), item.value()), (5, Value::Put(vec![5u8])));
+        let item = level.get_from_level(&42, 3, None).unwrap();
+        assert_eq!((item.seq(), item.value()), (2, Value::Put(vec![2u8])));
+        let item = level.get_from_level(&43, u64::MAX, None).unwrap();
+        assert_eq!(item.value(), Value::Delete);
+        assert!(level.get_from_level(&42, 1, None).is_none());
+
+        // a version found elsewhere wins when it is newer
+        let found = Some(Item::new(42, 9, Value::Put(vec![9u8])));
+        let item = level.get_from_level(&42, u64::MAX, found).unwrap();
+        assert_eq!(item.seq(), 9);
+    }
+
+    struct Concat;
+
+    impl MergeOperator for Concat {
+        fn merge(&self, existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Vec<u8> {
+            let mut value = existing.unwrap_or(&[]).to_vec();
+            for operand in operands {
+                value.extend(operand);
+            }
+            return value;
+        }
+    }
+
+    #[test_case(false ; "operands above older runs")]
+    #[test_case(true ; "bottom level")]
+    fn compact_versions_folds_merge_operands(bottom: bool) {
+        let items = vec![
+            Item::new(1, 9, Value::Merge(vec![9u8])),
+            Item::new(1, 8, Value::Merge(vec![8u8])),
+            Item::new(1, 6, Value::Merge(vec![6u8])),
+            Item::new(1, 4, Value::Put(vec![4u8])),
+            Item::new(1, 2, Value::Merge(vec![2u8])),
+            Item::new(2, 7, Value::Merge(vec![7u8])),
+            Item::new(2, 5, Value::Merge(vec![5u8])),
+            Item::new(3, 3, Value::Merge(vec![3u8])),
+            Item::new(3, 1, Value::Delete),
+        ];
+        let operator: Arc<dyn MergeOperator> = Arc::new(Concat);
+        let kept: Vec<(i32, u64, Value)> =
+            Run::compact_versions(items.into_iter(), vec![7], Some(operator), bottom, vec![])
+                .map(|i| (i.key, i.seq, i.value))
+                .collect();
+
+        // snapshot 7 reads key 1 at seq 6, so the operands above it are folded separately
+        let mut expected = vec![
+            (1, 9, Value::Merge(vec![9u8])),
+            (1, 8, Value::Merge(vec![8u8])),
+            (1, 6, Value::Put(vec![4u8, 6u8])),
+        ];
+        if bottom {
+            expected.push((2, 7, Value::Put(vec![5u8, 7u8])));
+        } else {
+            expected.push((2, 7, Value::Merge(vec![7u8])));
+            expected.push((2, 5, Value::Merge(vec![5u8])));
+        }
+        expected.push((3, 3, Value::Put(vec![3u8])));
+        assert_eq!(kept, expected);
+    }
+
+    #[test]
+    fn visible_at_folds_merge_operands() {
+        let items = vec![
+            Item::new(1, 5, Value::Merge(vec![5u8])),
+            Item::new(1, 3, Value::Merge(vec![3u8])),
+            Item::new(1, 2, Value::Put(vec![2u8])),
+            Item::new(2, 4, Value::Delete),
+            Item::new(2, 1, Value::Put(vec![1u8])),
+            Item::new(3, 6, Value::Merge(vec![6u8])),
+        ];
+        let operator: Arc<dyn MergeOperator> = Arc::new(Concat);
+        let pairs: Vec<(i32, Vec<u8>)> =
+            Run::visible_at(items.into_iter(), 4, Some(operator), vec![]).collect();
+        assert_eq!(pairs, vec![(1, vec![2u8, 3u8])]);
+    }
+
+    #[test]
+    fn compact_versions_drops_range_deleted_versions() {
+        let items = || {
+            vec![
+                Item::new(2, 6, Value::Put(vec![6u8])),
+                Item::new(2, 4, Value::Put(vec![4u8])),
+                Item::new(2, 2, Value::Put(vec![2u8])),
+                Item::new(3, 1, Value::Put(vec![1u8])),
+                Item::new(4, 1, Value::Put(vec![1u8])),
+            ]
+        };
+        let tombstones = vec![RangeTombstone::new(2, 4, 5)];
+        let compact = |snapshots| -> Vec<(i32, u64)> {
+            Run::compact_versions(
+                items().into_iter(),
+                snapshots,
+                None,
+                false,
+                tombstones.clone(),
+            )
+            .map(|i| (i.key, i.seq))
+            .collect()
+        };
+        assert_eq!(compact(vec![]), vec![(2, 6), (4, 1)]);
+        // snapshot 3 reads below the range delete
+        assert_eq!(compact(vec![3]), vec![(2, 6), (2, 2), (3, 1), (4, 1)]);
+    }
+
+    #[test]
+    fn compact_versions_drops_expired_puts() {
+        let items = || {
+            vec![
+                Item::new(1, 3, Value::Merge(vec![3u8])),
+                Item::new(
+                    1,
+                    2,
+                    Value::Expiring {
+                        value: vec![2u8],
+                        expires_at: 1,
+                    },
+                ),
+                Item::new(1, 1, Value::Put(vec![1u8])),
+                Item::new(
+                    2,
+                    1,
+                    Value::Expiring {
+                        value: vec![1u8],
+                        expires_at: u64::MAX,
+                    },
+                ),
+                Item::new(
+                    3,
+                    1,
+                    Value::Expiring {
+                        value: vec![1u8],
+                        expires_at: 1,
+                    },
+                ),
+            ]
+        };
+        let compact = |bottom| -> Vec<(i32, u64, Value)> {
+            Run::compact_versions(items().into_iter(), vec![], None, bottom, vec![])
+                .map(|i| (i.key, i.seq, i.value))
+                .collect()
+        };
+        let live = (
+            2,
+            1,
+            Value::Expiring {
+                value: vec![1u8],
+                expires_at: u64::MAX,
+            },
+        );
+        // an expired put still hides what is below it, unless nothing is
+        assert_eq!(
+            compact(false),
+            vec![
+                (1, 3, Value::Merge(vec![3u8])),
+                (1, 2, Value::Delete),
+                live.clone(),
+                (3, 1, Value::Delete),
+            ]
+        );
+        assert_eq!(
+            compact(true),
+            vec![(1, 3, Value::Merge(vec![3u8])), (1, 2, Value::Delete), live,]
+        );
+    }
+
+    #[test]
+    fn range_tombstones_hide_keys_in_run() {
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        let map = Arc::new(SkipMap::new());
+        map.insert(InternalKey::new(10, 1), Value::Put(vec![1u8]));
+        map.insert(InternalKey::new(12, 3), Value::Put(vec![3u8]));
+        map.insert(InternalKey::new(20, 1), Value::Put(vec![1u8]));
+        let tombstones = vec![RangeTombstone::new(5, 15, 2)];
+        let run: Run<i32> =
+            Run::new_from_skipmap_with_range_tombstones(map, tombstones.clone(), &config).unwrap();
+        let run: Run<i32> = Run::load(&run.file_name).unwrap();
+        assert_eq!(run.max_seq, 3);
+        assert_eq!(run.range_tombstones, tombstones);
+
+        assert_eq!(run.get_from_run(&10), None);
+        assert_eq!(run.get_from_run(&12), Some(vec![3u8]));
+        assert_eq!(run.get_from_run(&20), Some(vec![1u8]));
+        // keys the run never held are deleted too
+        let item = run.get_version(&7, u64::MAX).unwrap();
+        assert_eq!((item.seq(), item.value()), (2, Value::Delete));
+        let item = run.get_version(&10, 1).unwrap();
+        assert_eq!(item.value(), Value::Put(vec![1u8]));
+
+        // a run can hold only range deletes
+        let empty = Arc::new(SkipMap::new());
+        let run: Run<i32> =
+            Run::new_from_skipmap_with_range_tombstones(empty, tombstones, &config).unwrap();
+        let run: Run<i32> = Run::load(&run.file_name).unwrap();
+        assert_eq!(run.num_blocks, 0);
+        assert_eq!(run.into_iter().count(), 0);
+        assert_eq!(run.range(Bound::Unbounded, Bound::Unbounded).count(), 0);
+        assert_eq!(
+            run.get_version(&10, u64::MAX).unwrap().value(),
+            Value::Delete
+        );
+        assert!(run.get_version(&20, u64::MAX).is_none());
+    }
+
+    #[test_case(false, vec![] ; "above other runs")]
+    #[test_case(true, vec![] ; "bottom level")]
+    #[test_case(true, vec![1] ; "bottom level with an older snapshot")]
+    fn range_tombstones_dropped_at_bottom(bottom: bool, snapshots: Vec<u64>) {
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        let older = Arc::new(SkipMap::new());
+        for i in 0..100 {
+            older.insert(InternalKey::new(i, 1), Value::Put(vec![i as u8]));
+        }
+        let newer = Arc::new(SkipMap::new());
+        newer.insert(InternalKey::new(15, 3), Value::Put(vec![0u8]));
+        let tombstones = vec![RangeTombstone::new(10, 20, 2)];
+        let runs: Vec<Arc<Run<i32>>> = vec![
+            Arc::new(Run::new_from_skipmap(older, &config).unwrap()),
+            Arc::new(
+                Run::new_from_skipmap_with_range_tombstones(newer, tombstones, &config).unwrap(),
+            ),
+        ];
+        let keep_all = !snapshots.is_empty();
+        let mut merged = Run::new_from_merge(
+            &runs,
+            &config,
+            2,
+            snapshots,
+            bottom,
+            None,
+            &mut CompactionStats::default(),
+        )
+        .unwrap();
+        assert_eq!(merged.len(), 1);
+        let merged = merged.pop().unwrap();
+
+        assert_eq!(merged.range_tombstones.is_empty(), bottom && !keep_all);
+        let keys: Vec<i32> = merged.into_iter().map(|i| i.key).collect();
+        let expected: Vec<i32> = if keep_all {
+            (0..15).chain(15..16).chain(15..100).collect()
+        } else {
+            (0..10).chain(15..16).chain(20..100).collect()
+        };
+        assert_eq!(keys, expected);
+        assert_eq!(merged.get_from_run(&12), None);
+        assert_eq!(merged.get_from_run(&15), Some(vec![0u8]));
+    }
+
+    #[test_case(vec![], 5 ; "no snapshots")]
+    #[test_case(vec![2], 16 ; "snapshot older than the deletes")]
+    #[test_case(vec![4], 5 ; "snapshot newer than the deletes")]
+    fn bottom_merge_drops_deletes(snapshots: Vec<u64>, written: u64) {
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        let puts = Arc::new(SkipMap::new());
+        for key in 0..10 {
+            puts.insert(InternalKey::new(key, 1), Value::Put(vec![key as u8]));
+        }
+        let deletes = Arc::new(SkipMap::new());
+        for key in (0..5).chain(20..21) {
+            deletes.insert(InternalKey::new(key, 3), Value::Delete);
+        }
+        let runs: Vec<Arc<Run<i32>>> = vec![
+            Arc::new(Run::new_from_skipmap(puts, &config).unwrap()),
+            Arc::new(Run::new_from_skipmap(deletes, &config).unwrap()),
+        ];
+        let mut stats = CompactionStats::default();
+        let merged =
+            Run::new_from_merge(&runs, &config, 2, snapshots, true, None, &mut stats).unwrap();
+
+        let items: Vec<Item<i32>> = merged.iter().flat_map(|run| run.into_iter()).collect();
+        assert_eq!(items.len() as u64, written);
+        let dropped = if written == 5 { 6 } else { 0 };
+        let expected = CompactionStats {
+            compactions: 1,
+            entries_read: 16,
+            entries_written: written,
+            tombstones_dropped: dropped,
+            range_tombstones_dropped: 0,
+        };
+        assert_eq!(stats, expected);
+        let level = Level {
+            num_runs: merged.len(),
+            runs: merged.into_iter().map(Arc::new).collect(),
+        };
+        for key in 0..5 {
+            let read_at_2 = level.get_from_level(&key, 2, None).map(|i| i.value());
+            assert_eq!(read_at_2.is_some(), dropped == 0);
+            let latest = level.get_from_level(&key, u64::MAX, None).map(|i| i.seq());
+            assert_eq!(latest, if dropped == 0 { Some(3) } else { None });
+        }
+        assert_eq!(
+            level.get_from_level(&7, u64::MAX, None).unwrap().value(),
+            Value::Put(vec![7u8])
+        );
+    }
+
+    #[test]
+    fn retain_versions_keeps_what_snapshots_read() {
+        let items = vec![
+            Item::new(1, 9, Value::Put(vec![9u8])),
+            Item::new(1, 6, Value::Delete),
+            Item::new(1, 4, Value::Put(vec![4u8])),
+            Item::new(1, 2, Value::Put(vec![2u8])),
+            Item::new(2, 3, Value::Put(vec![3u8])),
+            Item::new(2, 1, Value::Put(vec![1u8])),
+        ];
+        let kept: Vec<(i32, u64)> = Run::retain_versions(items.into_iter(), vec![5, 7])
+            .map(|i| (i.key, i.seq))
+            .collect();
+        // snapshot 5 reads (1, 4), snapshot 7 reads the delete at (1, 6)
+        assert_eq!(kept, vec![(1, 9), (1, 6), (1, 4), (2, 3)]);
+    }
+
+    #[test_case(1 ; "one thread")]
+    #[test_case(4 ; "subcompactions")]
+    fn new_from_merge_splits_into_disjoint_files(max_subcompactions: usize) {
+        let _ = env_logger::try_init();
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        config.set_max_file_size(4 * config.block_size).unwrap();
+        config.set_max_subcompactions(max_subcompactions).unwrap();
+        let mut rng = ChaChaRng::from_seed([42; 32]);
+        let mut runs = vec![];
+        for i in 0..3 {
+            let map = Arc::new(SkipMap::new());
+            for key in 0..3000 {
+                let mut value = vec![i as u8; 100];
+                rng.fill(&mut value[1..]);
+                map.insert(InternalKey::new(key, i + 1), Value::Put(value));
+            }
+            runs.push(Arc::new(Run::new_from_skipmap(map, &config).unwrap()));
+        }
+        let deleted = Arc::new(SkipMap::new());
+        deleted.insert(InternalKey::new(2999, 5), Value::Put(vec![9u8]));
+        let tombstones = vec![RangeTombstone::new(1000, 1500, 4)];
+        runs.push(Arc::new(
+            Run::new_from_skipmap_with_range_tombstones(deleted, tombstones, &config).unwrap(),
+        ));
+
+        let mut stats = CompactionStats::default();
+        let files =
+            Run::new_from_merge(&runs, &config, 2, vec![], false, None, &mut stats).unwrap();
+        assert!(files.len() > 10, "{} files", files.len());
+        assert_eq!(files.iter().map(|f| f.flushes).sum::<u64>(), 4);
+        assert_eq!(Run::split_keys(&runs, 4).len(), 3);
+        assert_eq!(stats.compactions, 1);
+        assert_eq!(stats.entries_read, 9001);
+        assert_eq!(stats.entries_written, 2500);
+        for (i, file) in files.iter().enumerate() {
+            assert!(file.num_blocks <= 4);
+            assert_eq!(file.sorted_run, files[0].sorted_run);
+            for other in files.iter().skip(i + 1) {
+                assert!(!file.overlaps(other));
+            }
+        }
+        // the range delete is cut up between the files without leaving gaps
+        let mut pieces: Vec<&RangeTombstone<i32>> = files
+            .iter()
+            .flat_map(|f| f.range_tombstones.iter())
+            .collect();
+        pieces.sort_by_key(|t| t.start);
+        assert_eq!(pieces.first().unwrap().start, 1000);
+        assert_eq!(pieces.last().unwrap().end, 1500);
+        for pair in pieces.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start);
+        }
+
+        let keys: Vec<i32> = files
+            .iter()
+            .flat_map(|f| f.into_iter().map(|i| i.key))
+            .collect();
+        let expected: Vec<i32> = (0..1000).chain(1500..3000).collect();
+        assert_eq!(keys, expected);
+        let level = Level {
+            num_runs: files.len(),
+            runs: files.into_iter().map(Arc::new).collect(),
+        };
+        assert_eq!(level.num_sorted_runs(), 1);
+        let item = level.get_from_level(&1200, u64::MAX, None).unwrap();
+        assert_eq!(item.value(), Value::Delete);
+        let item = level.get_from_level(&2999, u64::MAX, None).unwrap();
+        assert_eq!(item.value(), Value::Put(vec![9u8]));
+        let item = level.get_from_level(&20, u64::MAX, None).unwrap();
+        assert_eq!(item.seq(), 3);
+    }
+
     #[test_case( 5 ; "one block")]
     #[test_case(3000 ; "several blocks")]
     #[test_case(10000 ; "many blocks")]
@@ -992,7 +2109,7 @@ mod test_run {
         config.set_directory(dir.path());
         let map = Arc::new(SkipMap::new());
         for i in 0..num_items {
-            map.insert(i, Some(vec![i as u8]));
+            map.insert(InternalKey::new(i, 0), Value::Put(vec![i as u8]));
         }
         for x in map.clone().iter() {
             println!("{:?} {:?}", x.key(), x.value());
@@ -1000,7 +2117,7 @@ mod test_run {
         let run = Run::new_from_skipmap(map, &config).unwrap();
         let mut i = 0;
         for key_val in &run {
-            assert_eq!(key_val.value.unwrap()[0], i as u8);
+            assert_eq!(key_val.value, Value::Put(vec![i as u8]));
             assert_eq!(key_val.key, i);
             i += 1;
         }
@@ -1020,10 +2137,10 @@ mod test_run {
         let right_map = Arc::new(SkipMap::new());
 
         for i in 0..left_size {
-            left_map.insert(i, Some(vec![(i % 255) as u8]));
+            left_map.insert(InternalKey::new(i, 0), Value::Put(vec![(i % 255) as u8]));
         }
         for i in left_size..(left_size + right_size) {
-            right_map.insert(i, Some(vec![(i % 255) as u8]));
+            right_map.insert(InternalKey::new(i, 0), Value::Put(vec![(i % 255) as u8]));
         }
 
         let left_run = Arc::new(Run::new_from_skipmap(left_map, &config).unwrap());
@@ -1059,6 +2176,32 @@ mod test_run {
         Arc::try_unwrap(right_run).ok().unwrap().delete().unwrap();
     }
 
+    #[test_case(2 ; "two runs")]
+    #[test_case(3 ; "three runs")]
+    #[test_case(5 ; "five runs")]
+    fn new_from_merge_keeps_keys_of_every_run(num_runs: i32) {
+        let _ = env_logger::try_init();
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+
+        let runs: Vec<Arc<Run<i32>>> = (0..num_runs)
+            .map(|i| {
+                let map = Arc::new(SkipMap::new());
+                map.insert(InternalKey::new(i, i as u64 + 1), Value::Put(vec![i as u8]));
+                Arc::new(Run::new_from_skipmap(map, &config).unwrap())
+            })
+            .collect();
+
+        let mut stats = CompactionStats::default();
+        let merged =
+            Run::new_from_merge(&runs, &config, 2, vec![], false, None, &mut stats).unwrap();
+        assert_eq!(merged.len(), 1);
+        for i in 0..num_runs {
+            assert_eq!(merged[0].get_from_run(&i), Some(vec![i as u8]));
+        }
+    }
+
     #[test_case( 3, 5 ; "3 5 runs")]
     #[test_case( 10, 100 ; "10 100 runs")]
     #[test_case(10, 1000 ; "10 1000 runs")]
@@ -1075,14 +2218,21 @@ mod test_run {
         let mut total_num_pre_merge = 0;
         for i in 0..num_runs {
             let map = create_skipmap(map_size, &mut rng);
-            map.insert(42, Some(vec![i as u8]));
+            map.insert(
+                InternalKey::new(42, i as u64 + 1),
+                Value::Put(vec![i as u8]),
+            );
             runs.push(Arc::new(Run::new_from_skipmap(map, &config).unwrap()));
             for item in runs.last().clone().into_iter() {
                 total_num_pre_merge += 1;
             }
         }
 
-        let merged_run = Run::new_from_merge(&runs, &config, 2).unwrap();
+        let mut stats = CompactionStats::default();
+        let mut merged =
+            Run::new_from_merge(&runs, &config, 2, vec![], false, None, &mut stats).unwrap();
+        let merged_run = merged.pop().unwrap();
+        assert!(merged.is_empty());
         let mut num_els = 0;
         for i in merged_run.into_iter() {
             num_els += 1;
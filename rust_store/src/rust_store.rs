@@ -1,8 +1,26 @@
+use crate::column_family::ColumnFamily;
+use crate::compaction::{check_policy, CompactionReport, CompactionStats};
+use crate::compaction_filter::CompactionFilter;
+use crate::key::Key;
 use crate::lsm::{Lsm, LsmError};
+use crate::merge_operator::MergeOperator;
+use crate::rate_limiter::RateLimiter;
 use crate::run::RunError;
+use crate::snapshot::Snapshot;
+use crate::transaction::{LockTable, Transaction, TransactionMode};
+use crate::wal::SyncPolicy;
+use crate::write_batch::WriteBatch;
+use crate::write_stall::WriteStallStats;
+use log::error;
 use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::ops::RangeBounds;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
 
 const KB: u64 = 1024;
@@ -20,8 +38,10 @@ pub enum RustStoreError {
     NotImplemented,
 }
 
-pub struct RustStore {
-    lsm: Arc<Lsm>,
+/// A key value store, keys can be any type implementing `Key`
+pub struct RustStore<K: Key> {
+    lsm: Arc<Lsm<K>>,
+    locks: Arc<LockTable<K>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -31,7 +51,7 @@ pub enum MemoryMapType {
 
 //TODO make these fields private and also accessible from the rest of the crate.
 /// The options struct for rust_store.
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
     /// Max size in bytes for the in memory map
     pub memory_map_budget: u64,
@@ -47,6 +67,39 @@ pub struct Config {
     pub directory: Option<PathBuf>,
     /// Block size for runs.
     pub block_size: u64,
+    /// Size in bytes compactions cut the runs they write into files at
+    pub max_file_size: u64,
+    /// Most key ranges a compaction is split into and merged in parallel
+    pub max_subcompactions: usize,
+    /// When writes to the write ahead log are synced to disk
+    pub wal_sync_policy: SyncPolicy,
+    /// How long a pessimistic transaction waits for a key lock before giving up
+    pub lock_timeout: Duration,
+    /// Folds the operands written by `RustStore::merge`
+    #[serde(skip)]
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// Keeps, drops or rewrites values as compactions rewrite them
+    #[serde(skip)]
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    /// Memory in a column family's memory maps, as a multiple of `memory_map_budget`, at which
+    /// writes are delayed
+    pub memory_slowdown_ratio: f64,
+    /// Memory in a column family's memory maps, as a multiple of `memory_map_budget`, at which
+    /// writes are blocked until a flush finishes
+    pub memory_stop_ratio: f64,
+    /// Sorted runs in a column family's level 1 at which writes are delayed
+    pub level1_slowdown_runs: usize,
+    /// Sorted runs in a column family's level 1 at which writes are blocked until a compaction
+    /// catches up
+    pub level1_stop_runs: usize,
+    /// How long each write is delayed once a slowdown threshold is reached
+    pub write_slowdown_delay: Duration,
+    /// Limits the bytes per second flushes and compactions write, None for no limit
+    #[serde(skip)]
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// Tuning of each column family other than the default one, by name
+    #[serde(skip)]
+    pub column_families: BTreeMap<String, Config>,
 }
 
 impl Config {
@@ -66,6 +119,19 @@ impl Config {
             z: 10,
             directory: None,
             block_size: 4 * KB,
+            max_file_size: 64 * MB,
+            max_subcompactions: 4,
+            wal_sync_policy: SyncPolicy::None,
+            lock_timeout: Duration::from_secs(1),
+            memory_slowdown_ratio: 1.5,
+            memory_stop_ratio: 2.0,
+            level1_slowdown_runs: 20,
+            level1_stop_runs: 36,
+            write_slowdown_delay: Duration::from_millis(1),
+            merge_operator: None,
+            compaction_filter: None,
+            rate_limiter: None,
+            column_families: BTreeMap::new(),
         };
     }
     /// Sets the directory for data
@@ -83,9 +149,9 @@ impl Config {
     /// # Arguments
     ///
     /// * `budget` - The maximum number of bytes to be used by the in memory store.
-    ///              Currently this is not a hard limit, but a threshold which will trigger the creation
+    ///              This is not a hard limit, but a threshold which will trigger the creation
     ///              of a new run. If there is a high write rate then the budget may be exceeded while
-    ///              creating the new run.
+    ///              creating the new run, until writes are stalled, see `set_memory_map_stall`.
     pub fn set_memory_map_budget(self: &mut Self, budget: u64) -> Result<(), RustStoreError> {
         if budget == 0 {
             return Err(RustStoreError::OptionParsingError(
@@ -122,26 +188,215 @@ impl Config {
         self.block_size = size;
         return Ok(());
     }
+
+    /// Sets the size of the files compactions write.
+    ///
+    /// A compaction writes its output as files of about `size` bytes with disjoint key ranges.
+    /// When a level moves down into a leveled level only the files sharing keys with the moved
+    /// data are rewritten, so smaller files spread the writes of deep levels out more evenly
+    /// and bound the extra disk space a compaction needs, at the cost of more files.
+    pub fn set_max_file_size(self: &mut Self, size: u64) -> Result<(), RustStoreError> {
+        if size < self.block_size {
+            return Err(RustStoreError::OptionParsingError(
+                "max file size cannot be smaller than a block"
+                    .parse()
+                    .unwrap(),
+            ));
+        }
+        self.max_file_size = size;
+        return Ok(());
+    }
+
+    /// Sets how many threads a compaction may use.
+    ///
+    /// A large compaction is split into up to `n` disjoint key ranges, at the fence pointers of
+    /// the runs it merges, which are merged in parallel into their own files. Each range is at
+    /// least a file's worth of data, so smaller compactions use fewer threads.
+    pub fn set_max_subcompactions(self: &mut Self, n: usize) -> Result<(), RustStoreError> {
+        if n == 0 {
+            return Err(RustStoreError::OptionParsingError(
+                "a compaction needs at least one thread".parse().unwrap(),
+            ));
+        }
+        self.max_subcompactions = n;
+        return Ok(());
+    }
+
+    /// Sets the sync policy for the write ahead log.
+    ///
+    /// Every put and delete is appended to the write ahead log in `directory` before it is applied,
+    /// and the log is replayed on startup. With `SyncPolicy::None` writes survive the process
+    /// crashing but not the machine, `SyncPolicy::EveryWrite` and `SyncPolicy::GroupCommit` sync the
+    /// log before a write returns. No log is kept if no directory is set.
+    pub fn set_wal_sync_policy(self: &mut Self, policy: SyncPolicy) {
+        self.wal_sync_policy = policy;
+    }
+
+    /// Sets the shape of the levels.
+    ///
+    /// Each level holds `t` times as much data as the one above it. Every level may hold up to
+    /// `k` runs, except for the largest, which may hold up to `z`. `k = z = 1` is leveling, which
+    /// favours reads, `k = z = t - 1` is tiering, which favours writes, and `k = t - 1, z = 1` is
+    /// lazy leveling, which writes nearly as little as tiering while keeping most of the data in
+    /// a single run.
+    pub fn set_compaction_policy(
+        self: &mut Self,
+        t: u64,
+        k: u64,
+        z: u64,
+    ) -> Result<(), RustStoreError> {
+        check_policy(t, k, z).map_err(RustStoreError::OptionParsingError)?;
+        self.t = t;
+        self.k = k;
+        self.z = z;
+        return Ok(());
+    }
+
+    /// Sets how long transactions wait for key locks.
+    ///
+    /// Pessimistic transactions lock every key they read or write, and optimistic ones lock the
+    /// keys they write while committing. A transaction waiting longer than `timeout` for a lock
+    /// fails with `TransactionError::LockTimeout`, which is also how deadlocks are broken.
+    pub fn set_lock_timeout(self: &mut Self, timeout: Duration) {
+        self.lock_timeout = timeout;
+    }
+
+    /// Sets when writes are held up by memory map flushes falling behind.
+    ///
+    /// While one memory map is written to a run new writes go to the other. Once both maps of a
+    /// column family hold `slowdown` times `memory_map_budget` bytes between them each write is
+    /// delayed by `write_slowdown_delay`, and once they hold `stop` times the budget writes
+    /// block until a flush finishes. A `stop` of 2 blocks writers once the active map is full
+    /// while the other one is still being flushed.
+    pub fn set_memory_map_stall(
+        self: &mut Self,
+        slowdown: f64,
+        stop: f64,
+    ) -> Result<(), RustStoreError> {
+        if slowdown < 1.0 || stop <= 1.0 {
+            return Err(RustStoreError::OptionParsingError(
+                "writes can only stall once a memory map is over budget"
+                    .parse()
+                    .unwrap(),
+            ));
+        }
+        if slowdown > stop {
+            return Err(RustStoreError::OptionParsingError(
+                "writes must slow down before they stop".parse().unwrap(),
+            ));
+        }
+        self.memory_slowdown_ratio = slowdown;
+        self.memory_stop_ratio = stop;
+        return Ok(());
+    }
+
+    /// Sets when writes are held up by compactions falling behind.
+    ///
+    /// Every flush adds a run to level 1 and every get searches all of them. Once a column
+    /// family's level 1 holds `slowdown` sorted runs each write is delayed by
+    /// `write_slowdown_delay`, and at `stop` runs writes block until a compaction brings it back
+    /// under. Writes are never stopped while level 1 holds no more than the `k` or `z` runs the
+    /// compaction policy leaves there.
+    pub fn set_level1_stall(
+        self: &mut Self,
+        slowdown: usize,
+        stop: usize,
+    ) -> Result<(), RustStoreError> {
+        if slowdown == 0 {
+            return Err(RustStoreError::OptionParsingError(
+                "writes can't slow down with an empty level 1"
+                    .parse()
+                    .unwrap(),
+            ));
+        }
+        if slowdown > stop {
+            return Err(RustStoreError::OptionParsingError(
+                "writes must slow down before they stop".parse().unwrap(),
+            ));
+        }
+        self.level1_slowdown_runs = slowdown;
+        self.level1_stop_runs = stop;
+        return Ok(());
+    }
+
+    /// Sets how long each write is delayed by while a column family is past one of its
+    /// slowdown thresholds, which gives flushes and compactions a chance to catch up before
+    /// writes have to stop.
+    pub fn set_write_slowdown_delay(self: &mut Self, delay: Duration) {
+        self.write_slowdown_delay = delay;
+    }
+
+    /// Sets the merge operator used to fold the operands written by `RustStore::merge`.
+    ///
+    /// Operands stay in the store until a read or compaction folds them, so a store holding
+    /// operands must always be opened with the same operator.
+    pub fn set_merge_operator(self: &mut Self, operator: Arc<dyn MergeOperator>) {
+        self.merge_operator = Some(operator);
+    }
+
+    /// Sets the compaction filter, which decides whether values are kept, removed or rewritten
+    /// as compactions rewrite them. See `CompactionFilter`.
+    pub fn set_compaction_filter(self: &mut Self, filter: Arc<dyn CompactionFilter>) {
+        self.compaction_filter = Some(filter);
+    }
+
+    /// Sets the limiter for the bytes per second written by flushes and compactions of every
+    /// column family. Keep a handle to it to change the rate while the store is open, see
+    /// `RateLimiter`.
+    pub fn set_rate_limiter(self: &mut Self, limiter: Arc<RateLimiter>) {
+        self.rate_limiter = Some(limiter);
+    }
+
+    /// Declares a column family, an independent keyspace in the same store.
+    ///
+    /// The family is created when the store is opened if it does not exist yet. Its memory map
+    /// budget, bloom filter budget, `t`, `k`, `z`, block and file sizes, stall thresholds, merge
+    /// operator and compaction filter come from `config`, while the directory, log, locking,
+    /// write slowdown delay and rate limiting options are always the store's. Families which
+    /// exist on disk but are not declared are opened with the store's own tuning.
+    pub fn add_column_family(self: &mut Self, name: &str, config: Config) {
+        self.column_families.insert(String::from(name), config);
+    }
+
+    // The config a column family is opened with, its own tuning if it was declared
+    pub(crate) fn column_family_config(self: &Self, declared: Option<Config>) -> Config {
+        let mut config = declared.unwrap_or_else(|| self.clone());
+        config.directory = self.directory.clone();
+        config.wal_sync_policy = self.wal_sync_policy;
+        config.lock_timeout = self.lock_timeout;
+        config.write_slowdown_delay = self.write_slowdown_delay;
+        config.rate_limiter = self.rate_limiter.clone();
+        config.column_families = BTreeMap::new();
+        return config;
+    }
 }
 
-impl RustStore {
+impl<K: Key> RustStore<K> {
     /// Instantiate a new RustStore database.
     ///
     /// # Arguments
     ///
     /// * `config` - An optional RustStore::Config struct. If None is passed
     ///              the default config is used.
-    pub fn new(config: Option<Config>) -> RustStore {
-        return RustStore {
-            lsm: Lsm::new(config),
+    ///
+    /// If the config has a directory with a write ahead log in it, the log is replayed.
+    pub fn new(config: Option<Config>) -> Result<RustStore<K>, RustStoreError> {
+        let lock_timeout = match &config {
+            Some(config) => config.lock_timeout,
+            None => Config::default().lock_timeout,
         };
+        return Ok(RustStore {
+            lsm: Lsm::new(config)?,
+            locks: Arc::new(LockTable::new(lock_timeout)),
+        });
     }
 
     /// Get a value from the database
     ///
     /// # Arguments
     ///
-    /// * `key` - key to look up a value for.
+    /// * `key` - key to look up a value for, or a borrowed form of it such as `&[u8]` for
+    ///           `Vec<u8>` keys.
     ///
     /// # Examples
     ///
@@ -152,11 +407,15 @@ impl RustStore {
     /// let config = Config::default();
     ///
     /// // Equivalent to RustStore::new(None) since we are using default options
-    /// let db = RustStore::new(Some(config));
+    /// let db: RustStore<i32> = RustStore::new(Some(config)).unwrap();
     /// let key = 42;
     /// let val = db.get(&42);
     /// ```
-    pub fn get(self: &Self, key: &i32) -> Option<Vec<u8>> {
+    pub fn get<Q>(self: &Self, key: &Q) -> Option<Vec<u8>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + Hash + Debug + ToOwned<Owned = K> + ?Sized,
+    {
         return self.lsm.get(key);
     }
 
@@ -166,20 +425,313 @@ impl RustStore {
     ///
     /// * `key` - key associated with val
     /// * `value` - key associated with val
+    ///
+    /// With a directory the write is logged as a single record, a key and value taking more
+    /// than `wal::MAX_RECORD_SIZE` bytes serialized fail with `WalError::RecordTooLarge`.
     /// # Examples
     ///
     //     ```
     //     use rust_kv::{Config, RustStore};
     //     let config = Config::default();
     //
-    //     let db = RustStore::new(config);
+    //     let db = RustStore::new(config).unwrap();
     //     let key = 42;
     //     let put_val = vec![43_u8, 44_u8];
     //     let get_val = db.get(&42);
     //     // get_val == put_val
     //     ```
-    pub fn put(self: &Self, key: i32, value: Vec<u8>) -> () {
-        self.lsm.put(key, value);
+    pub fn put(self: &Self, key: K, value: Vec<u8>) -> Result<(), RustStoreError> {
+        self.lsm.put(key, value)?;
+        return Ok(());
+    }
+
+    /// Get a handle to the column family `name`, None if it was never created.
+    ///
+    /// Column families are created by declaring them with `Config::add_column_family`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_kv::{Config, RustStore};
+    /// let mut config = Config::default();
+    /// config.add_column_family("users", Config::default());
+    /// let db = RustStore::new(Some(config)).unwrap();
+    /// let users = db.column_family("users").unwrap();
+    /// users.put(1, vec![1u8]).unwrap();
+    /// assert_eq!(users.get(&1), Some(vec![1u8]));
+    /// assert_eq!(db.get(&1), None);
+    /// assert!(db.column_family("groups").is_none());
+    /// ```
+    pub fn column_family(self: &Self, name: &str) -> Option<ColumnFamily<K>> {
+        return self
+            .lsm
+            .column_family(name)
+            .map(|data| ColumnFamily::new(self.lsm.clone(), data));
+    }
+
+    /// What background compactions have read, written and dropped since the store was opened,
+    /// over every column family.
+    ///
+    /// Deletes and the versions they shadow are dropped once they are compacted into the
+    /// bottom level, so `tombstones_dropped` shows how much deleted data has been reclaimed.
+    pub fn compaction_stats(self: &Self) -> CompactionStats {
+        return self.lsm.compaction_stats();
+    }
+
+    /// How many writes have been delayed or blocked by flushes and compactions falling behind
+    /// since the store was opened, and for how long.
+    pub fn write_stall_stats(self: &Self) -> WriteStallStats {
+        return self.lsm.write_stall_stats();
+    }
+
+    /// Flush the memory maps and merge every run file holding keys in `range` down into the
+    /// deepest level, returning once the merged files have replaced them.
+    ///
+    /// Background compactions only reach the bottom level, where deletes and the versions
+    /// they shadow are dropped, once the levels above fill up. Compacting the range after a
+    /// bulk delete reclaims the space straight away, unless a live snapshot still reads the
+    /// deleted versions. Files sharing keys with the ones in range are merged too, so more
+    /// than the range may be rewritten. Only the default column family is compacted, see
+    /// `ColumnFamily::compact_range`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_kv::{Config, RustStore};
+    /// let dir = tempfile::tempdir().unwrap();
+    /// let mut config = Config::default();
+    /// config.set_directory(dir.path());
+    /// let db = RustStore::new(Some(config)).unwrap();
+    /// for i in 0..100 {
+    ///     db.put(i, vec![1u8]).unwrap();
+    /// }
+    /// db.delete_range(0, 50).unwrap();
+    /// let report = db.compact_range(0..50).unwrap();
+    /// assert_eq!(report.output_level, Some(1));
+    /// assert_eq!(report.stats.range_tombstones_dropped, 1);
+    /// assert_eq!(report.stats.entries_written, 50);
+    /// assert_eq!(db.range(..).count(), 50);
+    /// ```
+    pub fn compact_range<R: RangeBounds<K>>(
+        self: &Self,
+        range: R,
+    ) -> Result<CompactionReport, RustStoreError> {
+        return Ok(self.lsm.compact_range(range)?);
+    }
+
+    /// Flush the memory maps and merge every run file of the default column family into a
+    /// single sorted run in the deepest level, see `compact_range`.
+    ///
+    /// Once this returns the default family is fully on disk in as few files as the file size
+    /// allows, which makes it a good point to back the directory up.
+    pub fn compact_all(self: &Self) -> Result<CompactionReport, RustStoreError> {
+        return self.compact_range(..);
+    }
+
+    /// Take a snapshot, reads through it see the database as it is now while writes,
+    /// flushes and compactions carry on.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_kv::{Config, RustStore};
+    /// let db = RustStore::new(Some(Config::default())).unwrap();
+    /// db.put(1, vec![1u8]).unwrap();
+    /// let snapshot = db.snapshot();
+    /// db.put(1, vec![2u8]).unwrap();
+    /// assert_eq!(snapshot.get(&1), Some(vec![1u8]));
+    /// assert_eq!(db.get(&1), Some(vec![2u8]));
+    /// ```
+    pub fn snapshot(self: &Self) -> Snapshot<K> {
+        return Snapshot::new(self.lsm.clone());
+    }
+
+    /// Put a key value pair which expires after `ttl`.
+    ///
+    /// Once expired the key reads as deleted, and compactions drop the value. Expiry is
+    /// measured against the system clock.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_kv::RustStore;
+    /// use std::time::Duration;
+    /// let db = RustStore::new(None).unwrap();
+    /// db.put_with_ttl(1, vec![1u8], Duration::from_millis(20)).unwrap();
+    /// assert_eq!(db.get(&1), Some(vec![1u8]));
+    /// std::thread::sleep(Duration::from_millis(30));
+    /// assert_eq!(db.get(&1), None);
+    /// ```
+    pub fn put_with_ttl(
+        self: &Self,
+        key: K,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<(), RustStoreError> {
+        self.lsm.put_with_ttl(key, value, ttl)?;
+        return Ok(());
+    }
+
+    /// Delete every key in `start..end`.
+    ///
+    /// A single range tombstone is written however many keys the range holds, it hides the
+    /// older values until compaction reaches the bottom level and drops them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_kv::RustStore;
+    /// let db = RustStore::new(None).unwrap();
+    /// for i in 0..10 {
+    ///     db.put(i, vec![i as u8]).unwrap();
+    /// }
+    /// db.delete_range(2, 8).unwrap();
+    /// assert_eq!(db.get(&2), None);
+    /// assert_eq!(db.get(&8), Some(vec![8u8]));
+    /// assert_eq!(db.range(..).count(), 4);
+    /// ```
+    pub fn delete_range(self: &Self, start: K, end: K) -> Result<(), RustStoreError> {
+        if start >= end {
+            return Ok(());
+        }
+        self.lsm.delete_range(start, end)?;
+        return Ok(());
+    }
+
+    /// Write an operand for the configured merge operator to fold into the value of `key`.
+    ///
+    /// Nothing is read, the operand is folded in by later reads and compactions. Fails if no
+    /// merge operator is set in the config.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_kv::{Config, MergeOperator, RustStore};
+    /// use std::sync::Arc;
+    ///
+    /// struct Append;
+    ///
+    /// impl MergeOperator for Append {
+    ///     fn merge(&self, existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Vec<u8> {
+    ///         let mut value = existing.unwrap_or(&[]).to_vec();
+    ///         for operand in operands {
+    ///             value.extend(operand);
+    ///         }
+    ///         return value;
+    ///     }
+    /// }
+    ///
+    /// let mut config = Config::default();
+    /// config.set_merge_operator(Arc::new(Append));
+    /// let db = RustStore::new(Some(config)).unwrap();
+    /// db.put(1, vec![1u8]).unwrap();
+    /// db.merge(1, vec![2u8]).unwrap();
+    /// db.merge(1, vec![3u8]).unwrap();
+    /// assert_eq!(db.get(&1), Some(vec![1u8, 2u8, 3u8]));
+    /// ```
+    pub fn merge(self: &Self, key: K, operand: Vec<u8>) -> Result<(), RustStoreError> {
+        self.lsm.merge(key, operand)?;
+        return Ok(());
+    }
+
+    /// Atomically replace the value of `key` if it is currently `expected`.
+    ///
+    /// `expected` of None means the key must be absent, `new` of None deletes the key. Returns
+    /// whether the swap happened, nothing is written if the current value differs.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_kv::RustStore;
+    /// let db = RustStore::new(None).unwrap();
+    /// db.put(1, vec![1u8]).unwrap();
+    /// assert!(!db.compare_and_swap(1, Some(&[2u8]), Some(vec![3u8])).unwrap());
+    /// assert!(db.compare_and_swap(1, Some(&[1u8]), Some(vec![3u8])).unwrap());
+    /// assert!(db.compare_and_swap(1, Some(&[3u8]), None).unwrap());
+    /// assert_eq!(db.get(&1), None);
+    /// ```
+    pub fn compare_and_swap(
+        self: &Self,
+        key: K,
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool, RustStoreError> {
+        return Ok(self.lsm.compare_and_swap(key, expected, new)?);
+    }
+
+    /// Put a key value pair unless the key already has a value, returns whether it was put
+    pub fn put_if_absent(self: &Self, key: K, value: Vec<u8>) -> Result<bool, RustStoreError> {
+        return self.compare_and_swap(key, None, Some(value));
+    }
+
+    /// Begin a transaction, its writes are applied atomically when it commits.
+    ///
+    /// An optimistic transaction reads from a snapshot taken when it begins and fails to commit
+    /// with `TransactionError::Conflict` if another write has touched a key it read or wrote since.
+    /// A pessimistic transaction locks each key it reads or writes until it finishes, so it
+    /// always commits but may fail with `TransactionError::LockTimeout`. Writes made through
+    /// `put`, `delete` and `write` do not take key locks.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_kv::{RustStore, TransactionMode};
+    /// let db = RustStore::new(None).unwrap();
+    /// db.put(1, vec![10u8]).unwrap();
+    /// let mut txn = db.begin_transaction(TransactionMode::Optimistic);
+    /// let balance = txn.get(&1).unwrap().unwrap();
+    /// txn.put(1, vec![balance[0] - 3]).unwrap();
+    /// txn.put(2, vec![3u8]).unwrap();
+    /// txn.commit().unwrap();
+    /// assert_eq!(db.get(&1), Some(vec![7u8]));
+    /// ```
+    pub fn begin_transaction(self: &Self, mode: TransactionMode) -> Transaction<K> {
+        return Transaction::new(self.lsm.clone(), self.locks.clone(), mode);
+    }
+
+    /// Apply a batch of puts and deletes atomically.
+    ///
+    /// The batch is logged as one record, so after a crash either all of it or none of it is
+    /// replayed, and readers never see part of it. With a directory a batch taking more than
+    /// `wal::MAX_RECORD_SIZE` bytes serialized fails with `WalError::RecordTooLarge`, split it
+    /// into smaller batches instead.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_kv::{Config, RustStore, WriteBatch};
+    /// let db = RustStore::new(Some(Config::default())).unwrap();
+    /// let mut batch = WriteBatch::new();
+    /// batch.put(1, vec![1u8]);
+    /// batch.put(2, vec![2u8]);
+    /// batch.delete(3);
+    /// db.write(batch).unwrap();
+    /// assert_eq!(db.get(&2), Some(vec![2u8]));
+    /// ```
+    pub fn write(self: &Self, batch: WriteBatch<K>) -> Result<(), RustStoreError> {
+        self.lsm.write_batch(batch)?;
+        return Ok(());
+    }
+
+    /// Iterate over the key value pairs with keys in `range`, in key order.
+    ///
+    /// # Arguments
+    ///
+    /// * `range` - keys to scan, e.g. `start..end` or `start..`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_kv::{Config, RustStore};
+    /// let db = RustStore::new(Some(Config::default())).unwrap();
+    /// db.put(1, vec![1u8]).unwrap();
+    /// db.put(2, vec![2u8]).unwrap();
+    /// db.put(3, vec![3u8]).unwrap();
+    /// let pairs: Vec<(i32, Vec<u8>)> = db.range(1..3).collect();
+    /// assert_eq!(pairs, vec![(1, vec![1u8]), (2, vec![2u8])]);
+    /// ```
+    pub fn range<R: RangeBounds<K>>(self: &Self, range: R) -> impl Iterator<Item = (K, Vec<u8>)> {
+        return self.lsm.range(range);
     }
 
     /// Delete a value associated with a key
@@ -187,20 +739,50 @@ impl RustStore {
     /// # Arguments
     ///
     /// * `key` - Key to delete
-    pub fn delete(self: &Self, key: &i32) -> () {
-        self.lsm.delete(key);
+    pub fn delete(self: &Self, key: &K) -> Result<(), RustStoreError> {
+        self.lsm.delete(key)?;
+        return Ok(());
+    }
+
+    /// Shut down the database.
+    ///
+    /// Waits for any flush or compaction in progress, stops the background threads and writes
+    /// everything in memory to disk so the next open doesn't need to replay the log.
+    /// Dropping a RustStore does the same, but any error is only logged.
+    ///
+    /// The directory can be opened again right away. Column family, snapshot and transaction
+    /// handles still alive can read, but their writes fail.
+    pub fn close(self: Self) -> Result<(), RustStoreError> {
+        self.lsm.close()?;
+        return Ok(());
+    }
+}
+
+impl<K: Key> Drop for RustStore<K> {
+    fn drop(&mut self) {
+        if let Err(e) = self.lsm.close() {
+            error!("Error closing RustStore: {:?}", e);
+        }
     }
 }
 
 #[cfg(test)]
 mod test_rust_store {
-    use crate::{Config, RustStore, RustStoreError};
+    use crate::lsm::LsmError;
+    use crate::{
+        Bytes, Comparator, Config, MergeOperator, RustStore, RustStoreError, SyncPolicy, WriteBatch,
+    };
     use log::info;
     use rand::prelude::SliceRandom;
     use rand::Rng;
     use rand_chacha::rand_core::SeedableRng;
     use rand_chacha::ChaChaRng;
+    use std::cmp::Ordering;
+    use std::convert::TryInto;
+    use std::sync::Arc;
+    use std::thread;
     use std::thread::sleep;
+    use std::time::Duration;
     use tempfile::tempdir;
     use test_case::test_case;
     // use test_env_log::test;
@@ -214,7 +796,7 @@ mod test_rust_store {
         let dir = tempdir().unwrap();
         config.set_directory(dir.path());
 
-        let db = RustStore::new(Some(config));
+        let db = RustStore::new(Some(config)).unwrap();
 
         let mut values = Vec::new();
         let mut keys = Vec::new();
@@ -227,7 +809,7 @@ mod test_rust_store {
         keys.shuffle(&mut rng);
 
         for i in 0..num_pairs {
-            db.put(keys[i], values[i].clone());
+            db.put(keys[i], values[i].clone()).unwrap();
         }
 
         for i in 0..num_pairs {
@@ -236,6 +818,674 @@ mod test_rust_store {
         }
     }
 
+    #[test]
+    fn writes_survive_reopen() {
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        config.set_wal_sync_policy(SyncPolicy::EveryWrite);
+        {
+            let db = RustStore::new(Some(config)).unwrap();
+            for i in 0..100 {
+                db.put(i, vec![i as u8]).unwrap();
+            }
+            db.delete(&50).unwrap();
+        }
+
+        let mut config = Config::default();
+        config.set_directory(dir.path());
+        let db: RustStore<i32> = RustStore::new(Some(config)).unwrap();
+        for i in 0..100 {
+            if i == 50 {
+                assert_eq!(db.get(&i), None);
+            } else {
+                assert_eq!(db.get(&i), Some(vec![i as u8]));
+            }
+        }
+    }
+
+    #[test]
+    fn close_writes_memory_maps_to_runs() {
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        let db = RustStore::new(Some(config)).unwrap();
+        for i in 0..100 {
+            db.put(i, vec![i as u8]).unwrap();
+        }
+        db.close().unwrap();
+
+        let num_runs = std::fs::read_dir(dir.path())
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().extension() == Some("run".as_ref()))
+            .count();
+        assert_eq!(num_runs, 1);
+
+        let mut config = Config::default();
+        config.set_directory(dir.path());
+        let db: RustStore<i32> = RustStore::new(Some(config)).unwrap();
+        for i in 0..100 {
+            assert_eq!(db.get(&i), Some(vec![i as u8]));
+        }
+    }
+
+    #[test]
+    fn range_merges_memory_maps_and_runs() {
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        {
+            let db = RustStore::new(Some(config)).unwrap();
+            for i in 0..2000 {
+                db.put(i, vec![0u8]).unwrap();
+            }
+            db.close().unwrap();
+        }
+
+        // overwrite and delete some keys so the memory map shadows the run
+        let mut config = Config::default();
+        config.set_directory(dir.path());
+        let db = RustStore::new(Some(config)).unwrap();
+        for i in (0..2000).step_by(2) {
+            db.put(i, vec![1u8]).unwrap();
+        }
+        for i in (0..2000).step_by(3) {
+            db.delete(&i).unwrap();
+        }
+        db.put(5000, vec![2u8]).unwrap();
+
+        let expected: Vec<(i32, Vec<u8>)> = (100..1900)
+            .filter(|i| i % 3 != 0)
+            .map(|i| (i, vec![if i % 2 == 0 { 1u8 } else { 0u8 }]))
+            .collect();
+        let pairs: Vec<(i32, Vec<u8>)> = db.range(100..1900).collect();
+        assert_eq!(pairs, expected);
+
+        let tail: Vec<i32> = db.range(1995..).map(|(k, _)| k).collect();
+        assert_eq!(tail, vec![1996, 1997, 1999, 5000]);
+        assert_eq!(db.range(2000..5000).count(), 0);
+    }
+
+    #[test]
+    fn u64_keys_survive_reopen() {
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        {
+            let db: RustStore<u64> = RustStore::new(Some(config)).unwrap();
+            for i in 0..1000u64 {
+                db.put(u64::MAX - i, vec![i as u8]).unwrap();
+            }
+            db.close().unwrap();
+        }
+
+        let mut config = Config::default();
+        config.set_directory(dir.path());
+        let db: RustStore<u64> = RustStore::new(Some(config)).unwrap();
+        for i in 0..1000u64 {
+            assert_eq!(db.get(&(u64::MAX - i)), Some(vec![i as u8]));
+        }
+        let keys: Vec<u64> = db.range(u64::MAX - 2..).map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![u64::MAX - 2, u64::MAX - 1, u64::MAX]);
+    }
+
+    #[test]
+    fn composite_keys() {
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        {
+            let db: RustStore<(String, u32)> = RustStore::new(Some(config)).unwrap();
+            for user in ["alice", "bob", "carol"].iter() {
+                for i in 0..200 {
+                    db.put((user.to_string(), i), vec![i as u8]).unwrap();
+                }
+            }
+            db.delete(&("bob".to_string(), 7)).unwrap();
+            db.close().unwrap();
+        }
+
+        let mut config = Config::default();
+        config.set_directory(dir.path());
+        let db: RustStore<(String, u32)> = RustStore::new(Some(config)).unwrap();
+        assert_eq!(db.get(&("carol".to_string(), 199)), Some(vec![199u8]));
+        assert_eq!(db.get(&("bob".to_string(), 7)), None);
+        let bobs = db
+            .range(("bob".to_string(), 0)..("carol".to_string(), 0))
+            .count();
+        assert_eq!(bobs, 199);
+    }
+
+    #[test]
+    fn byte_string_keys() {
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        let seed = [42; 32];
+        let mut rng = ChaChaRng::from_seed(seed);
+        let mut keys: Vec<Vec<u8>> = (0..1000).map(|_| gen_rand_bytes(&mut rng)).collect();
+        {
+            let db: RustStore<Vec<u8>> = RustStore::new(Some(config)).unwrap();
+            for key in keys.iter() {
+                db.put(key.clone(), key.clone()).unwrap();
+            }
+            db.close().unwrap();
+        }
+
+        let mut config = Config::default();
+        config.set_directory(dir.path());
+        let db: RustStore<Vec<u8>> = RustStore::new(Some(config)).unwrap();
+        for key in keys.iter() {
+            let slice: &[u8] = key;
+            assert_eq!(db.get(slice), Some(key.clone()));
+        }
+        keys.sort();
+        keys.dedup();
+        let scanned: Vec<Vec<u8>> = db.range(..).map(|(k, _)| k).collect();
+        assert_eq!(scanned, keys);
+    }
+
+    struct Reverse;
+
+    impl Comparator for Reverse {
+        const NAME: &'static str = "test.Reverse";
+
+        fn compare(a: &[u8], b: &[u8]) -> Ordering {
+            return b.cmp(a);
+        }
+    }
+
+    #[test]
+    fn custom_comparator_orders_runs_and_scans() {
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        {
+            let db: RustStore<Bytes<Reverse>> = RustStore::new(Some(config)).unwrap();
+            for i in 0..500u32 {
+                db.put(Bytes::new(i.to_be_bytes().to_vec()), vec![1u8])
+                    .unwrap();
+            }
+            db.close().unwrap();
+        }
+
+        let mut config = Config::default();
+        config.set_directory(dir.path());
+        let db: RustStore<Bytes<Reverse>> = RustStore::new(Some(config)).unwrap();
+        for i in 500..1000u32 {
+            db.put(Bytes::new(i.to_be_bytes().to_vec()), vec![2u8])
+                .unwrap();
+        }
+        for i in 0..1000u32 {
+            assert!(db.get(&Bytes::new(i.to_be_bytes().to_vec())).is_some());
+        }
+        let scanned: Vec<u32> = db
+            .range(..)
+            .map(|(k, _)| u32::from_be_bytes(k.as_bytes().try_into().unwrap()))
+            .collect();
+        assert_eq!(scanned, (0..1000u32).rev().collect::<Vec<u32>>());
+    }
+
+    #[test_case(1, 1, 1 ; "size ratio of one")]
+    #[test_case(0, 1, 1 ; "size ratio of zero")]
+    #[test_case(4, 0, 1 ; "no runs per level")]
+    #[test_case(4, 3, 0 ; "no runs in largest level")]
+    fn invalid_compaction_policy_is_rejected(t: u64, k: u64, z: u64) {
+        let mut config = Config::default();
+        config.t = t;
+        config.k = k;
+        config.z = z;
+        let res: Result<RustStore<i32>, RustStoreError> = RustStore::new(Some(config.clone()));
+        assert!(matches!(
+            res,
+            Err(RustStoreError::LsmError(LsmError::InvalidOption(_)))
+        ));
+
+        // a column family's policy is checked too
+        let mut store_config = Config::default();
+        store_config.add_column_family("users", config);
+        let res: Result<RustStore<i32>, RustStoreError> = RustStore::new(Some(store_config));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn reopen_with_other_comparator_fails() {
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        {
+            let db: RustStore<Bytes<Reverse>> = RustStore::new(Some(config)).unwrap();
+            db.put(Bytes::from(&b"key"[..]), vec![1u8]).unwrap();
+            db.close().unwrap();
+        }
+
+        let mut config = Config::default();
+        config.set_directory(dir.path());
+        let res: Result<RustStore<Bytes>, RustStoreError> = RustStore::new(Some(config));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn reopen_empty_store_with_other_comparator_fails() {
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        {
+            // no runs are written, only the manifest knows the comparator
+            let db: RustStore<Bytes<Reverse>> = RustStore::new(Some(config)).unwrap();
+            db.close().unwrap();
+        }
+
+        let mut config = Config::default();
+        config.set_directory(dir.path());
+        let res: Result<RustStore<Bytes>, RustStoreError> = RustStore::new(Some(config));
+        assert!(res.is_err());
+
+        let mut config = Config::default();
+        config.set_directory(dir.path());
+        let db: RustStore<Bytes<Reverse>> = RustStore::new(Some(config)).unwrap();
+        db.put(Bytes::from(&b"key"[..]), vec![1u8]).unwrap();
+        assert_eq!(db.get(&Bytes::from(&b"key"[..])), Some(vec![1u8]));
+    }
+
+    #[test]
+    fn write_batch_survives_reopen() {
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        config.set_wal_sync_policy(SyncPolicy::EveryWrite);
+        {
+            let db = RustStore::new(Some(config)).unwrap();
+            db.put(0, vec![0u8]).unwrap();
+            let mut batch = WriteBatch::new();
+            for i in 1..100 {
+                batch.put(i, vec![i as u8]);
+            }
+            batch.delete(0);
+            batch.put(50, vec![0u8]);
+            db.write(batch).unwrap();
+            assert_eq!(db.get(&0), None);
+            assert_eq!(db.get(&50), Some(vec![0u8]));
+        }
+
+        let mut config = Config::default();
+        config.set_directory(dir.path());
+        let db: RustStore<i32> = RustStore::new(Some(config)).unwrap();
+        assert_eq!(db.get(&0), None);
+        assert_eq!(db.get(&50), Some(vec![0u8]));
+        assert_eq!(db.range(1..100).count(), 99);
+    }
+
+    #[test]
+    fn readers_never_see_part_of_a_batch() {
+        let db = Arc::new(RustStore::new(None).unwrap());
+        let writer_db = db.clone();
+        let writer = thread::spawn(move || {
+            for round in 0..200u8 {
+                let mut batch = WriteBatch::new();
+                for key in 0..50 {
+                    batch.put(key, vec![round]);
+                }
+                writer_db.write(batch).unwrap();
+            }
+        });
+        while !writer.is_finished() {
+            let values: Vec<Vec<u8>> = db.range(0..50).map(|(_, v)| v).collect();
+            if !values.is_empty() {
+                assert_eq!(values.len(), 50);
+                assert!(values.iter().all(|v| *v == values[0]));
+            }
+        }
+        writer.join().unwrap();
+        assert_eq!(db.get(&0), Some(vec![199u8]));
+    }
+
+    #[test]
+    fn snapshot_ignores_later_writes() {
+        let mut config = Config::default();
+        let dir = tempdir().unwrap();
+        config.set_directory(dir.path());
+        let db = RustStore::new(Some(config)).unwrap();
+        for i in 0..100 {
+            db.put(i, vec![0u8]).unwrap();
+        }
+        let snapshot = db.snapshot();
+        for i in 0..100 {
+            db.put(i, vec![1u8]).unwrap();
+        }
+        for i in (0..100).step_by(2) {
+            db.delete(&i).unwrap();
+        }
+        db.put(100, vec![1u8]).unwrap();
+
+        for i in 0..100 {
+            assert_eq!(snapshot.get(&i), Some(vec![0u8]));
+        }
+        assert_eq!(snapshot.get(&100), None);
+        let pairs: Vec<(i32, Vec<u8>)> = snapshot.range(..).collect();
+        assert_eq!(pairs, (0..100).map(|i| (i, vec![0u8])).collect::<Vec<_>>());
+
+        assert_eq!(db.get(&0), None);
+        assert_eq!(db.get(&1), Some(vec![1u8]));
+        assert_eq!(db.range(..).count(), 51);
+    }
+
+    #[test]
+    fn snapshot_sees_a_batch_whole_or_not_at_all() {
+        let db = RustStore::new(None).unwrap();
+        db.put(0, vec![0u8]).unwrap();
+        let before = db.snapshot();
+        let mut batch = WriteBatch::new();
+        batch.put(0, vec![1u8]);
+        batch.put(1, vec![1u8]);
+        db.write(batch).unwrap();
+        let after = db.snapshot();
+        // the writes in a batch take consecutive sequence numbers
+        assert_eq!(after.seq(), before.seq() + 2);
+        assert_eq!(before.range(..).count(), 1);
+        assert_eq!(after.range(..).count(), 2);
+        assert_eq!(after.get(&0), Some(vec![1u8]));
+    }
+
+    #[test]
+    fn compare_and_swap_checks_current_value() {
+        let db = RustStore::new(None).unwrap();
+        assert!(!db
+            .compare_and_swap(1, Some(&[1u8]), Some(vec![2u8]))
+            .unwrap());
+        assert_eq!(db.get(&1), None);
+        assert!(db.put_if_absent(1, vec![1u8]).unwrap());
+        assert!(!db.put_if_absent(1, vec![5u8]).unwrap());
+        assert_eq!(db.get(&1), Some(vec![1u8]));
+
+        assert!(!db.compare_and_swap(1, None, Some(vec![2u8])).unwrap());
+        assert!(db
+            .compare_and_swap(1, Some(&[1u8]), Some(vec![2u8]))
+            .unwrap());
+        assert_eq!(db.get(&1), Some(vec![2u8]));
+        assert!(!db.compare_and_swap(1, Some(&[1u8]), None).unwrap());
+        assert!(db.compare_and_swap(1, Some(&[2u8]), None).unwrap());
+        assert_eq!(db.get(&1), None);
+        // a deleted key counts as absent
+        assert!(db.put_if_absent(1, vec![3u8]).unwrap());
+    }
+
+    #[test]
+    fn compare_and_swap_counter_from_many_threads() {
+        let db: Arc<RustStore<i32>> = Arc::new(RustStore::new(None).unwrap());
+        let mut handles = vec![];
+        for _ in 0..4 {
+            let db = db.clone();
+            handles.push(thread::spawn(move || {
+                let mut leases = 0;
+                for _ in 0..250 {
+                    loop {
+                        let current = db.get(&0);
+                        let count = current
+                            .as_ref()
+                            .map_or(0, |v| u32::from_le_bytes(v[..].try_into().unwrap()));
+                        let new = (count + 1).to_le_bytes().to_vec();
+                        if db
+                            .compare_and_swap(0, current.as_deref(), Some(new))
+                            .unwrap()
+                        {
+                            break;
+                        }
+                    }
+                }
+                for lease in 0..50 {
+                    if db.put_if_absent(lease + 1, vec![0u8]).unwrap() {
+                        leases += 1;
+                    }
+                }
+                return leases;
+            }));
+        }
+        let leases: i32 = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(leases, 50);
+        assert_eq!(db.get(&0), Some(1000u32.to_le_bytes().to_vec()));
+    }
+
+    struct Append;
+
+    impl MergeOperator for Append {
+        fn merge(&self, existing: Option<&[u8]>, operands: &[Vec<u8>]) -> Vec<u8> {
+            let mut value = existing.unwrap_or(&[]).to_vec();
+            for operand in operands {
+                value.extend(operand);
+            }
+            return value;
+        }
+    }
+
+    #[test]
+    fn merge_folds_in_gets_scans_and_snapshots() {
+        let mut config = Config::default();
+        config.set_merge_operator(Arc::new(Append));
+        let db = RustStore::new(Some(config)).unwrap();
+        db.merge(1, vec![1u8]).unwrap();
+        db.put(2, vec![0u8]).unwrap();
+        db.merge(2, vec![2u8]).unwrap();
+        let snapshot = db.snapshot();
+        db.merge(2, vec![3u8]).unwrap();
+        db.delete(&1).unwrap();
+        db.merge(1, vec![4u8]).unwrap();
+
+        // a put and a merge of the same key in one batch both count
+        let mut batch = WriteBatch::new();
+        batch.put(3, vec![5u8]);
+        batch.merge(3, vec![6u8]);
+        db.write(batch).unwrap();
+
+        assert_eq!(db.get(&1), Some(vec![4u8]));
+        assert_eq!(db.get(&2), Some(vec![0u8, 2u8, 3u8]));
+        assert_eq!(db.get(&3), Some(vec![5u8, 6u8]));
+        let pairs: Vec<(i32, Vec<u8>)> = db.range(..).collect();
+        assert_eq!(
+            pairs,
+            vec![
+                (1, vec![4u8]),
+                (2, vec![0u8, 2u8, 3u8]),
+                (3, vec![5u8, 6u8])
+            ]
+        );
+        assert_eq!(snapshot.get(&1), Some(vec![1u8]));
+        assert_eq!(snapshot.get(&2), Some(vec![0u8, 2u8]));
+        assert_eq!(snapshot.range(..).count(), 2);
+    }
+
+    #[test]
+    fn merges_survive_reopen() {
+        let dir = tempdir().unwrap();
+        for _ in 0..2 {
+            let mut config = Config::default();
+            config.set_directory(dir.path());
+            config.set_merge_operator(Arc::new(Append));
+            let db = RustStore::new(Some(config)).unwrap();
+            db.merge(1, vec![1u8]).unwrap();
+            db.close().unwrap();
+        }
+        let mut config = Config::default();
+        config.set_directory(dir.path());
+        config.set_merge_operator(Arc::new(Append));
+        let db: RustStore<i32> = RustStore::new(Some(config)).unwrap();
+        assert_eq!(db.get(&1), Some(vec![1u8, 1u8]));
+    }
+
+    #[test]
+    fn delete_range_hides_keys_in_memory_and_runs() {
+        let dir = tempdir().unwrap();
+        let mut config = Config::default();
+        config.set_directory(dir.path());
+        {
+            let db = RustStore::new(Some(config)).unwrap();
+            for i in 0..1000 {
+                db.put(i, vec![0u8]).unwrap();
+            }
+            db.close().unwrap();
+        }
+
+        let mut config = Config::default();
+        config.set_directory(dir.path());
+        let db = RustStore::new(Some(config)).unwrap();
+        for i in 0..1000 {
+            db.put(i, vec![1u8]).unwrap();
+        }
+        let snapshot = db.snapshot();
+        db.delete_range(100, 200).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.delete_range(300, 400);
+        batch.put(350, vec![2u8]);
+        db.write(batch).unwrap();
+        db.put(150, vec![2u8]).unwrap();
+        // an empty range deletes nothing
+        db.delete_range(600, 500).unwrap();
+
+        let check = |db: &RustStore<i32>| {
+            assert_eq!(db.get(&99), Some(vec![1u8]));
+            assert_eq!(db.get(&100), None);
+            assert_eq!(db.get(&150), Some(vec![2u8]));
+            assert_eq!(db.get(&199), None);
+            assert_eq!(db.get(&200), Some(vec![1u8]));
+            assert_eq!(db.get(&350), Some(vec![2u8]));
+            let keys: Vec<i32> = db.range(..).map(|(k, _)| k).collect();
+            let expected: Vec<i32> = (0..100)
+                .chain(150..151)
+                .chain(200..300)
+                .chain(350..351)
+                .chain(400..1000)
+                .collect();
+            assert_eq!(keys, expected);
+        };
+        check(&db);
+        assert_eq!(snapshot.get(&100), Some(vec![1u8]));
+        assert_eq!(snapshot.range(..).count(), 1000);
+        drop(snapshot);
+        db.close().unwrap();
+
+        let mut config = Config::default();
+        config.set_directory(dir.path());
+        let db = RustStore::new(Some(config)).unwrap();
+        check(&db);
+    }
+
+    #[test]
+    fn ttl_puts_expire_in_memory_and_runs() {
+        let dir = tempdir().unwrap();
+        // long enough to outlive the test, only the short one is meant to pass
+        let long_ttl = Duration::from_secs(3600);
+        let short_ttl = Duration::from_millis(200);
+        {
+            let mut config = Config::default();
+            config.set_directory(dir.path());
+            let db = RustStore::new(Some(config)).unwrap();
+            for i in 0..1000 {
+                db.put_with_ttl(i, vec![0u8], long_ttl).unwrap();
+            }
+            for i in 2000..2100 {
+                db.put_with_ttl(i, vec![0u8], short_ttl).unwrap();
+            }
+            db.close().unwrap();
+        }
+
+        let mut config = Config::default();
+        config.set_directory(dir.path());
+        let db = RustStore::new(Some(config)).unwrap();
+        for i in (0..1000).step_by(2) {
+            db.put(i, vec![1u8]).unwrap();
+        }
+        db.put_with_ttl(3000, vec![1u8], short_ttl).unwrap();
+        assert_eq!(db.get(&1), Some(vec![0u8]));
+        sleep(short_ttl);
+
+        // the short lived keys expired both in runs and in memory
+        assert_eq!(db.get(&2000), None);
+        assert_eq!(db.get(&3000), None);
+        assert_eq!(db.get(&1), Some(vec![0u8]));
+        assert_eq!(db.get(&2), Some(vec![1u8]));
+        let keys: Vec<i32> = db.range(..).map(|(k, _)| k).collect();
+        let expected: Vec<i32> = (0..1000).collect();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn huge_ttls_never_expire() {
+        let dir = tempdir().unwrap();
+        {
+            let mut config = Config::default();
+            config.set_directory(dir.path());
+            let db = RustStore::new(Some(config)).unwrap();
+            db.put_with_ttl(1, vec![1u8], Duration::MAX).unwrap();
+            db.put_with_ttl(2, vec![2u8], Duration::from_millis(u64::MAX))
+                .unwrap();
+            assert_eq!(db.get(&1), Some(vec![1u8]));
+            assert_eq!(db.get(&2), Some(vec![2u8]));
+            db.close().unwrap();
+        }
+
+        let mut config = Config::default();
+        config.set_directory(dir.path());
+        let db: RustStore<i32> = RustStore::new(Some(config)).unwrap();
+        assert_eq!(db.get(&1), Some(vec![1u8]));
+        assert_eq!(db.get(&2), Some(vec![2u8]));
+    }
+
+    #[test]
+    fn column_families_are_independent_and_survive_reopen() {
+        let _ = env_logger::try_init();
+        let dir = tempdir().unwrap();
+        let open = |declare: bool| -> RustStore<i32> {
+            let mut config = Config::default();
+            config.set_directory(dir.path());
+            if declare {
+                let mut users = Config::default();
+                users.set_memory_map_budget(1000).unwrap();
+                users.set_merge_operator(Arc::new(Append));
+                config.add_column_family("users", users);
+            }
+            return RustStore::new(Some(config)).unwrap();
+        };
+        {
+            let db = open(true);
+            let users = db.column_family("users").unwrap();
+            assert_eq!(users.name(), "users");
+            for i in 0..1000 {
+                db.put(i, vec![0u8]).unwrap();
+                users.put(i, vec![1u8]).unwrap();
+            }
+            users.delete(&0).unwrap();
+            users.delete_range(10, 20).unwrap();
+            users.merge(1, vec![2u8]).unwrap();
+            assert!(db.merge(1, vec![2u8]).is_err());
+
+            let mut batch = WriteBatch::new();
+            batch.put(2000, vec![0u8]);
+            batch.put_cf(&users, 2000, vec![1u8]);
+            batch.delete_cf(&users, 999);
+            db.write(batch).unwrap();
+            db.close().unwrap();
+        }
+
+        // a family on disk is opened even if it is not declared
+        for declare in [true, false].iter() {
+            let db = open(*declare);
+            let users = db.column_family("users").unwrap();
+            assert_eq!(db.get(&0), Some(vec![0u8]));
+            assert_eq!(db.get(&15), Some(vec![0u8]));
+            assert_eq!(db.get(&999), Some(vec![0u8]));
+            assert_eq!(db.range(..).count(), 1001);
+            assert_eq!(users.get(&0), None);
+            assert_eq!(users.get(&15), None);
+            assert_eq!(users.get(&999), None);
+            assert_eq!(users.get(&2000), Some(vec![1u8]));
+            assert_eq!(users.range(..).count(), 1001 - 12);
+            if *declare {
+                assert_eq!(users.get(&1), Some(vec![1u8, 2u8]));
+            }
+            db.close().unwrap();
+        }
+    }
+
     #[test]
     fn test_invalid_block_size() {
         // env_logger::init();
@@ -0,0 +1,48 @@
+use crate::key::Key;
+use crate::lsm::Lsm;
+use std::borrow::Borrow;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+
+/// A consistent view of the store as of the moment it was taken.
+///
+/// Reads through a snapshot ignore every later write, including ones flushed or compacted
+/// while it is alive. Compaction keeps the versions a snapshot reads until it is dropped.
+pub struct Snapshot<K: Key> {
+    lsm: Arc<Lsm<K>>,
+    seq: u64,
+}
+
+impl<K: Key> Snapshot<K> {
+    pub(crate) fn new(lsm: Arc<Lsm<K>>) -> Snapshot<K> {
+        let seq = lsm.acquire_snapshot();
+        return Snapshot { lsm: lsm, seq: seq };
+    }
+
+    /// Sequence number of the last write the snapshot sees
+    pub fn seq(self: &Self) -> u64 {
+        return self.seq;
+    }
+
+    /// Get a value as it was when the snapshot was taken
+    pub fn get<Q>(self: &Self, key: &Q) -> Option<Vec<u8>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + Hash + Debug + ToOwned<Owned = K> + ?Sized,
+    {
+        return self.lsm.get_at(key, self.seq);
+    }
+
+    /// Iterate over the key value pairs in `range` as they were when the snapshot was taken
+    pub fn range<R: RangeBounds<K>>(self: &Self, range: R) -> impl Iterator<Item = (K, Vec<u8>)> {
+        return self.lsm.range_at(range, self.seq);
+    }
+}
+
+impl<K: Key> Drop for Snapshot<K> {
+    fn drop(&mut self) {
+        self.lsm.release_snapshot(self.seq);
+    }
+}
@@ -0,0 +1,411 @@
+use crate::key::Key;
+use crate::lsm::{Lsm, LsmError};
+use crate::write_batch::WriteBatch;
+use log::{debug, trace};
+use parking_lot::{Condvar, Mutex};
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum TransactionError {
+    #[error("Another write touched a key the transaction used, it was not committed")]
+    Conflict,
+    #[error("Timed out waiting for the lock on a key")]
+    LockTimeout,
+    #[error("Error in the LSM")]
+    LsmError(#[from] LsmError),
+}
+
+/// How a transaction keeps other writes from interfering with it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransactionMode {
+    /// Read from a snapshot and check at commit that nothing read or written has changed since
+    Optimistic,
+    /// Lock every key read or written until the transaction finishes
+    Pessimistic,
+}
+
+/// Keys locked by transactions, shared by every transaction on a store
+pub(crate) struct LockTable<K: Key> {
+    locked: Mutex<HashSet<K>>,
+    unlocked: Condvar,
+    timeout: Duration,
+}
+
+impl<K: Key> LockTable<K> {
+    pub fn new(timeout: Duration) -> LockTable<K> {
+        return LockTable {
+            locked: Mutex::new(HashSet::new()),
+            unlocked: Condvar::new(),
+            timeout: timeout,
+        };
+    }
+
+    /// Wait for the lock on `key`, returns false if it is still held by someone else after
+    /// the timeout.
+    fn lock(self: &Self, key: &K) -> bool {
+        let deadline = Instant::now() + self.timeout;
+        let mut locked = self.locked.lock();
+        while locked.contains(key) {
+            if self.unlocked.wait_until(&mut locked, deadline).timed_out() && locked.contains(key) {
+                debug!("Timed out waiting for the lock on key {:?}", key);
+                return false;
+            }
+        }
+        locked.insert(key.clone());
+        return true;
+    }
+
+    fn unlock<'a, I>(self: &Self, keys: I)
+    where
+        I: Iterator<Item = &'a K>,
+    {
+        let mut locked = self.locked.lock();
+        for key in keys {
+            locked.remove(key);
+        }
+        self.unlocked.notify_all();
+    }
+}
+
+/// A group of reads and writes committed atomically, see `RustStore::begin_transaction`.
+///
+/// Writes are buffered in the transaction, reads see them. Dropping a transaction without
+/// committing it rolls it back.
+pub struct Transaction<K: Key> {
+    lsm: Arc<Lsm<K>>,
+    locks: Arc<LockTable<K>>,
+    mode: TransactionMode,
+    /// Sequence number of the snapshot optimistic transactions read from
+    snapshot: Option<u64>,
+    /// Keys read from the store rather than from `writes`, checked on commit
+    read_keys: BTreeSet<K>,
+    /// The last write to each key, None for deletes
+    writes: BTreeMap<K, Option<Vec<u8>>>,
+    /// Keys this transaction holds the lock for
+    locked_keys: BTreeSet<K>,
+}
+
+impl<K: Key> Transaction<K> {
+    pub(crate) fn new(
+        lsm: Arc<Lsm<K>>,
+        locks: Arc<LockTable<K>>,
+        mode: TransactionMode,
+    ) -> Transaction<K> {
+        let snapshot = match mode {
+            TransactionMode::Optimistic => Some(lsm.acquire_snapshot()),
+            TransactionMode::Pessimistic => None,
+        };
+        return Transaction {
+            lsm: lsm,
+            locks: locks,
+            mode: mode,
+            snapshot: snapshot,
+            read_keys: BTreeSet::new(),
+            writes: BTreeMap::new(),
+            locked_keys: BTreeSet::new(),
+        };
+    }
+
+    pub fn mode(self: &Self) -> TransactionMode {
+        return self.mode;
+    }
+
+    /// Get a value, including writes made earlier in the transaction
+    pub fn get<Q>(self: &mut Self, key: &Q) -> Result<Option<Vec<u8>>, TransactionError>
+    where
+        K: Borrow<Q>,
+        Q: Ord + Hash + Debug + ToOwned<Owned = K> + ?Sized,
+    {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        return match self.snapshot {
+            Some(seq) => {
+                self.read_keys.insert(key.to_owned());
+                Ok(self.lsm.get_at(key, seq))
+            }
+            None => {
+                self.lock(&key.to_owned())?;
+                Ok(self.lsm.get(key))
+            }
+        };
+    }
+
+    pub fn put(self: &mut Self, key: K, value: Vec<u8>) -> Result<(), TransactionError> {
+        if self.mode == TransactionMode::Pessimistic {
+            self.lock(&key)?;
+        }
+        self.writes.insert(key, Some(value));
+        return Ok(());
+    }
+
+    pub fn delete(self: &mut Self, key: &K) -> Result<(), TransactionError> {
+        if self.mode == TransactionMode::Pessimistic {
+            self.lock(key)?;
+        }
+        self.writes.insert(key.clone(), None);
+        return Ok(());
+    }
+
+    /// Apply the transaction's writes atomically.
+    ///
+    /// An optimistic transaction fails with `TransactionError::Conflict` if a key it read or wrote
+    /// has been written since it began, none of its writes are applied in that case.
+    pub fn commit(mut self: Self) -> Result<(), TransactionError> {
+        let writes = std::mem::take(&mut self.writes);
+        if writes.is_empty() {
+            return Ok(());
+        }
+        let seq = match self.snapshot {
+            Some(seq) => seq,
+            None => {
+                self.lsm.write_batch(Transaction::into_batch(writes))?;
+                return Ok(());
+            }
+        };
+
+        // lock the written keys so a pessimistic transaction can't read one of them between
+        // the check and the write
+        for key in writes.keys() {
+            self.lock(key)?;
+        }
+        let mut keys: Vec<K> = self.read_keys.iter().cloned().collect();
+        keys.extend(
+            writes
+                .keys()
+                .filter(|k| !self.read_keys.contains(*k))
+                .cloned(),
+        );
+        if !self
+            .lsm
+            .write_batch_if_unchanged(Transaction::into_batch(writes), &keys, seq)?
+        {
+            debug!("Transaction reading at {} conflicted", seq);
+            return Err(TransactionError::Conflict);
+        }
+        return Ok(());
+    }
+
+    /// Discard the transaction's writes
+    pub fn rollback(self: Self) {
+        trace!("Rolling back transaction");
+    }
+
+    fn lock(self: &mut Self, key: &K) -> Result<(), TransactionError> {
+        if self.locked_keys.contains(key) {
+            return Ok(());
+        }
+        if !self.locks.lock(key) {
+            return Err(TransactionError::LockTimeout);
+        }
+        self.locked_keys.insert(key.clone());
+        return Ok(());
+    }
+
+    fn into_batch(writes: BTreeMap<K, Option<Vec<u8>>>) -> WriteBatch<K> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in writes {
+            match value {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            }
+        }
+        return batch;
+    }
+}
+
+impl<K: Key> Drop for Transaction<K> {
+    fn drop(&mut self) {
+        if let Some(seq) = self.snapshot {
+            self.lsm.release_snapshot(seq);
+        }
+        if !self.locked_keys.is_empty() {
+            self.locks.unlock(self.locked_keys.iter());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_transaction {
+    use crate::{Config, RustStore, TransactionError, TransactionMode};
+    use std::convert::TryInto;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use test_case::test_case;
+
+    #[test_case(TransactionMode::Optimistic ; "optimistic")]
+    #[test_case(TransactionMode::Pessimistic ; "pessimistic")]
+    fn commit_applies_writes(mode: TransactionMode) {
+        let _ = env_logger::try_init();
+        let db = RustStore::new(None).unwrap();
+        db.put(1, vec![1u8]).unwrap();
+        db.put(2, vec![2u8]).unwrap();
+
+        let mut txn = db.begin_transaction(mode);
+        assert_eq!(txn.get(&1).unwrap(), Some(vec![1u8]));
+        txn.put(1, vec![10u8]).unwrap();
+        txn.delete(&2).unwrap();
+        txn.put(3, vec![3u8]).unwrap();
+        // reads see the transaction's own writes, the store does not yet
+        assert_eq!(txn.get(&1).unwrap(), Some(vec![10u8]));
+        assert_eq!(txn.get(&2).unwrap(), None);
+        assert_eq!(db.get(&1), Some(vec![1u8]));
+        txn.commit().unwrap();
+
+        assert_eq!(db.get(&1), Some(vec![10u8]));
+        assert_eq!(db.get(&2), None);
+        assert_eq!(db.get(&3), Some(vec![3u8]));
+    }
+
+    #[test_case(TransactionMode::Optimistic ; "optimistic")]
+    #[test_case(TransactionMode::Pessimistic ; "pessimistic")]
+    fn rollback_discards_writes(mode: TransactionMode) {
+        let db = RustStore::new(None).unwrap();
+        db.put(1, vec![1u8]).unwrap();
+        let mut txn = db.begin_transaction(mode);
+        txn.put(1, vec![10u8]).unwrap();
+        txn.put(2, vec![2u8]).unwrap();
+        txn.rollback();
+        assert_eq!(db.get(&1), Some(vec![1u8]));
+        assert_eq!(db.get(&2), None);
+
+        // a rolled back pessimistic transaction releases its locks
+        let mut txn = db.begin_transaction(mode);
+        txn.put(1, vec![11u8]).unwrap();
+        txn.commit().unwrap();
+        assert_eq!(db.get(&1), Some(vec![11u8]));
+    }
+
+    #[test]
+    fn optimistic_conflict_on_changed_read() {
+        let db = RustStore::new(None).unwrap();
+        db.put(1, vec![1u8]).unwrap();
+        let mut txn = db.begin_transaction(TransactionMode::Optimistic);
+        assert_eq!(txn.get(&1).unwrap(), Some(vec![1u8]));
+        db.put(1, vec![5u8]).unwrap();
+        // the transaction keeps reading its snapshot
+        assert_eq!(txn.get(&1).unwrap(), Some(vec![1u8]));
+        txn.put(2, vec![2u8]).unwrap();
+        assert!(matches!(txn.commit(), Err(TransactionError::Conflict)));
+        assert_eq!(db.get(&2), None);
+
+        // deletes conflict too, as do writes to keys the transaction only wrote
+        let mut txn = db.begin_transaction(TransactionMode::Optimistic);
+        txn.put(2, vec![2u8]).unwrap();
+        db.delete(&2).unwrap();
+        assert!(matches!(txn.commit(), Err(TransactionError::Conflict)));
+    }
+
+    #[test]
+    fn optimistic_disjoint_transactions_commit() {
+        let db = RustStore::new(None).unwrap();
+        let mut first = db.begin_transaction(TransactionMode::Optimistic);
+        let mut second = db.begin_transaction(TransactionMode::Optimistic);
+        assert_eq!(first.get(&1).unwrap(), None);
+        assert_eq!(second.get(&2).unwrap(), None);
+        first.put(1, vec![1u8]).unwrap();
+        second.put(2, vec![2u8]).unwrap();
+        first.commit().unwrap();
+        second.commit().unwrap();
+        assert_eq!(db.get(&1), Some(vec![1u8]));
+        assert_eq!(db.get(&2), Some(vec![2u8]));
+    }
+
+    #[test]
+    fn pessimistic_lock_times_out() {
+        let mut config = Config::default();
+        config.set_lock_timeout(Duration::from_millis(50));
+        let db = RustStore::new(Some(config)).unwrap();
+        let mut first = db.begin_transaction(TransactionMode::Pessimistic);
+        first.get(&1).unwrap();
+        let mut second = db.begin_transaction(TransactionMode::Pessimistic);
+        assert!(matches!(
+            second.put(1, vec![2u8]),
+            Err(TransactionError::LockTimeout)
+        ));
+        // optimistic commits wait for the lock as well
+        let mut third = db.begin_transaction(TransactionMode::Optimistic);
+        third.put(1, vec![3u8]).unwrap();
+        assert!(matches!(third.commit(), Err(TransactionError::LockTimeout)));
+
+        first.put(1, vec![1u8]).unwrap();
+        first.commit().unwrap();
+        second.put(1, vec![2u8]).unwrap();
+        second.commit().unwrap();
+        assert_eq!(db.get(&1), Some(vec![2u8]));
+    }
+
+    // Transfers between accounts from several threads must never create or destroy money
+    #[test_case(TransactionMode::Optimistic ; "optimistic")]
+    #[test_case(TransactionMode::Pessimistic ; "pessimistic")]
+    fn concurrent_transfers_keep_total(mode: TransactionMode) {
+        let _ = env_logger::try_init();
+        let num_accounts = 5u64;
+        let mut config = Config::default();
+        config.set_lock_timeout(Duration::from_millis(20));
+        let db = Arc::new(RustStore::new(Some(config)).unwrap());
+        for account in 0..num_accounts {
+            db.put(account, 100u64.to_le_bytes().to_vec()).unwrap();
+        }
+
+        let mut handles = vec![];
+        for thread_id in 0..4u64 {
+            let db = db.clone();
+            handles.push(thread::spawn(move || {
+                for i in 0..200u64 {
+                    let from = (thread_id + i) % num_accounts;
+                    let to = (from + 1 + i % (num_accounts - 1)) % num_accounts;
+                    loop {
+                        let mut txn = db.begin_transaction(mode);
+                        let result = transfer(&mut txn, from, to, 1).and_then(|_| txn.commit());
+                        match result {
+                            Ok(()) => break,
+                            Err(TransactionError::Conflict) => continue,
+                            Err(TransactionError::LockTimeout) => continue,
+                            Err(e) => panic!("transfer failed: {:?}", e),
+                        }
+                    }
+                }
+            }));
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let total: u64 = (0..num_accounts)
+            .map(|account| balance(&db.get(&account)))
+            .sum();
+        assert_eq!(total, 100 * num_accounts);
+    }
+
+    fn transfer(
+        txn: &mut crate::Transaction<u64>,
+        from: u64,
+        to: u64,
+        amount: u64,
+    ) -> Result<(), TransactionError> {
+        // read the accounts in key order so pessimistic transactions lock them in the same order
+        let (first, second) = (from.min(to), from.max(to));
+        let first_balance = balance(&txn.get(&first)?);
+        let second_balance = balance(&txn.get(&second)?);
+        let (from_balance, to_balance) = if from == first {
+            (first_balance, second_balance)
+        } else {
+            (second_balance, first_balance)
+        };
+        txn.put(from, (from_balance - amount).to_le_bytes().to_vec())?;
+        txn.put(to, (to_balance + amount).to_le_bytes().to_vec())?;
+        return Ok(());
+    }
+
+    fn balance(value: &Option<Vec<u8>>) -> u64 {
+        return u64::from_le_bytes(value.as_ref().unwrap()[..].try_into().unwrap());
+    }
+}
@@ -0,0 +1,601 @@
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs::{read_dir, remove_file, File, OpenOptions};
+use std::hash::Hasher;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use thiserror::Error;
+
+const WAL_EXTENSION: &str = "wal";
+// Each record is prefixed by a u32 length and a u64 checksum of the payload
+const RECORD_HEADER_SIZE: u64 = 4 + 8;
+/// The largest record the log takes, in bytes once serialized. Writes bigger than this are
+/// refused with `WalError::RecordTooLarge`, and a header claiming more is read as torn.
+pub const MAX_RECORD_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum WalError {
+    #[error("IO Error")]
+    IoError(#[from] io::Error),
+    #[error("Error Serializing")]
+    SerializeError(#[from] bincode::Error),
+    #[error("Record of {size} bytes is over the limit of {max} bytes")]
+    RecordTooLarge { size: u64, max: u64 },
+    #[error("Corrupt WAL: {0}")]
+    Corrupt(String),
+}
+
+/// Controls when writes to the write ahead log are fsynced.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    /// Writes are handed to the operating system but never explicitly synced.
+    /// They survive the process crashing, but not the machine.
+    None,
+    /// Every write is synced to disk before it is acknowledged.
+    EveryWrite,
+    /// Every write is synced to disk before it is acknowledged, but writers that arrive
+    /// while a sync is in progress share the next sync instead of each issuing their own.
+    GroupCommit,
+}
+
+/// A single logged operation.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum WalRecord<K> {
+    Put {
+        key: K,
+        value: Vec<u8>,
+    },
+    Delete {
+        key: K,
+    },
+    /// The records of a write batch, logged together so they are replayed all or nothing
+    Batch {
+        records: Vec<WalRecord<K>>,
+    },
+    /// An operand for the merge operator
+    Merge {
+        key: K,
+        value: Vec<u8>,
+    },
+    /// Deletes every key in `start..end`
+    DeleteRange {
+        start: K,
+        end: K,
+    },
+    /// A put which expires at `expires_at`, in milliseconds since the unix epoch
+    ExpiringPut {
+        key: K,
+        value: Vec<u8>,
+        expires_at: u64,
+    },
+    /// A write to a column family other than the default one
+    ColumnFamily {
+        family: u32,
+        record: Box<WalRecord<K>>,
+    },
+}
+
+/// A position in the log, ordered by segment and then by offset within the segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WalPosition {
+    segment: u64,
+    offset: u64,
+}
+
+struct WalSegment {
+    number: u64,
+    writer: BufWriter<File>,
+    // A second handle to the same file so we can sync without holding the writer lock
+    sync_handle: Arc<File>,
+    offset: u64,
+}
+
+/// An append only log made up of numbered segment files.
+///
+/// Every memory map has the segments written while it was active, once a memory map has been
+/// written to a run those segments can be removed.
+pub struct Wal {
+    directory: PathBuf,
+    sync_policy: SyncPolicy,
+    segment: Mutex<WalSegment>,
+    synced: Mutex<WalPosition>,
+    // segments which existed when the log was opened, they are replayed and then
+    // removed with the first memory map flush
+    old_segments: Vec<u64>,
+}
+
+impl Wal {
+    /// Open the log in `directory`, existing segments are kept for replay and a new segment
+    /// is started for new writes.
+    pub fn open(directory: &Path, sync_policy: SyncPolicy) -> Result<Wal, WalError> {
+        let mut old_segments = list_segments(directory)?;
+        old_segments.sort();
+        let number = match old_segments.last() {
+            Some(n) => n + 1,
+            None => 1,
+        };
+        info!(
+            "Opening WAL in {:?} with {} existing segments",
+            directory,
+            old_segments.len()
+        );
+        let segment = WalSegment::create(directory, number)?;
+        return Ok(Wal {
+            directory: PathBuf::from(directory),
+            sync_policy: sync_policy,
+            segment: Mutex::new(segment),
+            synced: Mutex::new(WalPosition {
+                segment: number,
+                offset: 0,
+            }),
+            old_segments: old_segments,
+        });
+    }
+
+    /// Read every record from the segments which existed when the log was opened, oldest first.
+    ///
+    /// A torn record at the end of the newest segment (e.g from crashing mid write) ends
+    /// replay, everything before it is returned and the segment is cut back to its last whole
+    /// record. A bad record in any older segment is corruption and fails with
+    /// `WalError::Corrupt`, as the records after it can't be skipped.
+    pub fn replay<K: DeserializeOwned>(self: &Self) -> Result<Vec<WalRecord<K>>, WalError> {
+        let mut records = vec![];
+        for (i, number) in self.old_segments.iter().enumerate() {
+            let path = segment_path(&self.directory, *number);
+            let file = File::open(&path)?;
+            let len = file.metadata()?.len();
+            let mut reader = CountingReader {
+                inner: BufReader::new(file),
+                count: 0,
+            };
+            let num_before = records.len();
+            let mut good_offset = 0;
+            loop {
+                match read_record(&mut reader)? {
+                    Some(record) => records.push(record),
+                    None => break,
+                }
+                good_offset = reader.count;
+            }
+            if good_offset < len {
+                if i + 1 < self.old_segments.len() {
+                    return Err(WalError::Corrupt(format!(
+                        "bad record at offset {} of {:?}, which is not the newest segment",
+                        good_offset, &path
+                    )));
+                }
+                // so the tail isn't mistaken for corruption once newer segments follow it
+                warn!(
+                    "Cutting {:?} back to {} bytes from {}",
+                    &path, good_offset, len
+                );
+                let file = OpenOptions::new().write(true).open(&path)?;
+                file.set_len(good_offset)?;
+                file.sync_all()?;
+            }
+            debug!(
+                "Replayed {} records from {:?}",
+                records.len() - num_before,
+                &path
+            );
+        }
+        info!("Replayed {} records from the WAL", records.len());
+        return Ok(records);
+    }
+
+    /// Append a record to the current segment.
+    ///
+    /// The returned position has to be passed to `sync_to` before the write is acknowledged.
+    /// Records over `MAX_RECORD_SIZE` bytes are refused without writing anything.
+    pub fn append<K: Serialize>(self: &Self, record: &WalRecord<K>) -> Result<WalPosition, WalError> {
+        let size = bincode::serialized_size(record)?;
+        if size > MAX_RECORD_SIZE as u64 {
+            return Err(WalError::RecordTooLarge {
+                size: size,
+                max: MAX_RECORD_SIZE as u64,
+            });
+        }
+        let mut segment = self.segment.lock();
+        let record_size = write_record(&mut segment.writer, record)?;
+        // hand the record to the OS so it survives the process dying
+        segment.writer.flush()?;
+        segment.offset += record_size;
+        let position = segment.position();
+        if self.sync_policy == SyncPolicy::EveryWrite {
+            segment.sync_handle.sync_data()?;
+            *self.synced.lock() = position;
+        }
+        return Ok(position);
+    }
+
+    /// Make sure everything up to and including `position` is on disk, according to the sync policy.
+    pub fn sync_to(self: &Self, position: WalPosition) -> Result<(), WalError> {
+        if self.sync_policy != SyncPolicy::GroupCommit {
+            return Ok(());
+        }
+        // Only one writer syncs at a time, anyone waiting here may find their write
+        // was covered by the sync that just finished.
+        let mut synced = self.synced.lock();
+        if *synced >= position {
+            return Ok(());
+        }
+        let (handle, target) = {
+            let segment = self.segment.lock();
+            (segment.sync_handle.clone(), segment.position())
+        };
+        handle.sync_data()?;
+        *synced = target;
+        return Ok(());
+    }
+
+    /// Start a new segment, returns the number of the new segment.
+    /// All records appended before this call are in segments with a lower number.
+    pub fn roll(self: &Self) -> Result<u64, WalError> {
+        let (old_position, number) = {
+            let mut segment = self.segment.lock();
+            segment.writer.flush()?;
+            if self.sync_policy != SyncPolicy::None {
+                segment.sync_handle.sync_data()?;
+            }
+            let old_position = segment.position();
+            let new_segment = WalSegment::create(&self.directory, segment.number + 1)?;
+            info!("Rolling WAL to segment {}", new_segment.number);
+            *segment = new_segment;
+            (old_position, segment.number)
+        };
+        // sync_to takes these locks in the opposite order, so the segment lock is released first
+        if self.sync_policy != SyncPolicy::None {
+            let mut synced = self.synced.lock();
+            if *synced < old_position {
+                *synced = old_position;
+            }
+        }
+        return Ok(number);
+    }
+
+    /// Remove every segment with a number lower than `number`, the caller must have
+    /// persisted all of their records elsewhere.
+    pub fn remove_segments_before(self: &Self, number: u64) -> Result<(), WalError> {
+        for segment in list_segments(&self.directory)? {
+            if segment < number {
+                debug!("Removing WAL segment {}", segment);
+                remove_file(segment_path(&self.directory, segment))?;
+            }
+        }
+        return Ok(());
+    }
+}
+
+impl WalSegment {
+    fn create(directory: &Path, number: u64) -> Result<WalSegment, WalError> {
+        let path = segment_path(directory, number);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let sync_handle = Arc::new(file.try_clone()?);
+        return Ok(WalSegment {
+            number: number,
+            writer: BufWriter::new(file),
+            sync_handle: sync_handle,
+            offset: 0,
+        });
+    }
+
+    fn position(self: &Self) -> WalPosition {
+        return WalPosition {
+            segment: self.number,
+            offset: self.offset,
+        };
+    }
+}
+
+fn segment_path(directory: &Path, number: u64) -> PathBuf {
+    let mut path = PathBuf::from(directory);
+    path.push(format!("{}.{}", number, WAL_EXTENSION));
+    return path;
+}
+
+fn list_segments(directory: &Path) -> Result<Vec<u64>, WalError> {
+    let mut segments = vec![];
+    for entry in read_dir(directory)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(WAL_EXTENSION) {
+            continue;
+        }
+        match path.file_stem().and_then(|s| s.to_str()).map(|s| s.parse()) {
+            Some(Ok(number)) => segments.push(number),
+            _ => warn!("Ignoring unexpected WAL file {:?}", path),
+        }
+    }
+    return Ok(segments);
+}
+
+/// Write a length and checksum prefixed record, returns the number of bytes written.
+/// Records over `MAX_RECORD_SIZE` bytes are refused.
+///
+/// This framing is shared by the write ahead log and the manifest.
+pub(crate) fn write_record<T: Serialize, W: Write>(
+    writer: &mut W,
+    record: &T,
+) -> Result<u64, bincode::Error> {
+    let payload = bincode::serialize(record)?;
+    if payload.len() > MAX_RECORD_SIZE {
+        return Err(Box::new(bincode::ErrorKind::SizeLimit));
+    }
+    let mut hasher = seahash::SeaHasher::new();
+    hasher.write(&payload);
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(&hasher.finish().to_le_bytes())?;
+    writer.write_all(&payload)?;
+    return Ok(RECORD_HEADER_SIZE + payload.len() as u64);
+}
+
+/// Read a record written by `write_record`.
+/// Returns None at the end of the input or at a torn / corrupt record.
+pub(crate) fn read_record<T: DeserializeOwned, R: Read>(
+    reader: &mut R,
+) -> Result<Option<T>, bincode::Error> {
+    let mut len_buf = [0u8; 4];
+    let mut checksum_buf = [0u8; 8];
+    if !read_full(reader, &mut len_buf)? || !read_full(reader, &mut checksum_buf)? {
+        return Ok(None);
+    }
+    let len = u32::from_le_bytes(len_buf) as usize;
+    if len > MAX_RECORD_SIZE {
+        warn!("Found a record of {} bytes, stopping reading the log", len);
+        return Ok(None);
+    }
+    let mut payload = vec![0u8; len];
+    if !read_full(reader, &mut payload)? {
+        warn!("Found a torn record at the end of a log");
+        return Ok(None);
+    }
+    let mut hasher = seahash::SeaHasher::new();
+    hasher.write(&payload);
+    if hasher.finish() != u64::from_le_bytes(checksum_buf) {
+        warn!("Found a record with a bad checksum, stopping reading the log");
+        return Ok(None);
+    }
+    return Ok(Some(bincode::deserialize(&payload)?));
+}
+
+// Counts the bytes read through it, so replay knows where the last whole record ended
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(self: &mut Self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        return Ok(n);
+    }
+}
+
+// read_exact, but running out of bytes is not an error
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<bool, io::Error> {
+    return match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    };
+}
+
+#[cfg(test)]
+mod test_wal {
+    use crate::wal::{
+        list_segments, segment_path, SyncPolicy, Wal, WalError, WalRecord, MAX_RECORD_SIZE,
+    };
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use tempfile::tempdir;
+    use test_case::test_case;
+
+    #[test_case(SyncPolicy::None ; "no sync")]
+    #[test_case(SyncPolicy::EveryWrite ; "sync every write")]
+    #[test_case(SyncPolicy::GroupCommit ; "group commit")]
+    fn wal_append_and_replay(sync_policy: SyncPolicy) {
+        let _ = env_logger::try_init();
+        let dir = tempdir().unwrap();
+        {
+            let wal = Wal::open(dir.path(), sync_policy).unwrap();
+            for i in 0..100 {
+                let pos = wal
+                    .append(&WalRecord::Put {
+                        key: i,
+                        value: vec![i as u8],
+                    })
+                    .unwrap();
+                wal.sync_to(pos).unwrap();
+            }
+            wal.append(&WalRecord::Delete { key: 7 }).unwrap();
+        }
+
+        let wal = Wal::open(dir.path(), sync_policy).unwrap();
+        let records: Vec<WalRecord<i32>> = wal.replay().unwrap();
+        assert_eq!(records.len(), 101);
+        assert_eq!(
+            records[3],
+            WalRecord::Put {
+                key: 3,
+                value: vec![3u8]
+            }
+        );
+        assert_eq!(records[100], WalRecord::Delete { key: 7 });
+    }
+
+    #[test]
+    fn wal_replays_batch_as_one_record() {
+        let _ = env_logger::try_init();
+        let dir = tempdir().unwrap();
+        {
+            let wal = Wal::open(dir.path(), SyncPolicy::EveryWrite).unwrap();
+            wal.append(&WalRecord::Batch {
+                records: vec![
+                    WalRecord::Put {
+                        key: 1,
+                        value: vec![1u8],
+                    },
+                    WalRecord::Delete { key: 2 },
+                ],
+            })
+            .unwrap();
+        }
+        // a batch torn half way through is dropped entirely
+        let mut f = OpenOptions::new()
+            .append(true)
+            .open(segment_path(dir.path(), 1))
+            .unwrap();
+        f.write_all(&[200u8, 0u8, 0u8, 0u8, 1u8, 2u8, 3u8]).unwrap();
+
+        let wal = Wal::open(dir.path(), SyncPolicy::None).unwrap();
+        let records: Vec<WalRecord<i32>> = wal.replay().unwrap();
+        assert_eq!(records.len(), 1);
+        match &records[0] {
+            WalRecord::Batch { records } => assert_eq!(records.len(), 2),
+            _ => panic!("expected a batch"),
+        }
+    }
+
+    #[test]
+    fn wal_replay_ignores_torn_record() {
+        let _ = env_logger::try_init();
+        let dir = tempdir().unwrap();
+        {
+            let wal = Wal::open(dir.path(), SyncPolicy::None).unwrap();
+            for i in 0..10 {
+                wal.append(&WalRecord::Put {
+                    key: i,
+                    value: vec![1u8, 2u8, 3u8],
+                })
+                .unwrap();
+            }
+        }
+        // simulate crashing half way through writing a record
+        let mut f = OpenOptions::new()
+            .append(true)
+            .open(segment_path(dir.path(), 1))
+            .unwrap();
+        f.write_all(&[40u8, 0u8, 0u8, 0u8, 1u8, 2u8]).unwrap();
+
+        let wal = Wal::open(dir.path(), SyncPolicy::None).unwrap();
+        let records: Vec<WalRecord<i32>> = wal.replay().unwrap();
+        assert_eq!(records.len(), 10);
+    }
+
+    #[test]
+    fn wal_refuses_records_over_the_limit() {
+        let _ = env_logger::try_init();
+        let dir = tempdir().unwrap();
+        let wal = Wal::open(dir.path(), SyncPolicy::None).unwrap();
+        let res = wal.append(&WalRecord::Put {
+            key: 1,
+            value: vec![0u8; MAX_RECORD_SIZE],
+        });
+        assert!(matches!(res, Err(WalError::RecordTooLarge { .. })));
+        wal.append(&WalRecord::Delete { key: 2 }).unwrap();
+        drop(wal);
+
+        let wal = Wal::open(dir.path(), SyncPolicy::None).unwrap();
+        let records: Vec<WalRecord<i32>> = wal.replay().unwrap();
+        assert_eq!(records, vec![WalRecord::Delete { key: 2 }]);
+    }
+
+    #[test]
+    fn wal_replay_ignores_record_with_huge_length() {
+        let _ = env_logger::try_init();
+        let dir = tempdir().unwrap();
+        {
+            let wal = Wal::open(dir.path(), SyncPolicy::None).unwrap();
+            for i in 0..10 {
+                wal.append(&WalRecord::Delete { key: i }).unwrap();
+            }
+        }
+        // a corrupt header claiming a record of almost 4 GiB
+        let mut f = OpenOptions::new()
+            .append(true)
+            .open(segment_path(dir.path(), 1))
+            .unwrap();
+        f.write_all(&u32::MAX.to_le_bytes()).unwrap();
+        f.write_all(&[7u8; 8]).unwrap();
+
+        let wal = Wal::open(dir.path(), SyncPolicy::None).unwrap();
+        let records: Vec<WalRecord<i32>> = wal.replay().unwrap();
+        assert_eq!(records.len(), 10);
+    }
+
+    #[test]
+    fn wal_replay_survives_torn_record_in_earlier_run() {
+        let _ = env_logger::try_init();
+        let dir = tempdir().unwrap();
+        {
+            let wal = Wal::open(dir.path(), SyncPolicy::None).unwrap();
+            wal.append(&WalRecord::Delete { key: 1 }).unwrap();
+        }
+        let mut f = OpenOptions::new()
+            .append(true)
+            .open(segment_path(dir.path(), 1))
+            .unwrap();
+        f.write_all(&[40u8, 0u8, 0u8, 0u8, 1u8, 2u8]).unwrap();
+
+        // crash again before the replayed records are flushed
+        {
+            let wal = Wal::open(dir.path(), SyncPolicy::None).unwrap();
+            let records: Vec<WalRecord<i32>> = wal.replay().unwrap();
+            assert_eq!(records, vec![WalRecord::Delete { key: 1 }]);
+            wal.append(&WalRecord::Delete { key: 2 }).unwrap();
+        }
+
+        let wal = Wal::open(dir.path(), SyncPolicy::None).unwrap();
+        let records: Vec<WalRecord<i32>> = wal.replay().unwrap();
+        assert_eq!(
+            records,
+            vec![WalRecord::Delete { key: 1 }, WalRecord::Delete { key: 2 }]
+        );
+    }
+
+    #[test]
+    fn wal_replay_fails_on_bad_record_in_older_segment() {
+        let _ = env_logger::try_init();
+        let dir = tempdir().unwrap();
+        {
+            let wal = Wal::open(dir.path(), SyncPolicy::None).unwrap();
+            for i in 0..10 {
+                wal.append(&WalRecord::Delete { key: i }).unwrap();
+            }
+            wal.roll().unwrap();
+            wal.append(&WalRecord::Delete { key: 10 }).unwrap();
+        }
+        // flip a byte in the payload of the first record
+        let path = segment_path(dir.path(), 1);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[12] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let wal = Wal::open(dir.path(), SyncPolicy::None).unwrap();
+        let res: Result<Vec<WalRecord<i32>>, WalError> = wal.replay();
+        assert!(matches!(res, Err(WalError::Corrupt(_))));
+    }
+
+    #[test]
+    fn wal_roll_and_remove_segments() {
+        let _ = env_logger::try_init();
+        let dir = tempdir().unwrap();
+        let wal = Wal::open(dir.path(), SyncPolicy::EveryWrite).unwrap();
+        wal.append(&WalRecord::Delete { key: 1 }).unwrap();
+        let new_segment = wal.roll().unwrap();
+        assert_eq!(new_segment, 2);
+        wal.append(&WalRecord::Delete { key: 2 }).unwrap();
+        assert_eq!(list_segments(dir.path()).unwrap().len(), 2);
+
+        wal.remove_segments_before(new_segment).unwrap();
+        assert_eq!(list_segments(dir.path()).unwrap(), vec![2]);
+
+        let wal = Wal::open(dir.path(), SyncPolicy::EveryWrite).unwrap();
+        let records: Vec<WalRecord<i32>> = wal.replay().unwrap();
+        assert_eq!(records, vec![WalRecord::Delete { key: 2 }]);
+    }
+}
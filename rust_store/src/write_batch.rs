@@ -0,0 +1,114 @@
+use crate::column_family::ColumnFamily;
+use crate::key::Key;
+use crate::wal::WalRecord;
+
+/// A group of puts and deletes which `RustStore::write` applies atomically.
+///
+/// The writes are logged as a single record and readers see either all of them or none, even
+/// when they go to different column families.
+/// When a key is written more than once, the last write in the batch wins.
+pub struct WriteBatch<K: Key> {
+    records: Vec<WalRecord<K>>,
+}
+
+impl<K: Key> WriteBatch<K> {
+    pub fn new() -> WriteBatch<K> {
+        return WriteBatch { records: vec![] };
+    }
+
+    pub fn put(self: &mut Self, key: K, value: Vec<u8>) {
+        self.records.push(WalRecord::Put {
+            key: key,
+            value: value,
+        });
+    }
+
+    pub fn delete(self: &mut Self, key: K) {
+        self.records.push(WalRecord::Delete { key: key });
+    }
+
+    /// Delete every key in `start..end`, see `RustStore::delete_range`
+    pub fn delete_range(self: &mut Self, start: K, end: K) {
+        self.records.push(WalRecord::DeleteRange {
+            start: start,
+            end: end,
+        });
+    }
+
+    /// Add an operand for the store's merge operator, see `RustStore::merge`
+    pub fn merge(self: &mut Self, key: K, operand: Vec<u8>) {
+        self.records.push(WalRecord::Merge {
+            key: key,
+            value: operand,
+        });
+    }
+
+    /// Put a key value pair into the column family `cf`
+    pub fn put_cf(self: &mut Self, cf: &ColumnFamily<K>, key: K, value: Vec<u8>) {
+        self.push_cf(
+            cf,
+            WalRecord::Put {
+                key: key,
+                value: value,
+            },
+        );
+    }
+
+    pub fn delete_cf(self: &mut Self, cf: &ColumnFamily<K>, key: K) {
+        self.push_cf(cf, WalRecord::Delete { key: key });
+    }
+
+    /// Delete every key in `start..end` of the column family `cf`
+    pub fn delete_range_cf(self: &mut Self, cf: &ColumnFamily<K>, start: K, end: K) {
+        self.push_cf(
+            cf,
+            WalRecord::DeleteRange {
+                start: start,
+                end: end,
+            },
+        );
+    }
+
+    /// Add an operand for the merge operator of the column family `cf`
+    pub fn merge_cf(self: &mut Self, cf: &ColumnFamily<K>, key: K, operand: Vec<u8>) {
+        self.push_cf(
+            cf,
+            WalRecord::Merge {
+                key: key,
+                value: operand,
+            },
+        );
+    }
+
+    fn push_cf(self: &mut Self, cf: &ColumnFamily<K>, record: WalRecord<K>) {
+        self.records.push(WalRecord::ColumnFamily {
+            family: cf.id(),
+            record: Box::new(record),
+        });
+    }
+
+    /// Number of writes in the batch
+    pub fn len(self: &Self) -> usize {
+        return self.records.len();
+    }
+
+    pub fn is_empty(self: &Self) -> bool {
+        return self.records.is_empty();
+    }
+
+    pub fn clear(self: &mut Self) {
+        self.records.clear();
+    }
+
+    pub(crate) fn into_record(self: Self) -> WalRecord<K> {
+        return WalRecord::Batch {
+            records: self.records,
+        };
+    }
+}
+
+impl<K: Key> Default for WriteBatch<K> {
+    fn default() -> WriteBatch<K> {
+        return WriteBatch::new();
+    }
+}
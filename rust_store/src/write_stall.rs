@@ -0,0 +1,80 @@
+use crate::rust_store::Config;
+use std::cmp::max;
+use std::time::Duration;
+
+/// What happens to new writes while flushes or compactions of a column family fall behind
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WriteStall {
+    /// Writes go straight through
+    None,
+    /// Each write is delayed by `Config::write_slowdown_delay`
+    Slowdown,
+    /// Writes are blocked until a flush or compaction catches up
+    Stop,
+}
+
+impl WriteStall {
+    /// The stall for a column family tuned by `config` with `memory_use` bytes in its memory
+    /// maps and `level1_runs` sorted runs in level 1.
+    ///
+    /// The policy keeps up to `max(k, z)` runs in level 1 without compacting it, so writes
+    /// are never stopped below that many or they would wait for a compaction that never runs.
+    pub fn from_config(config: &Config, memory_use: u64, level1_runs: usize) -> WriteStall {
+        let memory = memory_use as f64 / config.memory_map_budget as f64;
+        let level1_stop_runs = max(
+            config.level1_stop_runs,
+            max(config.k, config.z) as usize + 1,
+        );
+        if memory >= config.memory_stop_ratio || level1_runs >= level1_stop_runs {
+            return WriteStall::Stop;
+        }
+        if memory >= config.memory_slowdown_ratio || level1_runs >= config.level1_slowdown_runs {
+            return WriteStall::Slowdown;
+        }
+        return WriteStall::None;
+    }
+}
+
+/// How often and for how long writes have been held up by flushes and compactions falling
+/// behind, see `Config::set_memory_map_stall` and `Config::set_level1_stall`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct WriteStallStats {
+    /// Writes delayed once a slowdown threshold was reached
+    pub slowed_writes: u64,
+    /// Writes blocked until every column family was back under its stop thresholds
+    pub stopped_writes: u64,
+    /// Time writers have spent delayed or blocked
+    pub stall_time: Duration,
+}
+
+#[cfg(test)]
+mod test_write_stall {
+    use crate::rust_store::Config;
+    use crate::write_stall::WriteStall;
+    use test_case::test_case;
+
+    #[test_case(0, 0 => WriteStall::None; "empty")]
+    #[test_case(1400, 0 => WriteStall::None; "one map over budget")]
+    #[test_case(1500, 0 => WriteStall::Slowdown; "memory slowdown")]
+    #[test_case(2000, 0 => WriteStall::Stop; "memory stop")]
+    #[test_case(0, 7 => WriteStall::Slowdown; "level 1 slowdown")]
+    #[test_case(0, 10 => WriteStall::Stop; "level 1 stop")]
+    #[test_case(1500, 10 => WriteStall::Stop; "stop wins")]
+    fn thresholds(memory_use: u64, level1_runs: usize) -> WriteStall {
+        let mut config = Config::default();
+        config.set_memory_map_budget(1000).unwrap();
+        config.set_memory_map_stall(1.5, 2.0).unwrap();
+        config.set_compaction_policy(4, 3, 1).unwrap();
+        config.set_level1_stall(7, 10).unwrap();
+        return WriteStall::from_config(&config, memory_use, level1_runs);
+    }
+
+    #[test]
+    fn never_stops_below_what_the_policy_keeps() {
+        let mut config = Config::default();
+        config.set_compaction_policy(10, 9, 9).unwrap();
+        config.set_level1_stall(2, 4).unwrap();
+        assert_eq!(WriteStall::from_config(&config, 0, 9), WriteStall::Slowdown);
+        assert_eq!(WriteStall::from_config(&config, 0, 10), WriteStall::Stop);
+    }
+}